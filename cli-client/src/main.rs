@@ -1,22 +1,26 @@
-use crdts_sandbox_lib::document::{
-    Command, DocActor, DocResponse, Document, DocumentOp, RecordEntry,
-};
+mod store;
+
+use store::Store;
 
-use bytes::Bytes;
+use crdts_sandbox_lib::{
+    backoff::Backoff,
+    document::{
+        self, DigestRange, DocActor, DocKey, HashMatch, Item, RecordEntry,
+    },
+    oplog,
+};
 
 use std::{
+    collections::HashSet,
     io::{stdout, Write},
-    net::SocketAddr,
-    time::Duration,
+    sync::{Arc, Mutex},
 };
 
-use futures::{future::FutureExt, Sink, SinkExt, StreamExt};
+use futures::{future::FutureExt, Sink, SinkExt, Stream, StreamExt};
 
-use futures_timer::Delay;
+use tokio::sync::mpsc;
 
-use tokio::{io, net::TcpStream, sync::mpsc};
-
-use tokio_util::codec::{BytesCodec, Framed};
+use tokio_tungstenite::tungstenite::{self, Message};
 
 use crossterm::{
     cursor,
@@ -25,11 +29,26 @@ use crossterm::{
     terminal::ClearType,
 };
 
+type Key = String;
+type Command = document::Command<Key>;
+type DocResponse = document::DocResponse<Key>;
+type DocReplica = document::DocReplica<Key>;
+type Document = document::Document<Key>;
+type DocumentBuilder = document::DocumentBuilder<Key>;
+type DigestWalk = document::DigestWalk<Key>;
+type OpId = oplog::OpId<Key>;
+
+const SERVER_URL: &str = "ws://127.0.0.1:3030/service";
+const STREAM_CHUNK_SIZE: u32 = 64;
+const HISTORY_LIMIT: u32 = 20;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 enum MenuInput {
     Up,
     Down,
     Enter,
+    Char(char),
+    Backspace,
     Quit,
 }
 
@@ -39,6 +58,8 @@ impl MenuInput {
             KeyCode::Up => Some(Self::Up),
             KeyCode::Down => Some(Self::Down),
             KeyCode::Enter => Some(Self::Enter),
+            KeyCode::Char(c) => Some(Self::Char(c)),
+            KeyCode::Backspace => Some(Self::Backspace),
             KeyCode::Esc => Some(Self::Quit),
             _ => None,
         }
@@ -54,16 +75,461 @@ impl MenuInput {
 #[derive(Debug)]
 struct ClientState {
     actor: Option<DocActor>,
-    document: Option<Document>,
-    read_ctx: Option<crdts::ctx::ReadCtx<(), DocActor>>,
+    replica: DocReplica,
+    store: Option<Store>,
+    online: bool,
+    status_row: u16,
+    loading: Option<DocumentBuilder>,
+    walk: Option<DigestWalk>,
 }
 
 impl ClientState {
-    fn new() -> Self {
+    fn new(actor: Option<DocActor>) -> Self {
         ClientState {
-            actor: None,
-            document: None,
-            read_ctx: None,
+            actor,
+            replica: DocReplica::new(actor.unwrap_or_default()),
+            store: None,
+            online: false,
+            status_row: 0,
+            loading: None,
+            walk: None,
+        }
+    }
+
+    fn save<W: Write>(&self, write: &mut W) -> crossterm::Result<()> {
+        if let Some(store) = &self.store {
+            if let Err(err) = store.save(&self.replica) {
+                let msg = format!("Couldn't save document: {}", err);
+                print_at(5, 5, &msg, write)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn print_status<W: Write>(&self, write: &mut W) -> crossterm::Result<()> {
+        let status = format!(
+            "[{}] {} pending ops",
+            if self.online { "online" } else { "offline" },
+            self.replica.pending_local()
+        );
+        print_at(0, self.status_row, &status, write)
+    }
+
+    fn merge_document<W: Write>(
+        &mut self,
+        doc: Document,
+        stdout: &mut W,
+    ) -> Option<Command> {
+        self.replica.merge_document(doc);
+        let _ = self.save(stdout);
+        let doc = self.replica.document();
+        for (i, item_ctx) in doc.records.iter().enumerate() {
+            let _ = print_at(5, 6, "Doc:", stdout);
+            let i = (7 + i) as u16;
+            let (k, v) = item_ctx.val;
+            let mut s = String::new();
+            v.read().val.iter().for_each(|x| {
+                s.push_str(&format!(", {}", x));
+            });
+            let _ = print_at(5, i, &format!("{} - {}", k, s), stdout);
+        }
+        let actor = self.actor?;
+        let clock = doc.get_read_ctx().add_clock;
+        Some(Command::AckClock { actor, clock })
+    }
+
+    // Returns the commands the response calls for: acknowledging the clock
+    // of a received document, or continuing a comparison with the server.
+    fn handle_response<W: Write>(
+        &mut self,
+        doc_resp: DocResponse,
+        stdout: &mut W,
+    ) -> Vec<Command> {
+        match doc_resp {
+            DocResponse::Document(doc) => {
+                print_at(5, 5, "Received doc", stdout).unwrap();
+                return self.merge_document(doc, stdout).into_iter().collect();
+            }
+            DocResponse::NotModified => {
+                print_at(5, 5, "Document not modified", stdout).unwrap();
+            }
+            DocResponse::Record(rec) => {
+                let rec = rec.val;
+                if let Some(record) = rec {
+                    print_at(5, 5, "Received filled record", stdout).unwrap();
+                    let mut rec_string = String::new();
+                    record.read().val.iter().for_each(|x| {
+                        rec_string.push_str(&format!(" {}", x));
+                    });
+                    let fmted = format!("Record:\n{}", rec_string);
+                    let _ = print_at(5, 6, &fmted, stdout);
+                } else {
+                    print_at(5, 5, "Received empty record", stdout).unwrap();
+                }
+            }
+            DocResponse::ReadCtx(ctx) => {
+                let msg = format!("Received read ctx {:?}", ctx.add_clock);
+                print_at(5, 5, &msg, stdout).unwrap();
+            }
+            DocResponse::RecordCreated { key } => {
+                let msg = format!("Created record {}", key);
+                print_at(5, 5, &msg, stdout).unwrap();
+            }
+            DocResponse::Rejected { reason } => {
+                let msg = format!("Rejected: {}", reason);
+                print_at(5, 5, &msg, stdout).unwrap();
+            }
+            DocResponse::GarbageCollected { report, .. } => {
+                let msg = format!(
+                    "Collected {} deferred removes, {} bytes",
+                    report.deferred_dropped,
+                    report.reclaimed()
+                );
+                print_at(5, 5, &msg, stdout).unwrap();
+            }
+            DocResponse::Ack { id, duplicate } => {
+                self.replica.ack(&id);
+                let _ = self.save(stdout);
+                let msg = if duplicate {
+                    format!("Server already had op {:?}", id)
+                } else {
+                    format!("Server acked op {:?}", id)
+                };
+                print_at(5, 5, &msg, stdout).unwrap();
+            }
+            DocResponse::Stats(stats) => {
+                let msg = format!(
+                    "{} ops logged, {} duplicates, {} pending",
+                    stats.logged, stats.duplicates, stats.pending
+                );
+                print_at(5, 5, &msg, stdout).unwrap();
+            }
+            DocResponse::Records { records, next } => {
+                let msg = format!(
+                    "Received {} records, more after {:?}",
+                    records.len(),
+                    next
+                );
+                print_at(5, 5, &msg, stdout).unwrap();
+            }
+            DocResponse::RecordChunk {
+                records,
+                sent,
+                total,
+            } => {
+                let msg =
+                    format!("Loading document: {}/{} records", sent, total);
+                print_at(5, 5, &msg, stdout).unwrap();
+                self.loading
+                    .get_or_insert_with(DocumentBuilder::new)
+                    .push(records);
+            }
+            DocResponse::DocumentEnd { read_ctx } => {
+                let builder = self.loading.take().unwrap_or_default();
+                match builder.finish(read_ctx.add_clock) {
+                    Some(doc) => {
+                        print_at(5, 5, "Loaded doc", stdout).unwrap();
+                        return self
+                            .merge_document(doc, stdout)
+                            .into_iter()
+                            .collect();
+                    }
+                    None => {
+                        print_at(5, 5, "Couldn't load doc", stdout).unwrap();
+                    }
+                }
+            }
+            DocResponse::Missed { ops } => {
+                let msg = format!("Caught up on {} ops", ops.len());
+                print_at(5, 5, &msg, stdout).unwrap();
+                for op in ops {
+                    self.replica.apply_op(op);
+                }
+                let _ = self.save(stdout);
+                if let Some(actor) = self.actor {
+                    let clock =
+                        self.replica.document().get_read_ctx().add_clock;
+                    return vec![Command::AckClock { actor, clock }];
+                }
+            }
+            DocResponse::Digest(digest) => {
+                if let Some(walk) = self.walk.as_mut() {
+                    let mut cmds: Vec<_> = walk
+                        .receive(&digest)
+                        .into_iter()
+                        .map(|range| Command::GetDigest { range })
+                        .collect();
+                    cmds.extend(self.walked(stdout));
+                    return cmds;
+                }
+            }
+            DocResponse::Hashes { content, state, .. } => {
+                let doc = self.replica.document();
+                let msg = match doc.compare_hashes(&content, &state) {
+                    HashMatch::State => "Same state as the server",
+                    HashMatch::Content => "Same content as the server",
+                    HashMatch::Differs => "Content differs from the server's",
+                };
+                print_at(5, 5, msg, stdout).unwrap();
+            }
+            DocResponse::Blame { key, entries } => {
+                let msg = format!("Record {}: {} entries", key, entries.len());
+                print_at(5, 5, &msg, stdout).unwrap();
+                for (i, blame) in entries.iter().enumerate() {
+                    let dots: Vec<_> =
+                        blame.dots.iter().map(ToString::to_string).collect();
+                    let line = format!("{} - {}", blame.entry, dots.join(", "));
+                    let _ = print_at(5, (6 + i) as u16, &line, stdout);
+                }
+            }
+            DocResponse::History {
+                key,
+                entries,
+                earlier,
+            } => {
+                let msg = match earlier {
+                    Some(earlier) => {
+                        format!("Record {}: {} earlier ops", key, earlier)
+                    }
+                    None => format!("Record {}", key),
+                };
+                print_at(5, 5, &msg, stdout).unwrap();
+                for (i, entry) in entries.iter().enumerate() {
+                    let line = entry.to_string();
+                    let _ = print_at(5, (6 + i) as u16, &line, stdout);
+                }
+            }
+            DocResponse::RecordSet(set) => {
+                let walk = self.walk.take();
+                let count = set.records.len();
+                let msg = if !walk.is_some_and(|walk| walk.matches(&set)) {
+                    "Server changed while comparing, try again".to_string()
+                } else if self.replica.merge_records(set).is_some() {
+                    let _ = self.save(stdout);
+                    format!("Merged {} differing records", count)
+                } else {
+                    "Couldn't merge records".to_string()
+                };
+                print_at(5, 5, &msg, stdout).unwrap();
+            }
+            // only servers ask each other for their logs
+            DocResponse::Log { .. } => (),
+        }
+        Vec::new()
+    }
+
+    // Asks for the differing records once the comparison has no digests
+    // left to wait for.
+    fn walked<W: Write>(&mut self, stdout: &mut W) -> Option<Command> {
+        let walk = self.walk.as_ref().filter(|walk| walk.is_done())?;
+        let keys = walk.keys().to_vec();
+        if keys.is_empty() {
+            self.walk = None;
+            print_at(5, 5, "Document matches the server's", stdout).unwrap();
+            return None;
+        }
+        let msg = format!("{} records differ from the server's", keys.len());
+        print_at(5, 5, &msg, stdout).unwrap();
+        Some(Command::GetRecordSet { keys })
+    }
+
+    // Edits need an actor of their own, so without one the client is
+    // read-only.
+    fn replica_mut(&mut self) -> Option<&mut DocReplica> {
+        self.actor?;
+        Some(&mut self.replica)
+    }
+
+    fn edit(&mut self, menu_item: MenuItem, prompt: Prompt) -> Option<()> {
+        let replica = self.replica_mut()?;
+        match prompt {
+            Prompt::Key { input } => {
+                replica.remove_record(Key::parse_key(&input)?);
+            }
+            Prompt::Entries {
+                key: Some(key),
+                lines,
+                ..
+            } => {
+                let item = lines_to_item(lines)?;
+                if !replica.document().can_write(&key) {
+                    return None;
+                }
+                if menu_item == MenuItem::RemoveEntries {
+                    replica.remove_entries(key, item.into_vec());
+                } else {
+                    replica.add_item(key, item);
+                }
+            }
+            Prompt::Entries {
+                key: None, lines, ..
+            } => {
+                replica.create_record(lines_to_item(lines)?);
+            }
+        }
+        Some(())
+    }
+
+    // Lets the server skip sending a document this replica already has,
+    // and starts comparing with the server's hash tree from the root.
+    fn prepare(&mut self, cmd: Command) -> Command {
+        match cmd {
+            Command::GetDigest { range } if range == DigestRange::root() => {
+                let tree = self.replica.document().merkle_tree();
+                self.walk = Some(DigestWalk::new(tree));
+                cmd
+            }
+            Command::GetDocument { clock: None } => {
+                let read_ctx = self.replica.document().get_read_ctx();
+                Command::GetDocument {
+                    clock: Some(read_ctx.add_clock),
+                }
+            }
+            cmd => cmd,
+        }
+    }
+
+    fn outgoing(&mut self) -> Vec<Command> {
+        self.replica
+            .take_queued()
+            .into_iter()
+            .map(|op| Command::Apply { op })
+            .collect()
+    }
+}
+
+fn lines_to_item(lines: Vec<String>) -> Option<Item<RecordEntry>> {
+    if lines.is_empty() {
+        return None;
+    }
+    let entries = lines.iter().map(|line| RecordEntry::text(line)).collect();
+    Some(Item::from_vec(entries))
+}
+
+#[derive(Debug)]
+enum Prompt {
+    Key {
+        input: String,
+    },
+    Entries {
+        key: Option<Key>,
+        lines: Vec<String>,
+        input: String,
+    },
+}
+
+impl Prompt {
+    fn input_mut(&mut self) -> &mut String {
+        match self {
+            Prompt::Key { input } => input,
+            Prompt::Entries { input, .. } => input,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MenuItem {
+    GetDocument,
+    GetRecord,
+    GetReadCtx,
+    AddEntries,
+    CreateRecord,
+    CollectGarbage,
+    GetStats,
+    RemoveEntries,
+    RemoveRecord,
+    StreamDocument,
+    CompareDigests,
+    CompareHashes,
+    Blame,
+    History,
+}
+
+impl MenuItem {
+    const ALL: [MenuItem; 14] = [
+        MenuItem::GetDocument,
+        MenuItem::GetRecord,
+        MenuItem::GetReadCtx,
+        MenuItem::AddEntries,
+        MenuItem::CreateRecord,
+        MenuItem::CollectGarbage,
+        MenuItem::GetStats,
+        MenuItem::RemoveEntries,
+        MenuItem::RemoveRecord,
+        MenuItem::StreamDocument,
+        MenuItem::CompareDigests,
+        MenuItem::CompareHashes,
+        MenuItem::Blame,
+        MenuItem::History,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            MenuItem::GetDocument => "Get document",
+            MenuItem::GetRecord => "Get record by key",
+            MenuItem::GetReadCtx => "Get document read context",
+            MenuItem::AddEntries => "Add entries to a record",
+            MenuItem::CreateRecord => "Create a new record",
+            MenuItem::CollectGarbage => "Collect garbage",
+            MenuItem::GetStats => "Get op log statistics",
+            MenuItem::RemoveEntries => "Remove entries from a record",
+            MenuItem::RemoveRecord => "Remove a record",
+            MenuItem::StreamDocument => "Stream document",
+            MenuItem::CompareDigests => "Compare with server",
+            MenuItem::CompareHashes => "Compare hashes with server",
+            MenuItem::Blame => "Blame a record",
+            MenuItem::History => "Show a record's history",
+        }
+    }
+
+    fn needs_key(self) -> bool {
+        matches!(
+            self,
+            MenuItem::AddEntries
+                | MenuItem::RemoveEntries
+                | MenuItem::RemoveRecord
+        ) || self.is_record_query()
+    }
+
+    // Items editing a record prompt for its entries after its key.
+    fn needs_entries(self) -> bool {
+        matches!(self, MenuItem::AddEntries | MenuItem::RemoveEntries)
+    }
+
+    fn is_record_query(self) -> bool {
+        matches!(
+            self,
+            MenuItem::GetRecord | MenuItem::Blame | MenuItem::History
+        )
+    }
+
+    fn record_query(self, key: Key) -> Option<Command> {
+        match self {
+            MenuItem::GetRecord => Some(Command::GetRecord { key }),
+            MenuItem::Blame => Some(Command::Blame { key }),
+            MenuItem::History => Some(Command::GetHistory {
+                key,
+                limit: HISTORY_LIMIT,
+                before: None,
+            }),
+            _ => None,
+        }
+    }
+
+    fn command(self) -> Option<Command> {
+        match self {
+            MenuItem::GetDocument => Some(Command::GetDocument { clock: None }),
+            MenuItem::GetReadCtx => Some(Command::GetReadCtx),
+            MenuItem::CollectGarbage => Some(Command::CollectGarbage),
+            MenuItem::GetStats => Some(Command::GetStats),
+            MenuItem::StreamDocument => Some(Command::StreamDocument {
+                chunk_size: STREAM_CHUNK_SIZE,
+            }),
+            MenuItem::CompareDigests => Some(Command::GetDigest {
+                range: DigestRange::root(),
+            }),
+            MenuItem::CompareHashes => Some(Command::GetHashes),
+            _ => None,
         }
     }
 }
@@ -71,7 +537,8 @@ impl ClientState {
 #[derive(Debug)]
 struct MenuState {
     pub index: usize,
-    items: Vec<String>,
+    items: Vec<MenuItem>,
+    prompt: Option<Prompt>,
     pub menu_cmd_rx: mpsc::Receiver<MenuInput>,
     menu_cmd_tx: mpsc::Sender<MenuInput>,
 }
@@ -83,41 +550,77 @@ impl MenuState {
 
     fn command_menu() -> Self {
         let (menu_cmd_tx, menu_cmd_rx) = mpsc::channel(100);
-        let items = vec![
-            "Get document".into(),
-            "Get record by key".into(),
-            "Get document read context".into(),
-            "Add a record".into(),
-            "Apply an op".into(),
-        ];
+        let items = MenuItem::ALL.to_vec();
         MenuState {
             index: 0,
             items,
+            prompt: None,
             menu_cmd_rx,
             menu_cmd_tx,
         }
     }
 
-    fn choice_to_command(&self) -> Option<Command> {
-        match self.index {
-            0 => Some(Command::GetDocument),
-            1 => Some(Command::GetRecord { key: 0 }),
-            2 => Some(Command::GetReadCtx),
-            3 => None,
-            4 => None,
-            // 3 => Command::,
-            // 4 => Command::,
-            _ => None,
+    fn selected(&self) -> MenuItem {
+        self.items[self.index]
+    }
+
+    // Returns the finished prompt once the user has submitted it; the
+    // entries prompt collects lines until an empty one, so several lines
+    // can be pasted at once.
+    fn submit_prompt(&mut self) -> Option<Prompt> {
+        match self.prompt.take()? {
+            Prompt::Key { input } if self.selected().needs_entries() => {
+                let key = Key::parse_key(&input)?;
+                self.prompt = Some(Prompt::Entries {
+                    key: Some(key),
+                    lines: Vec::new(),
+                    input: String::new(),
+                });
+                None
+            }
+            Prompt::Entries {
+                key,
+                mut lines,
+                input,
+            } => {
+                if input.is_empty() {
+                    Some(Prompt::Entries { key, lines, input })
+                } else {
+                    lines.push(input);
+                    self.prompt = Some(Prompt::Entries {
+                        key,
+                        lines,
+                        input: String::new(),
+                    });
+                    None
+                }
+            }
+            prompt => Some(prompt),
         }
     }
 
+    fn print_prompt<W: Write>(&self, write: &mut W) -> crossterm::Result<()> {
+        let line = match &self.prompt {
+            Some(Prompt::Key { input }) => format!("key: {}", input),
+            Some(Prompt::Entries { key, lines, input }) => format!(
+                "{} ({} lines, empty line to send): {}",
+                key.as_deref().unwrap_or("new record"),
+                lines.len(),
+                input
+            ),
+            None => String::new(),
+        };
+        print_at(0, self.items.len() as u16 + 1, &line, write)
+    }
+
     fn print_menu<W: Write>(&self, write: &mut W) -> crossterm::Result<()> {
         execute!(write, cursor::SavePosition, terminal::Clear(ClearType::All),)?;
         for (i, item) in self.items.iter().enumerate() {
             if i == self.index {
-                println!("{}  * {}", style::Attribute::Bold, item);
+                println!("{}  * {}", style::Attribute::Bold, item.label());
             } else {
-                println!("{}  * {}", style::Attribute::NormalIntensity, item);
+                let normal = style::Attribute::NormalIntensity;
+                println!("{}  * {}", normal, item.label());
             }
             execute!(write, cursor::MoveToColumn(0))?;
         }
@@ -204,91 +707,31 @@ fn print_at<W: Write>(
     Ok(())
 }
 
-async fn wait() {
-    Delay::new(Duration::from_millis(1_000)).await;
-}
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "127.0.0.1:8080".parse::<SocketAddr>()?;
-    let stream = TcpStream::connect(addr).await?;
+    let actor = std::env::args().nth(1).and_then(|arg| arg.parse().ok());
+    let mut client_state = ClientState::new(actor);
+    if let Some(actor) = actor {
+        let dir = std::env::args()
+            .nth(2)
+            .unwrap_or_else(|| format!("cli-client-{}", actor));
+        let store = Store::open(dir)?;
+        client_state.replica = store.load(actor)?;
+        client_state.store = Some(store);
+    }
 
-    let framed = Framed::new(stream, BytesCodec::new());
-    let (sink, mut stream) = framed.split();
+    let menu_state = MenuState::command_menu();
+    client_state.status_row = menu_state.items.len() as u16 + 2;
+    let client_state = Arc::new(Mutex::new(client_state));
 
     let (doc_cmd_tx, doc_cmd_rx) = mpsc::channel(100);
 
-    let _recv_handle = tokio::spawn(async move {
-        let mut stdout = stdout();
-        while let Some(result) = stream.next().await {
-            if let Ok(input) = result {
-                if let Some(doc_resp) = DocResponse::from_bytes(&input) {
-                    match doc_resp {
-                        DocResponse::Document(doc) => {
-                            print_at(5, 5, "Received doc", &mut stdout)
-                                .unwrap();
-                            for (i, item_ctx) in doc.records.iter().enumerate()
-                            {
-                                let _ = print_at(5, 6, "Doc:", &mut stdout);
-                                let i = (7 + i) as u16;
-                                let (k, v) = item_ctx.val;
-                                let mut s = String::new();
-                                v.read().val.iter().for_each(|x| {
-                                    let string =
-                                        std::str::from_utf8(x).unwrap();
-                                    s.push_str(&format!(", {}", string));
-                                });
-                                let _ = print_at(
-                                    5,
-                                    i,
-                                    &format!("{} - {}", k, s),
-                                    &mut stdout,
-                                );
-                            }
-                        }
-                        DocResponse::Record(rec) => {
-                            let rec = rec.val;
-                            if let Some(record) = rec {
-                                print_at(
-                                    5,
-                                    5,
-                                    "Received filled record",
-                                    &mut stdout,
-                                )
-                                .unwrap();
-                                let mut rec_string = String::new();
-                                record.read().val.iter().for_each(|x| {
-                                    let s = std::str::from_utf8(x).unwrap();
-                                    rec_string.push_str(&format!(" {}", s));
-                                });
-                                let fmted = format!("Record:\n{}", rec_string);
-                                let _ = print_at(5, 6, &fmted, &mut stdout);
-                            } else {
-                                print_at(
-                                    5,
-                                    5,
-                                    "Received empty record",
-                                    &mut stdout,
-                                )
-                                .unwrap();
-                            }
-                        }
-                        DocResponse::ReadCtx(_ctx) => {
-                            print_at(5, 5, "Received read ctx", &mut stdout)
-                                .unwrap();
-                        }
-                    }
-                }
-            }
-        }
-    });
-
-    let _send_handle = tokio::spawn(async move {
-        let _ = send_cmds_handler(doc_cmd_rx, sink).await;
+    let conn_state = client_state.clone();
+    let ack_tx = doc_cmd_tx.clone();
+    let _conn_handle = tokio::spawn(async move {
+        connection_loop(conn_state, doc_cmd_rx, ack_tx).await;
     });
 
-    let menu_state = MenuState::command_menu();
-
     let mut sout = stdout();
 
     execute!(
@@ -308,8 +751,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     menu_state.print_menu(&mut sout)?;
+    client_state.lock().unwrap().print_status(&mut sout)?;
 
-    menu_handler(menu_state, doc_cmd_tx, stdout()).await?;
+    menu_handler(menu_state, client_state, doc_cmd_tx, stdout()).await?;
 
     terminal::disable_raw_mode()?;
 
@@ -317,36 +761,229 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+// Keeps (re)connecting to the server, backing off while it's unreachable.
+// Each time a connection is up, the client asks for what it missed since
+// its clock and resends every op the server hasn't acknowledged. Commands
+// queued while no connection is up are dropped: the ops among them are
+// requeued from the replica and the rest belong to the lost session.
+async fn connection_loop(
+    client_state: Arc<Mutex<ClientState>>,
+    mut doc_cmd_rx: mpsc::Receiver<Command>,
+    ack_tx: mpsc::Sender<Command>,
+) {
+    let mut backoff = Backoff::default();
+    loop {
+        if let Ok((ws_stream, _)) =
+            tokio_tungstenite::connect_async(SERVER_URL).await
+        {
+            let (mut sink, stream) = ws_stream.split();
+            backoff.reset();
+            while doc_cmd_rx.try_recv().is_ok() {}
+
+            let resync = {
+                let mut client_state = client_state.lock().unwrap();
+                client_state.online = true;
+                client_state.replica.requeue_unacked();
+                let _ = client_state.print_status(&mut stdout());
+                let clock = client_state.replica.document().get_read_ctx();
+                let mut cmds = vec![Command::Resume {
+                    clock: clock.add_clock,
+                }];
+                cmds.extend(client_state.outgoing());
+                cmds
+            };
+
+            let mut sent = HashSet::new();
+            let mut resynced = true;
+            for cmd in resync {
+                if let Command::Apply { op } = &cmd {
+                    sent.insert(OpId::of(op));
+                }
+                if send_command(&mut sink, &cmd).await.is_err() {
+                    resynced = false;
+                    break;
+                }
+            }
+
+            if resynced {
+                let recv = receive_responses(
+                    client_state.clone(),
+                    stream,
+                    ack_tx.clone(),
+                )
+                .fuse();
+                let send =
+                    send_cmds_handler(&mut doc_cmd_rx, &mut sent, sink).fuse();
+                futures::pin_mut!(recv, send);
+                futures::select! {
+                    _ = recv => (),
+                    _ = send => (),
+                }
+            }
+
+            let mut client_state = client_state.lock().unwrap();
+            client_state.online = false;
+            let _ = client_state.print_status(&mut stdout());
+        }
+
+        let delay = tokio::time::delay_for(backoff.next_delay()).fuse();
+        let discard = async { while doc_cmd_rx.recv().await.is_some() {} };
+        futures::pin_mut!(delay);
+        futures::select! {
+            _ = delay => (),
+            _ = discard.fuse() => (),
+        }
+    }
+}
+
+async fn receive_responses(
+    client_state: Arc<Mutex<ClientState>>,
+    mut stream: impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+    mut ack_tx: mpsc::Sender<Command>,
+) {
+    let mut stdout = stdout();
+    while let Some(result) = stream.next().await {
+        if let Ok(Message::Binary(input)) = result {
+            if let Some(doc_resp) = DocResponse::from_bytes(&input) {
+                let cmds = {
+                    let mut client_state = client_state.lock().unwrap();
+                    let cmds =
+                        client_state.handle_response(doc_resp, &mut stdout);
+                    let _ = client_state.print_status(&mut stdout);
+                    cmds
+                };
+                for cmd in cmds {
+                    if ack_tx.send(cmd).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
 async fn menu_handler<W: Write>(
     mut menu_state: MenuState,
+    client_state: Arc<Mutex<ClientState>>,
     mut doc_cmd_tx: mpsc::Sender<Command>,
     mut write: W,
 ) -> Result<(), Box<dyn std::error::Error>> {
     while let Some(cmd) = menu_state.menu_cmd_rx.recv().await {
+        if let Some(prompt) = menu_state.prompt.as_mut() {
+            match cmd {
+                MenuInput::Quit => break,
+                MenuInput::Char(c) => prompt.input_mut().push(c),
+                MenuInput::Backspace => {
+                    prompt.input_mut().pop();
+                }
+                MenuInput::Enter => {
+                    let doc_cmds = match menu_state.submit_prompt() {
+                        Some(Prompt::Key { input })
+                            if menu_state.selected().is_record_query() =>
+                        {
+                            Key::parse_key(&input)
+                                .and_then(|key| {
+                                    menu_state.selected().record_query(key)
+                                })
+                                .into_iter()
+                                .collect()
+                        }
+                        Some(prompt) => {
+                            let mut client_state = client_state.lock().unwrap();
+                            if client_state
+                                .edit(menu_state.selected(), prompt)
+                                .is_none()
+                            {
+                                print_at(5, 5, "Nothing edited", &mut write)?;
+                            }
+                            client_state.save(&mut write)?;
+                            client_state.print_status(&mut write)?;
+                            // queued edits are flushed on reconnect
+                            if client_state.online {
+                                client_state.outgoing()
+                            } else {
+                                Vec::new()
+                            }
+                        }
+                        None => Vec::new(),
+                    };
+                    for doc_cmd in doc_cmds {
+                        doc_cmd_tx.send(doc_cmd).await?;
+                    }
+                }
+                _ => (),
+            }
+            menu_state.print_prompt(&mut write)?;
+            continue;
+        }
+
         match cmd {
             MenuInput::Quit => break,
+            MenuInput::Enter if menu_state.selected().needs_key() => {
+                menu_state.prompt = Some(Prompt::Key {
+                    input: String::new(),
+                });
+                menu_state.print_prompt(&mut write)?;
+            }
+            MenuInput::Enter
+                if menu_state.selected() == MenuItem::CreateRecord =>
+            {
+                menu_state.prompt = Some(Prompt::Entries {
+                    key: None,
+                    lines: Vec::new(),
+                    input: String::new(),
+                });
+                menu_state.print_prompt(&mut write)?;
+            }
             MenuInput::Enter => {
-                if let Some(doc_cmd) = menu_state.choice_to_command() {
-                    doc_cmd_tx.send(doc_cmd).await?;
+                if let Some(doc_cmd) = menu_state.selected().command() {
+                    let doc_cmd = {
+                        let mut client_state = client_state.lock().unwrap();
+                        if client_state.online {
+                            Some(client_state.prepare(doc_cmd))
+                        } else {
+                            None
+                        }
+                    };
+                    match doc_cmd {
+                        Some(doc_cmd) => doc_cmd_tx.send(doc_cmd).await?,
+                        None => print_at(5, 5, "Offline", &mut write)?,
+                    }
                 }
             }
             _ => {
                 menu_state.apply_command(cmd);
                 menu_state.print_menu(&mut write)?;
+                client_state.lock().unwrap().print_status(&mut write)?;
             }
         }
     }
     Ok(())
 }
 
+async fn send_command(
+    sink: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    cmd: &Command,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bytes = bincode::serialize(cmd)?;
+    sink.send(Message::binary(bytes)).await?;
+    sink.flush().await?;
+    Ok(())
+}
+
+// Ops already resent on this connection when it came up are skipped.
 async fn send_cmds_handler(
-    mut doc_cmd_rx: mpsc::Receiver<Command>,
-    mut sink: impl Sink<Bytes, Error = io::Error> + Unpin,
+    doc_cmd_rx: &mut mpsc::Receiver<Command>,
+    sent: &mut HashSet<OpId>,
+    mut sink: impl Sink<Message, Error = tungstenite::Error> + Unpin,
 ) -> Result<(), Box<dyn std::error::Error>> {
     while let Some(cmd) = doc_cmd_rx.recv().await {
-        let bytes = bincode::serialize(&cmd)?;
-        sink.send(Bytes::from(bytes)).await?;
-        sink.flush().await?;
+        if let Command::Apply { op } = &cmd {
+            if !sent.insert(OpId::of(op)) {
+                continue;
+            }
+        }
+        send_command(&mut sink, &cmd).await?;
     }
 
     Ok(())
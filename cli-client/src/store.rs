@@ -0,0 +1,66 @@
+use crdts_sandbox_lib::document::{self, DocActor, DocumentOp};
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use super::{DocReplica, Key};
+
+type Document = document::Document<Key>;
+
+const DOCUMENT_FILE: &str = "document.bin";
+const QUEUE_FILE: &str = "queue.bin";
+
+/// Keeps the local replica on disk, so edits made while offline survive a
+/// restart: the document in one file, the unacknowledged ops in another.
+#[derive(Debug, Clone)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Store {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    pub fn load(&self, actor: DocActor) -> io::Result<DocReplica> {
+        let doc: Document = match read_file(&self.dir.join(DOCUMENT_FILE))? {
+            Some(doc) => doc,
+            None => return Ok(DocReplica::new(actor)),
+        };
+        let queue: Vec<DocumentOp<Key>> =
+            read_file(&self.dir.join(QUEUE_FILE))?.unwrap_or_default();
+        Ok(DocReplica::restore(actor, doc, queue))
+    }
+
+    pub fn save(&self, replica: &DocReplica) -> io::Result<()> {
+        write_file(&self.dir.join(DOCUMENT_FILE), replica.document())?;
+        write_file(&self.dir.join(QUEUE_FILE), &replica.unacked_ops())
+    }
+}
+
+fn read_file<T: serde::de::DeserializeOwned>(
+    path: &Path,
+) -> io::Result<Option<T>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    bincode::deserialize(&bytes)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// Writes to a temporary file first so a crash never leaves a torn file.
+fn write_file<T: serde::Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let bytes = bincode::serialize(value)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(tmp, path)
+}
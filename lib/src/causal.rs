@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+
+use crdts::{map::Op, CmRDT, CvRDT, Dot, VClock};
+
+use crate::document::{DocActor, DocKey, DocumentOp, RecordKey};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Ready,
+    Pending,
+    Duplicate,
+}
+
+/// The clock a replica must have reached before `op` can be applied.
+///
+/// A `map::Op` doesn't carry the full context it was created in, only its
+/// own dot and, for removes, the clock they were made under. So an update
+/// waits for the previous op of the same actor and for everything its
+/// remove (if any) has seen; a map remove waits for its clock.
+pub fn dependencies<K: DocKey>(op: &DocumentOp<K>) -> VClock<DocActor> {
+    let mut deps = VClock::new();
+    match op {
+        Op::Up { dot, op, .. } => {
+            let previous = dot.counter.saturating_sub(1);
+            deps.apply(Dot::new(dot.actor, previous));
+            if let crdts::orswot::Op::Rm { clock, .. } = op {
+                for rm_dot in clock.iter() {
+                    let counter = if *rm_dot.actor == dot.actor {
+                        rm_dot.counter.min(previous)
+                    } else {
+                        rm_dot.counter
+                    };
+                    deps.apply(Dot::new(*rm_dot.actor, counter));
+                }
+            }
+        }
+        Op::Rm { clock, .. } => deps = clock.clone(),
+    }
+    deps
+}
+
+pub fn delivery<K: DocKey>(
+    clock: &VClock<DocActor>,
+    op: &DocumentOp<K>,
+) -> Delivery {
+    if let Op::Up { dot, .. } = op {
+        if clock.get(&dot.actor) >= dot.counter {
+            return Delivery::Duplicate;
+        }
+    }
+
+    if &dependencies(op) <= clock {
+        Delivery::Ready
+    } else {
+        Delivery::Pending
+    }
+}
+
+/// Holds ops back until the causal history they depend on has been
+/// applied, so that ops received out of order are applied in causal order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct CausalBuffer<K: DocKey = RecordKey> {
+    pending: Vec<DocumentOp<K>>,
+    duplicates: u64,
+}
+
+impl<K: DocKey> Default for CausalBuffer<K> {
+    fn default() -> Self {
+        CausalBuffer {
+            pending: Vec::new(),
+            duplicates: 0,
+        }
+    }
+}
+
+impl<K: DocKey> CausalBuffer<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes in `op`, received by a replica at `clock`, and returns every op
+    /// that can now be applied, in the order they must be applied.
+    /// Duplicates, of applied or of pending ops, are dropped.
+    pub fn deliver(
+        &mut self,
+        clock: &VClock<DocActor>,
+        op: DocumentOp<K>,
+    ) -> Vec<DocumentOp<K>> {
+        match delivery(clock, &op) {
+            Delivery::Duplicate => {
+                self.duplicates += 1;
+                return Vec::new();
+            }
+            Delivery::Pending => {
+                if self.pending.contains(&op) {
+                    self.duplicates += 1;
+                } else {
+                    self.pending.push(op);
+                }
+                return Vec::new();
+            }
+            Delivery::Ready => (),
+        }
+
+        let mut clock = clock.clone();
+        if let Op::Up { dot, .. } = &op {
+            clock.apply(*dot);
+        }
+        let mut ready = vec![op];
+        ready.append(&mut self.release(&clock));
+        ready
+    }
+
+    /// Takes out every pending op that can be applied once a replica has
+    /// reached `clock`, in the order they must be applied.
+    pub fn release(&mut self, clock: &VClock<DocActor>) -> Vec<DocumentOp<K>> {
+        let mut clock = clock.clone();
+        let mut ready = Vec::new();
+        loop {
+            let mut released = false;
+            let pending = std::mem::take(&mut self.pending);
+            for op in pending {
+                match delivery(&clock, &op) {
+                    Delivery::Ready => {
+                        if let Op::Up { dot, .. } = &op {
+                            clock.apply(*dot);
+                        }
+                        ready.push(op);
+                        released = true;
+                    }
+                    Delivery::Pending => self.pending.push(op),
+                    Delivery::Duplicate => self.duplicates += 1,
+                }
+            }
+            if !released {
+                break;
+            }
+        }
+
+        ready
+    }
+
+    pub fn pending(&self) -> &[DocumentOp<K>] {
+        &self.pending
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The dots pending ops are still waiting for, beyond `clock`.
+    pub fn missing(&self, clock: &VClock<DocActor>) -> VClock<DocActor> {
+        let mut missing = VClock::new();
+        for op in self.pending.iter() {
+            missing.merge(dependencies(op).clone_without(clock));
+        }
+        missing
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::fixtures::add;
+
+    #[test]
+    fn ops_received_out_of_order_are_released_in_causal_order() {
+        let mut doc = Document::default();
+        let ops: Vec<_> = (0..3).map(|key| add(&mut doc, key, 1)).collect();
+
+        let mut buffer = CausalBuffer::new();
+        let clock = VClock::new();
+        assert!(buffer.deliver(&clock, ops[2].clone()).is_empty());
+        assert!(buffer.deliver(&clock, ops[1].clone()).is_empty());
+        assert_eq!(buffer.missing(&clock).get(&1), 2);
+
+        assert_eq!(buffer.deliver(&clock, ops[0].clone()), ops);
+        assert_eq!(buffer.pending_len(), 0);
+    }
+
+    #[test]
+    fn removes_wait_for_the_adds_they_have_seen() {
+        let mut doc = Document::default();
+        let added = add(&mut doc, 0, 1);
+        let removed = doc.remove_record(0);
+
+        let mut buffer = CausalBuffer::new();
+        let clock = VClock::new();
+        assert!(buffer.deliver(&clock, removed.clone()).is_empty());
+        assert_eq!(buffer.missing(&clock).get(&1), 1);
+        assert_eq!(buffer.deliver(&clock, added.clone()), vec![added, removed]);
+    }
+
+    #[test]
+    fn duplicates_are_dropped_and_counted() {
+        let mut doc = Document::default();
+        let first = add(&mut doc, 0, 1);
+        let second = add(&mut doc, 1, 1);
+
+        let mut buffer = CausalBuffer::new();
+        let mut clock = VClock::new();
+        assert!(buffer.deliver(&clock, second.clone()).is_empty());
+        assert!(buffer.deliver(&clock, second.clone()).is_empty());
+        assert_eq!(buffer.pending_len(), 1);
+
+        assert_eq!(buffer.deliver(&clock, first.clone()).len(), 2);
+        clock.apply(Dot::new(1, 2));
+        assert!(buffer.deliver(&clock, first).is_empty());
+        assert_eq!(buffer.duplicates(), 2);
+    }
+}
@@ -1,46 +1,144 @@
+pub mod blame;
+pub mod canonical;
+pub mod entry;
+pub mod gc;
 pub mod item;
+pub mod key;
+pub mod merkle;
+pub mod paging;
+pub mod parts;
+pub mod query;
+pub mod replica;
+pub mod shard;
 
 use serde::{Deserialize, Serialize};
 
+use crate::oplog::{HistoryEntry, LogStats, OpId};
+
 use serde_json;
 
 use crdts::{
-    ctx::{AddCtx, ReadCtx, RmCtx},
+    ctx::{AddCtx, ReadCtx},
     map::Op,
-    Actor, Causal, CmRDT, CvRDT, FunkyCmRDT, FunkyCvRDT, LWWReg, Map, Orswot,
-    VClock,
+    CmRDT, Map, Orswot, VClock,
 };
 
-use bstr::{ByteSlice, ByteVec};
+pub use blame::{Blamed, EntryBlame};
+pub use canonical::HashMatch;
+pub use entry::{ContentType, RecordEntry};
+pub use gc::{stable_clock, GcReport};
+pub use item::Item;
+pub use key::DocKey;
+pub use merkle::{
+    Digest, DigestRange, DigestWalk, Hash, MerkleTree, RecordSet,
+};
+pub use paging::{DocumentBuilder, PagedRecord};
+pub use parts::{Parts, PARTS_PER_SHARD};
+pub use query::JsonFilter;
+pub use replica::DocReplica;
+pub use shard::{shard_of, split_op, split_record_set};
 
 pub type DocActor = u32;
-pub type RecordKey = u32;
-pub type RecordEntry = Vec<u8>;
+
+pub const SERVER_ACTOR: DocActor = 0;
+pub type RecordKey = u64;
 pub type OrswotRecord = Orswot<RecordEntry, DocActor>;
-pub type RecordMap = Map<u32, Orswot<RecordEntry, DocActor>, DocActor>;
+pub type RecordMap<K = RecordKey> = Map<K, OrswotRecord, DocActor>;
 
-// pub type DocumentOp = Op<u64, Orswot<RecordEntry, u32>, u32>;
-pub type DocumentOp = Op<u32, OrswotRecord, u32>;
+pub type DocumentOp<K = RecordKey> = Op<K, OrswotRecord, DocActor>;
 pub type RecordOp = crdts::orswot::Op<RecordEntry, DocActor>;
 
+/// The clock of the record or entry a read returned: crdts hands it back
+/// as the read's rm clock, the clock a remove of it has to cover.
+pub(crate) fn own_clock<V>(ctx: &ReadCtx<V, DocActor>) -> &VClock<DocActor> {
+    &ctx.rm_clock
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Command {
-    GetDocument,
+#[serde(bound = "")]
+pub enum Command<K: DocKey = RecordKey> {
+    /// With a clock, the server answers `NotModified` if the clock
+    /// dominates its own. Removing a whole record doesn't advance the
+    /// clock, so such removals are only picked up through `Resume`.
+    GetDocument {
+        clock: Option<VClock<DocActor>>,
+    },
     GetRecord {
-        key: RecordKey,
+        key: K,
     },
     GetReadCtx,
     Add {
         add_ctx: AddCtx<DocActor>,
-        key: RecordKey,
-        content: String,
+        key: K,
+        item: Item<RecordEntry>,
     },
     Apply {
-        op: DocumentOp,
+        op: DocumentOp<K>,
+    },
+    CreateRecord {
+        initial: Item<RecordEntry>,
+    },
+    AckClock {
+        actor: DocActor,
+        clock: VClock<DocActor>,
+    },
+    CollectGarbage,
+    GetStats,
+    Resume {
+        clock: VClock<DocActor>,
+    },
+    GetRecords {
+        after_key: Option<K>,
+        limit: u32,
+    },
+    /// Sends the document as `RecordChunk`s of up to `chunk_size` records,
+    /// followed by `DocumentEnd`.
+    StreamDocument {
+        chunk_size: u32,
+    },
+    /// Merges a full copy of the document, as sent by a peer server whose
+    /// op log can't bring this one up to date.
+    Merge {
+        doc: Document<K>,
+    },
+    /// Asks for one node of the hash tree over the records, to find the
+    /// records that differ from another replica a level at a time.
+    GetDigest {
+        range: DigestRange,
+    },
+    GetRecordSet {
+        keys: Vec<K>,
+    },
+    /// Merges records that were found to differ, see
+    /// `Document::merge_records`.
+    MergeRecords {
+        set: RecordSet<K>,
+    },
+    /// Asks for the document's content and state hashes, to compare with
+    /// a replica's own.
+    GetHashes,
+    /// Asks who keeps each entry of the record at `key` alive, see
+    /// `Document::blame`.
+    Blame {
+        key: K,
+    },
+    /// Asks for up to `limit` of the logged ops that touched the record at
+    /// `key`, the latest ones before `before` in its history, or the
+    /// latest ones overall without it.
+    GetHistory {
+        key: K,
+        limit: u32,
+        before: Option<u64>,
+    },
+    /// Asks a shard's writer for the ops it logged from position `from`
+    /// on, see `OpLog::position`, so a server only pushes its peers the
+    /// ops logged since it last did.
+    GetLog {
+        from: u64,
     },
 }
 
-impl Command {
+impl<K: DocKey> Command<K> {
     pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
         bincode::deserialize(bytes).ok()
     }
@@ -51,13 +149,76 @@ impl Command {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum DocResponse {
-    Document(Document),
+#[serde(bound = "")]
+pub enum DocResponse<K: DocKey = RecordKey> {
+    Document(Document<K>),
+    NotModified,
     Record(ReadCtx<Option<OrswotRecord>, DocActor>),
     ReadCtx(ReadCtx<(), u32>),
+    RecordCreated {
+        key: K,
+    },
+    /// The command wasn't carried out, for `reason`.
+    Rejected {
+        reason: String,
+    },
+    GarbageCollected {
+        stable: VClock<DocActor>,
+        report: GcReport,
+    },
+    Ack {
+        id: OpId<K>,
+        duplicate: bool,
+    },
+    Stats(LogStats),
+    Missed {
+        ops: Vec<DocumentOp<K>>,
+    },
+    Records {
+        records: Vec<PagedRecord<K>>,
+        next: Option<K>,
+    },
+    RecordChunk {
+        records: Vec<PagedRecord<K>>,
+        sent: u64,
+        total: u64,
+    },
+    DocumentEnd {
+        read_ctx: ReadCtx<(), DocActor>,
+    },
+    Digest(Digest<K>),
+    RecordSet(RecordSet<K>),
+    /// See `Document::content_hash` and `Document::state_hash`; `clock`
+    /// is the clock of the document they were computed from.
+    Hashes {
+        content: Hash,
+        state: Hash,
+        clock: VClock<DocActor>,
+    },
+    /// The entries of the record at `key`, with receive times from the
+    /// server's op log where it still has them.
+    Blame {
+        key: K,
+        entries: Vec<EntryBlame>,
+    },
+    /// Ops in the history of the record at `key`, in causal order;
+    /// `earlier` is what to pass as `before` for the ops preceding them,
+    /// if there are any. The history only goes back as far as the
+    /// server's op log.
+    History {
+        key: K,
+        entries: Vec<HistoryEntry<K>>,
+        earlier: Option<u64>,
+    },
+    /// The ops a shard logged from the position asked for on, and the
+    /// position to ask from next time.
+    Log {
+        ops: Vec<DocumentOp<K>>,
+        next: u64,
+    },
 }
 
-impl DocResponse {
+impl<K: DocKey> DocResponse<K> {
     pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
         bincode::deserialize(bytes).ok()
     }
@@ -67,46 +228,33 @@ impl DocResponse {
     }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
-pub struct Document {
-    pub records: RecordMap,
-}
-
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
-pub struct DocReplica {
-    doc: Document,
-    actor: DocActor,
-    log: Vec<DocumentOp>,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Document<K: DocKey = RecordKey> {
+    pub records: RecordMap<K>,
 }
 
-impl DocReplica {
-    pub fn from_document(actor: DocActor, doc: &Document) -> Self {
-        DocReplica {
-            doc: doc.clone(),
-            actor,
-            log: Vec::new(),
+impl<K: DocKey> Default for Document<K> {
+    fn default() -> Self {
+        Document {
+            records: Map::new(),
         }
     }
-
-    pub fn apply_op(&mut self, op: DocumentOp) {
-        self.log.push(op.clone());
-        self.doc.records.apply(op);
-    }
 }
 
-impl Document {
-    pub fn example() -> Self {
-        let records: Map<u32, Orswot<Vec<u8>, u32>, u32> = Map::new();
+impl<K: DocKey> Document<K> {
+    pub fn example(key: K) -> Self {
+        let records: RecordMap<K> = Map::new();
 
         let mut doc = Document { records };
 
         let read_ctx = doc.get_read_ctx();
         let op =
-            doc.update_record(1, read_ctx.derive_add_ctx(0), |set, ctx| {
-                let items: Vec<Vec<u8>> = vec![
-                    Vec::from("thing 1"),
-                    Vec::from("another thing"),
-                    Vec::from("who knows what this is"),
+            doc.update_record(key, read_ctx.derive_add_ctx(0), |set, ctx| {
+                let items: Vec<RecordEntry> = vec![
+                    RecordEntry::text("thing 1"),
+                    RecordEntry::text("another thing"),
+                    RecordEntry::text("who knows what this is"),
                 ];
                 set.add_all(items, ctx)
             });
@@ -117,37 +265,87 @@ impl Document {
     }
 
     pub fn update_record<F>(
-        &mut self,
-        key: u32,
+        &self,
+        key: K,
         ctx: AddCtx<DocActor>,
         f: F,
-    ) -> DocumentOp
+    ) -> DocumentOp<K>
     where
         F: FnOnce(&OrswotRecord, AddCtx<DocActor>) -> RecordOp,
     {
         self.records.update(key, ctx, f)
     }
 
+    pub fn add_item(
+        &self,
+        key: K,
+        ctx: AddCtx<DocActor>,
+        item: Item<RecordEntry>,
+    ) -> DocumentOp<K> {
+        self.update_record(key, ctx, |set, ctx| match item {
+            Item::Single(entry) => set.add(entry, ctx),
+            Item::Multiple(entries) => set.add_all(entries, ctx),
+        })
+    }
+
+    /// Adds `initial` to a new record whose key is derived from the dot of
+    /// the add, so replicas can create records offline without colliding.
+    /// `None` if the actor's counter has outgrown generated keys.
+    pub fn create_record(
+        &self,
+        actor: DocActor,
+        initial: Item<RecordEntry>,
+    ) -> Option<(K, DocumentOp<K>)> {
+        let add_ctx = self.get_read_ctx().derive_add_ctx(actor);
+        let key = K::from_dot(&add_ctx.dot)?;
+        let op = self.add_item(key.clone(), add_ctx, initial);
+        Some((key, op))
+    }
+
+    /// Whether a user may write to `key`: generated keys only once
+    /// `create_record` has made their record.
+    pub fn can_write(&self, key: &K) -> bool {
+        !key.is_generated() || self.records.get(key).val.is_some()
+    }
+
+    /// Removes `entries` from the record, as far as this document has
+    /// seen them.
+    pub fn remove_entries(
+        &self,
+        key: K,
+        ctx: AddCtx<DocActor>,
+        entries: Vec<RecordEntry>,
+    ) -> DocumentOp<K> {
+        self.update_record(key, ctx, |set, _| {
+            set.rm_all(entries, set.read_ctx().derive_rm_ctx())
+        })
+    }
+
+    pub fn remove_record(&self, key: K) -> DocumentOp<K> {
+        let rm_ctx = self.records.get(&key).derive_rm_ctx();
+        self.records.rm(key, rm_ctx)
+    }
+
     pub fn get_read_ctx(&self) -> ReadCtx<(), u32> {
         self.records.read_ctx()
     }
 
     pub fn get_record(
         &self,
-        key: u32,
+        key: &K,
     ) -> ReadCtx<Option<OrswotRecord>, DocActor> {
-        self.records.get(&key)
+        self.records.get(key)
     }
 
-    pub fn apply(&mut self, op: DocumentOp) {
+    pub fn apply(&mut self, op: DocumentOp<K>) {
         self.records.apply(op)
     }
 
-    pub fn doc_keys(&self) -> impl Iterator<Item = ReadCtx<&u32, DocActor>> {
+    pub fn doc_keys(&self) -> impl Iterator<Item = ReadCtx<&K, DocActor>> {
         self.records.keys()
     }
 
-    pub fn keys_vec(&self) -> Vec<ReadCtx<&u32, DocActor>> {
+    pub fn keys_vec(&self) -> Vec<ReadCtx<&K, DocActor>> {
         self.records.keys().collect()
     }
 
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+
+use crdts::Dot;
+
+use std::fmt;
+
+use super::{own_clock, DocActor, DocKey, Document, OrswotRecord, RecordEntry};
+
+/// The dot of an add that keeps an entry alive, and when the server
+/// received the add, in microseconds since the Unix epoch, if it knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Blamed {
+    pub dot: Dot<DocActor>,
+    pub received: Option<u64>,
+}
+
+impl fmt::Display for Blamed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "actor {} ({})", self.dot.actor, self.dot.counter)?;
+        if let Some(received) = self.received {
+            write_received(f, received)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a receive time, in microseconds since the Unix epoch, as the
+/// seconds since the epoch to the millisecond.
+pub(crate) fn write_received(
+    f: &mut fmt::Formatter<'_>,
+    received: u64,
+) -> fmt::Result {
+    let (secs, micros) = (received / 1_000_000, received % 1_000_000);
+    write!(f, " at {}.{:03}", secs, micros / 1000)
+}
+
+/// An entry of a record and the adds keeping it alive. An entry added
+/// concurrently by several actors, or added again by one, has a dot for
+/// each add that no remove has seen.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryBlame {
+    pub entry: RecordEntry,
+    pub dots: Vec<Blamed>,
+}
+
+/// The entries of `record` in the order they are displayed in, each with
+/// its dots in order of actor, taking receive times from `received`.
+pub fn blame_record<F>(record: &OrswotRecord, received: F) -> Vec<EntryBlame>
+where
+    F: Fn(&Dot<DocActor>) -> Option<u64>,
+{
+    let mut blame: Vec<_> = record
+        .read()
+        .val
+        .into_iter()
+        .map(|entry| {
+            let dots = own_clock(&record.contains(&entry))
+                .iter()
+                .map(|dot| {
+                    let dot = Dot::new(*dot.actor, dot.counter);
+                    Blamed {
+                        received: received(&dot),
+                        dot,
+                    }
+                })
+                .collect();
+            EntryBlame { entry, dots }
+        })
+        .collect();
+    for entry in blame.iter_mut() {
+        entry.dots.sort_by_key(|blamed| blamed.dot.actor);
+    }
+    blame.sort_by_cached_key(|blame| blame.entry.display());
+    blame
+}
+
+impl<K: DocKey> Document<K> {
+    /// Blames the entries of the record at `key`, see `blame_record`;
+    /// a record that doesn't exist has no entries.
+    pub fn blame<F>(&self, key: &K, received: F) -> Vec<EntryBlame>
+    where
+        F: Fn(&Dot<DocActor>) -> Option<u64>,
+    {
+        match self.get_record(key).val {
+            Some(record) => blame_record(&record, received),
+            None => Vec::new(),
+        }
+    }
+}
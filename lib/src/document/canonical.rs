@@ -0,0 +1,278 @@
+use crdts::{ctx::ReadCtx, VClock};
+
+use super::merkle::{sha1, Hash};
+use super::{own_clock, DocActor, DocKey, Document, OrswotRecord, RecordEntry};
+
+// Encodings of document state that don't depend on the iteration order of
+// the hash maps inside the crdts, so equal states always encode to equal
+// bytes. Integers are big endian and variable length fields are prefixed
+// with their length.
+
+fn write_u32(n: u32, out: &mut Vec<u8>) {
+    out.extend_from_slice(&n.to_be_bytes());
+}
+
+fn write_u64(n: u64, out: &mut Vec<u8>) {
+    out.extend_from_slice(&n.to_be_bytes());
+}
+
+fn write_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    write_u64(bytes.len() as u64, out);
+    out.extend_from_slice(bytes);
+}
+
+/// Dots in actor order.
+pub fn write_clock(clock: &VClock<DocActor>, out: &mut Vec<u8>) {
+    let mut dots: Vec<_> =
+        clock.iter().map(|dot| (*dot.actor, dot.counter)).collect();
+    dots.sort_unstable();
+    write_u32(dots.len() as u32, out);
+    for (actor, counter) in dots {
+        write_u32(actor, out);
+        write_u64(counter, out);
+    }
+}
+
+/// The content type as its MIME string, empty if there is none.
+pub fn write_entry(entry: &RecordEntry, out: &mut Vec<u8>) {
+    let mime = entry.content_type.as_ref().map(|ty| ty.as_mime());
+    write_bytes(mime.unwrap_or("").as_bytes(), out);
+    write_bytes(&entry.bytes, out);
+}
+
+/// The key as displayed and the clock of the record's map entry, then the
+/// record as `encode_orswot` has it.
+pub fn encode_record<K: DocKey>(
+    key: &K,
+    entry_clock: &VClock<DocActor>,
+    record: &OrswotRecord,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_bytes(key.to_string().as_bytes(), &mut out);
+    write_clock(entry_clock, &mut out);
+    out.extend(encode_orswot(record));
+    out
+}
+
+/// The record's own clock and its entries, in the order of their
+/// encodings, each followed by the clock of the dots keeping it alive.
+pub fn encode_orswot(record: &OrswotRecord) -> Vec<u8> {
+    let mut entries: Vec<_> = record
+        .read()
+        .val
+        .into_iter()
+        .map(|entry| {
+            let mut encoded = Vec::new();
+            write_entry(&entry, &mut encoded);
+            (encoded, own_clock(&record.contains(&entry)).clone())
+        })
+        .collect();
+    entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut out = Vec::new();
+    write_clock(&record.clock(), &mut out);
+    write_u32(entries.len() as u32, &mut out);
+    for (encoded, clock) in entries {
+        out.extend_from_slice(&encoded);
+        write_clock(&clock, &mut out);
+    }
+    out
+}
+
+/// The key as displayed, then the record's entries in the order of their
+/// encodings, without any of the clocks.
+pub fn encode_content<K: DocKey>(key: &K, record: &OrswotRecord) -> Vec<u8> {
+    let mut entries: Vec<_> = record
+        .read()
+        .val
+        .iter()
+        .map(|entry| {
+            let mut encoded = Vec::new();
+            write_entry(entry, &mut encoded);
+            encoded
+        })
+        .collect();
+    entries.sort_unstable();
+
+    let mut out = Vec::new();
+    write_bytes(key.to_string().as_bytes(), &mut out);
+    write_u32(entries.len() as u32, &mut out);
+    for encoded in entries {
+        out.extend_from_slice(&encoded);
+    }
+    out
+}
+
+// records in the order of their encodings, which start with the key as
+// displayed, so the order doesn't depend on how the key type sorts
+fn write_records(mut records: Vec<Vec<u8>>, out: &mut Vec<u8>) {
+    records.sort_unstable();
+    write_u64(records.len() as u64, out);
+    for record in records {
+        out.extend_from_slice(&record);
+    }
+}
+
+/// See `Document::canonical_content`, for `records` in any order.
+pub fn content_of<'a, K, I>(records: I) -> Vec<u8>
+where
+    K: DocKey + 'a,
+    I: IntoIterator<Item = ReadCtx<(&'a K, &'a OrswotRecord), DocActor>>,
+{
+    let records = records
+        .into_iter()
+        .map(|ctx| {
+            let (key, record) = ctx.val;
+            encode_content(key, record)
+        })
+        .collect();
+    let mut out = Vec::new();
+    write_records(records, &mut out);
+    out
+}
+
+/// See `Document::canonical_state`, for the records of a document at
+/// `clock` in any order.
+pub fn state_of<'a, K, I>(clock: &VClock<DocActor>, records: I) -> Vec<u8>
+where
+    K: DocKey + 'a,
+    I: IntoIterator<Item = ReadCtx<(&'a K, &'a OrswotRecord), DocActor>>,
+{
+    let records = records
+        .into_iter()
+        .map(|ctx| {
+            let (key, record) = ctx.val;
+            encode_record(key, own_clock(&ctx), record)
+        })
+        .collect();
+    let mut out = Vec::new();
+    write_clock(clock, &mut out);
+    write_records(records, &mut out);
+    out
+}
+
+/// How a document compares with another replica's hashes of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashMatch {
+    /// Same content, and the same ops seen.
+    State,
+    /// Same content, reached through different ops.
+    Content,
+    Differs,
+}
+
+impl<K: DocKey> Document<K> {
+    pub fn canonical_record(&self, key: &K) -> Option<Vec<u8>> {
+        let ctx = self.get_record(key);
+        Some(encode_record(key, own_clock(&ctx), ctx.val.as_ref()?))
+    }
+
+    /// What a reader sees: every record and its entries. Replicas that
+    /// got there through different ops, or hold different tombstones,
+    /// still encode the same.
+    pub fn canonical_content(&self) -> Vec<u8> {
+        content_of(self.records.iter())
+    }
+
+    /// The content along with its causal context: the document's clock,
+    /// then every record with the dots keeping it and its entries alive.
+    /// Removes that are deferred until ops they depend on arrive aren't
+    /// included, since garbage collection drops them at different times
+    /// on different replicas.
+    pub fn canonical_state(&self) -> Vec<u8> {
+        state_of(&self.get_read_ctx().add_clock, self.records.iter())
+    }
+
+    /// Equal for replicas that show the same records and entries.
+    pub fn content_hash(&self) -> Hash {
+        sha1(&self.canonical_content())
+    }
+
+    /// Equal for replicas that have seen the same ops, see
+    /// `canonical_state`.
+    pub fn state_hash(&self) -> Hash {
+        sha1(&self.canonical_state())
+    }
+
+    pub fn compare_hashes(&self, content: &Hash, state: &Hash) -> HashMatch {
+        if &self.content_hash() != content {
+            HashMatch::Differs
+        } else if &self.state_hash() == state {
+            HashMatch::State
+        } else {
+            HashMatch::Content
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{entry::hex_bytes, RecordKey};
+    use crate::fixtures::add_op;
+
+    // The encodings are compared across replicas and stored in logs, so
+    // they must not change for the same document.
+    #[test]
+    fn hashes_are_stable() {
+        let doc = Document::example(7);
+        assert_eq!(
+            hex_bytes(&doc.content_hash()),
+            "4d5ef943b6fe8c4cf8833ac48e88633e87f81776"
+        );
+        assert_eq!(
+            hex_bytes(&doc.state_hash()),
+            "0385a76755eb7d3fdf98c64d3f12424fe8bfe9e3"
+        );
+    }
+
+    #[test]
+    fn delivery_order_doesnt_change_the_state() {
+        let base = Document::example(1);
+        let ops: Vec<_> = (2..6)
+            .map(|actor| add_op(&base, actor as RecordKey % 2, actor))
+            .collect();
+
+        let mut forward = base.clone();
+        let mut backward = base.clone();
+        for op in ops.iter() {
+            forward.apply(op.clone());
+        }
+        for op in ops.iter().rev() {
+            backward.apply(op.clone());
+        }
+        assert_eq!(forward.canonical_state(), backward.canonical_state());
+
+        let bytes = bincode::serialize(&forward).unwrap();
+        let decoded: Document = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.state_hash(), forward.state_hash());
+        assert_eq!(
+            forward.compare_hashes(
+                &backward.content_hash(),
+                &backward.state_hash()
+            ),
+            HashMatch::State
+        );
+    }
+
+    #[test]
+    fn content_ignores_how_it_was_reached() {
+        let base = Document::default();
+        let mut once = base.clone();
+        once.apply(add_op(&base, 1, 1));
+
+        let mut twice = base.clone();
+        twice.apply(add_op(&twice, 1, 2));
+        twice.apply(twice.remove_record(1));
+        twice.apply(add_op(&twice, 1, 1));
+
+        assert_eq!(once.canonical_content(), twice.canonical_content());
+        assert_ne!(once.state_hash(), twice.state_hash());
+        let (content, state) = (twice.content_hash(), twice.state_hash());
+        assert_eq!(once.compare_hashes(&content, &state), HashMatch::Content);
+
+        twice.apply(add_op(&twice, 2, 2));
+        let (content, state) = (twice.content_hash(), twice.state_hash());
+        assert_eq!(once.compare_hashes(&content, &state), HashMatch::Differs);
+    }
+}
@@ -0,0 +1,308 @@
+use serde::{Deserialize, Serialize};
+
+use serde_json::Value;
+
+use bstr::ByteSlice;
+
+use std::fmt::{self, Write};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ContentType {
+    Text,
+    Json,
+    Png,
+    Other(String),
+}
+
+impl ContentType {
+    pub fn from_mime(mime: &str) -> Self {
+        match mime {
+            "text/plain" => ContentType::Text,
+            "application/json" => ContentType::Json,
+            "image/png" => ContentType::Png,
+            other => ContentType::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_mime(&self) -> &str {
+        match self {
+            ContentType::Text => "text/plain",
+            ContentType::Json => "application/json",
+            ContentType::Png => "image/png",
+            ContentType::Other(mime) => mime,
+        }
+    }
+
+    pub fn is_textual(&self) -> bool {
+        match self {
+            ContentType::Text | ContentType::Json => true,
+            ContentType::Png => false,
+            ContentType::Other(mime) => mime.starts_with("text/"),
+        }
+    }
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_mime())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RecordEntry {
+    pub content_type: Option<ContentType>,
+    pub bytes: Vec<u8>,
+}
+
+impl RecordEntry {
+    pub fn new(content_type: Option<ContentType>, bytes: Vec<u8>) -> Self {
+        RecordEntry {
+            content_type,
+            bytes,
+        }
+    }
+
+    pub fn text(text: &str) -> Self {
+        Self::new(Some(ContentType::Text), Vec::from(text))
+    }
+
+    /// Builds a JSON entry from the canonical encoding of `value`, so that
+    /// equal values always produce equal entries and dedupe in a record.
+    pub fn json(value: &Value) -> Self {
+        Self::new(Some(ContentType::Json), canonical_json(value))
+    }
+
+    pub fn json_from_str(json: &str) -> Option<Self> {
+        let value: Value = serde_json::from_str(json).ok()?;
+        Some(Self::json(&value))
+    }
+
+    pub fn as_json(&self) -> Option<Value> {
+        match self.content_type {
+            Some(ContentType::Json) => serde_json::from_slice(&self.bytes).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn is_textual(&self) -> bool {
+        match &self.content_type {
+            Some(content_type) => content_type.is_textual(),
+            None => self.bytes.is_utf8(),
+        }
+    }
+
+    /// Renders the entry without losing information: textual entries are
+    /// escaped, anything else is shown as hex prefixed by its content type.
+    pub fn display(&self) -> String {
+        if self.is_textual() {
+            escape_bytes(&self.bytes)
+        } else {
+            let content_type = self
+                .content_type
+                .as_ref()
+                .map(ContentType::as_mime)
+                .unwrap_or("bytes");
+            format!(
+                "<{}, {} bytes> {}",
+                content_type,
+                self.bytes.len(),
+                hex_bytes(&self.bytes)
+            )
+        }
+    }
+}
+
+impl From<&str> for RecordEntry {
+    fn from(text: &str) -> Self {
+        RecordEntry::text(text)
+    }
+}
+
+impl From<Vec<u8>> for RecordEntry {
+    fn from(bytes: Vec<u8>) -> Self {
+        RecordEntry::new(None, bytes)
+    }
+}
+
+impl fmt::Display for RecordEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.display())
+    }
+}
+
+impl From<&Value> for RecordEntry {
+    fn from(value: &Value) -> Self {
+        RecordEntry::json(value)
+    }
+}
+
+/// Encodes `value` compactly with object keys in sorted order.
+pub fn canonical_json(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_canonical(value, &mut out);
+    out
+}
+
+fn write_canonical(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Array(values) => {
+            out.push(b'[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write_canonical(value, out);
+            }
+            out.push(b']');
+        }
+        Value::Object(map) => {
+            let mut fields: Vec<_> = map.iter().collect();
+            fields.sort_by_key(|(key, _)| *key);
+            out.push(b'{');
+            for (i, (key, value)) in fields.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                // serializing a string or scalar into a Vec can't fail
+                serde_json::to_writer(&mut *out, key).unwrap();
+                out.push(b':');
+                write_canonical(value, out);
+            }
+            out.push(b'}');
+        }
+        scalar => serde_json::to_writer(&mut *out, scalar).unwrap(),
+    }
+}
+
+/// Escapes control characters, backslashes and invalid UTF-8, the latter as
+/// `\xNN`, so the original bytes can always be recovered from the output.
+pub fn escape_bytes(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                c if c.is_control() => {
+                    out.extend(c.escape_default());
+                }
+                c => out.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            let _ = write!(out, "\\x{:02x}", b);
+        }
+    }
+    out
+}
+
+pub fn hex_bytes(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{:02x}", b);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Document, Item, RecordKey};
+    use std::collections::HashSet;
+
+    #[test]
+    fn equal_json_is_encoded_the_same() {
+        let spaced = r#"{ "b": [1, {"z": null, "y": "\u00e9"}], "a": true }"#;
+        let packed = r#"{"a":true,"b":[1,{"y":"é","z":null}]}"#;
+        let entry = RecordEntry::json_from_str(spaced).unwrap();
+        assert_eq!(entry, RecordEntry::json_from_str(packed).unwrap());
+        assert_eq!(entry.bytes, packed.as_bytes());
+        assert_eq!(entry.as_json(), serde_json::from_str(packed).ok());
+        assert_eq!(RecordEntry::json_from_str("{not json"), None);
+
+        let mut doc = Document::<RecordKey>::default();
+        for json in [spaced, packed].iter() {
+            let ctx = doc.get_read_ctx().derive_add_ctx(1);
+            let entry = RecordEntry::json_from_str(json).unwrap();
+            let op = doc.add_item(7, ctx, Item::Single(entry));
+            doc.apply(op);
+        }
+        let record = doc.get_record(&7).val.unwrap();
+        assert_eq!(record.read().val.len(), 1);
+    }
+
+    #[test]
+    fn escaping_keeps_every_byte_recoverable() {
+        assert_eq!(escape_bytes("plain é".as_bytes()), "plain é");
+        assert_eq!(escape_bytes(b"a\xffb\xc3"), "a\\xffb\\xc3");
+        assert_eq!(
+            escape_bytes(b"tab\there\n\x01\x7f"),
+            "tab\\there\\n\\u{1}\\u{7f}"
+        );
+        assert_eq!(escape_bytes(b"back\\slash"), "back\\\\slash");
+        assert_ne!(escape_bytes(b"\\xff"), escape_bytes(b"\xff"));
+        assert_ne!(escape_bytes(b"\\n"), escape_bytes(b"\n"));
+    }
+
+    #[test]
+    fn hex_is_two_lowercase_digits_a_byte() {
+        assert_eq!(hex_bytes(b""), "");
+        assert_eq!(hex_bytes(&[0x00, 0x0f, 0xab, 0xff]), "000fabff");
+    }
+
+    #[test]
+    fn binary_content_types_are_shown_as_hex() {
+        let png = RecordEntry::new(Some(ContentType::Png), vec![0x89, b'P']);
+        assert_eq!(png.display(), "<image/png, 2 bytes> 8950");
+        let other = ContentType::from_mime("application/octet-stream");
+        let other = RecordEntry::new(Some(other), b"ab".to_vec());
+        assert_eq!(other.display(), "<application/octet-stream, 2 bytes> 6162");
+        let untyped = RecordEntry::from(vec![0xff, 0x00]);
+        assert_eq!(untyped.display(), "<bytes, 2 bytes> ff00");
+
+        let csv = ContentType::from_mime("text/csv");
+        let csv = RecordEntry::new(Some(csv), b"a,\xff".to_vec());
+        assert_eq!(csv.display(), "a,\\xff");
+        assert_eq!(RecordEntry::from(b"a\n".to_vec()).to_string(), "a\\n");
+    }
+
+    #[test]
+    fn content_types_round_trip_through_their_mime_types() {
+        let mimes = ["text/plain", "application/json", "image/png", "text/csv"];
+        for mime in mimes.iter() {
+            let content_type = ContentType::from_mime(mime);
+            assert_eq!(content_type.as_mime(), *mime);
+            assert_eq!(content_type.to_string(), *mime);
+        }
+        assert_eq!(ContentType::from_mime("text/plain"), ContentType::Text);
+        assert!(ContentType::Json.is_textual());
+        assert!(ContentType::from_mime("text/csv").is_textual());
+        assert!(!ContentType::Png.is_textual());
+        assert!(!ContentType::from_mime("application/pdf").is_textual());
+    }
+
+    #[test]
+    fn distinct_bytes_never_display_the_same() {
+        let alphabet = [b'a', b'n', b'x', b'f', b'\\', b'\n', 0xff, 0xc3, 0xa9];
+        let mut strings = vec![Vec::new()];
+        let mut longest = strings.clone();
+        for _ in 0..3 {
+            longest = longest
+                .iter()
+                .flat_map(|bytes| {
+                    alphabet.iter().map(move |b| [&bytes[..], &[*b]].concat())
+                })
+                .collect();
+            strings.extend(longest.iter().cloned());
+        }
+        for content_type in [None, Some(ContentType::Text)].iter() {
+            let shown: HashSet<_> = strings
+                .iter()
+                .map(|bytes| {
+                    RecordEntry::new(content_type.clone(), bytes.clone())
+                        .display()
+                })
+                .collect();
+            assert_eq!(shown.len(), strings.len());
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crdts::{Causal, VClock};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::{
+    DocActor, DocKey, Document, OrswotRecord, PagedRecord, RecordEntry,
+    RecordMap,
+};
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct GcReport {
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+    pub deferred_dropped: usize,
+}
+
+impl GcReport {
+    pub fn reclaimed(&self) -> usize {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+}
+
+/// The greatest clock dominated by every clock in `clocks`, i.e. the causal
+/// history every one of them has seen.
+pub fn stable_clock<'a, I>(clocks: I) -> VClock<DocActor>
+where
+    I: IntoIterator<Item = &'a VClock<DocActor>>,
+{
+    let mut clocks = clocks.into_iter();
+    let mut stable = match clocks.next() {
+        Some(clock) => clock.clone(),
+        None => return VClock::new(),
+    };
+    for clock in clocks {
+        stable.glb(clock);
+    }
+    stable
+}
+
+// `Map` and `Orswot` keep their entry clocks and deferred removes private,
+// so garbage collection, and the functions below that take record maps
+// apart and put them together, go through these mirrors of their
+// serialized layout. They have to be kept in sync with the crdts version
+// in Cargo.toml, and nothing outside this module sees them.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+struct RawMap<K: DocKey> {
+    clock: VClock<DocActor>,
+    entries: BTreeMap<K, RawEntry>,
+    deferred: HashMap<VClock<DocActor>, BTreeSet<K>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawEntry {
+    clock: VClock<DocActor>,
+    val: OrswotRecord,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawOrswot {
+    clock: VClock<DocActor>,
+    entries: HashMap<RecordEntry, VClock<DocActor>>,
+    deferred: HashMap<VClock<DocActor>, HashSet<RecordEntry>>,
+}
+
+impl RawOrswot {
+    fn forget_members<'a, I>(&mut self, members: I, clock: &VClock<DocActor>)
+    where
+        I: IntoIterator<Item = &'a RecordEntry>,
+    {
+        for member in members {
+            if let Some(member_clock) = self.entries.get_mut(member) {
+                member_clock.forget(clock);
+                if member_clock.is_empty() {
+                    self.entries.remove(member);
+                }
+            }
+        }
+    }
+
+    fn collect(&mut self, stable: &VClock<DocActor>) -> usize {
+        let deferred = std::mem::take(&mut self.deferred);
+        let mut dropped = 0;
+        for (rm_clock, members) in deferred {
+            if &rm_clock <= stable {
+                self.forget_members(&members, &rm_clock);
+                dropped += 1;
+            } else {
+                self.deferred.insert(rm_clock, members);
+            }
+        }
+        dropped
+    }
+}
+
+impl<K: DocKey> RawMap<K> {
+    fn collect(&mut self, stable: &VClock<DocActor>) -> usize {
+        let deferred = std::mem::take(&mut self.deferred);
+        let mut dropped = 0;
+        for (rm_clock, keys) in deferred {
+            if &rm_clock <= stable {
+                for key in keys.iter() {
+                    if let Some(entry) = self.entries.get_mut(key) {
+                        entry.clock.forget(&rm_clock);
+                        if entry.clock.is_empty() {
+                            self.entries.remove(key);
+                        } else {
+                            entry.val.forget(&rm_clock);
+                        }
+                    }
+                }
+                dropped += 1;
+            } else {
+                self.deferred.insert(rm_clock, keys);
+            }
+        }
+
+        for entry in self.entries.values_mut() {
+            let raw: Option<RawOrswot> = remirror(&entry.val);
+            if let Some(mut raw) = raw {
+                let record_dropped = raw.collect(stable);
+                if record_dropped > 0 {
+                    if let Some(record) = remirror(&raw) {
+                        entry.val = record;
+                        dropped += record_dropped;
+                    }
+                }
+            }
+        }
+        dropped
+    }
+}
+
+impl<K: DocKey> RawMap<K> {
+    fn at(clock: VClock<DocActor>) -> Self {
+        RawMap {
+            clock,
+            entries: BTreeMap::new(),
+            deferred: HashMap::new(),
+        }
+    }
+}
+
+fn remirror<T: Serialize, U: DeserializeOwned>(value: &T) -> Option<U> {
+    let bytes = bincode::serialize(value).ok()?;
+    bincode::deserialize(&bytes).ok()
+}
+
+/// The map at `clock` holding `records` with the entry clocks they were
+/// paged with, and no deferred removes.
+pub(super) fn map_of<K: DocKey, I>(
+    clock: VClock<DocActor>,
+    records: I,
+) -> Option<RecordMap<K>>
+where
+    I: IntoIterator<Item = PagedRecord<K>>,
+{
+    let mut raw = RawMap::at(clock);
+    for paged in records {
+        let entry = RawEntry {
+            clock: paged.clock,
+            val: paged.record,
+        };
+        raw.entries.insert(paged.key, entry);
+    }
+    remirror(&raw)
+}
+
+/// Partitions `map` into `count` maps by `part_of` their keys, each with
+/// the whole clock and the deferred removes of its own keys.
+pub(super) fn partition<K: DocKey, F>(
+    map: &RecordMap<K>,
+    count: usize,
+    part_of: F,
+) -> Option<Vec<RecordMap<K>>>
+where
+    F: Fn(&K) -> usize,
+{
+    let raw: RawMap<K> = remirror(map)?;
+    let mut parts: Vec<_> =
+        (0..count).map(|_| RawMap::at(raw.clock.clone())).collect();
+    for (key, entry) in raw.entries {
+        parts[part_of(&key)].entries.insert(key, entry);
+    }
+    for (clock, keys) in raw.deferred {
+        for key in keys {
+            let part = &mut parts[part_of(&key)];
+            part.deferred.entry(clock.clone()).or_default().insert(key);
+        }
+    }
+    parts.iter().map(remirror).collect()
+}
+
+/// Splits the entries at `keys` off `map`: the rest keep its deferred
+/// removes, the ones split off get its clock and none.
+pub(super) fn split_off<K: DocKey>(
+    map: &RecordMap<K>,
+    keys: &[K],
+) -> Option<(RecordMap<K>, RecordMap<K>)> {
+    let mut rest: RawMap<K> = remirror(map)?;
+    let mut split = RawMap::at(rest.clock.clone());
+    for key in keys {
+        if let Some(entry) = rest.entries.remove(key) {
+            split.entries.insert(key.clone(), entry);
+        }
+    }
+    Some((remirror(&rest)?, remirror(&split)?))
+}
+
+/// Puts maps holding disjoint keys together at `clock`. Their entries are
+/// taken as they are rather than merged, which would drop entries one
+/// map's clock has seen and another map holds.
+pub(super) fn join<'a, K: DocKey, I>(
+    maps: I,
+    clock: VClock<DocActor>,
+) -> Option<RecordMap<K>>
+where
+    I: IntoIterator<Item = &'a RecordMap<K>>,
+{
+    let mut joined = RawMap::at(clock);
+    for map in maps {
+        let raw: RawMap<K> = remirror(map)?;
+        joined.entries.extend(raw.entries);
+        for (clock, mut keys) in raw.deferred {
+            joined.deferred.entry(clock).or_default().append(&mut keys);
+        }
+    }
+    remirror(&joined)
+}
+
+impl<K: DocKey> Document<K> {
+    /// Drops deferred removes, in the document map and in every record,
+    /// whose clock is dominated by `stable`. Every replica has seen all the
+    /// adds such a remove covers, so it is applied one final time and
+    /// forgotten.
+    ///
+    /// `stable` must also be dominated by this document's own clock.
+    pub fn collect_garbage(&mut self, stable: &VClock<DocActor>) -> GcReport {
+        let (collected, report) = self.collected(stable);
+        if let Some(doc) = collected {
+            *self = doc;
+        }
+        report
+    }
+
+    /// What `collect_garbage` would leave of the document, leaving the
+    /// document itself alone; `None` if there's nothing to drop.
+    pub fn collected(
+        &self,
+        stable: &VClock<DocActor>,
+    ) -> (Option<Self>, GcReport) {
+        let bytes_before = self.encoded_len();
+
+        let raw: Option<RawMap<K>> = remirror(&self.records);
+        let mut collected = None;
+        let mut deferred_dropped = 0;
+        if let Some(mut raw) = raw {
+            deferred_dropped = raw.collect(stable);
+            if deferred_dropped > 0 {
+                collected = remirror(&raw).map(|records| Document { records });
+            }
+        }
+
+        let bytes_after = collected
+            .as_ref()
+            .map_or(bytes_before, |doc: &Self| doc.encoded_len());
+        let report = GcReport {
+            bytes_before,
+            bytes_after,
+            deferred_dropped,
+        };
+        (collected, report)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        bincode::serialized_size(&self.records).unwrap_or(0) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Item, RecordKey, RecordMap};
+
+    fn text(s: &str) -> RecordEntry {
+        RecordEntry::text(s)
+    }
+
+    // A document whose map and records both hold deferred removes: other
+    // replicas remove record 1 and an entry of record 2 after seeing adds
+    // that this document never receives.
+    fn with_deferred() -> Document {
+        let mut doc = Document::default();
+        for (key, entry) in [(1, "a"), (2, "b"), (2, "c")] {
+            let ctx = doc.get_read_ctx().derive_add_ctx(1);
+            doc.apply(doc.add_item(key, ctx, Item::Single(text(entry))));
+        }
+
+        let mut ops = Vec::new();
+        let mut other = doc.clone();
+        for key in [2, 1] {
+            let ctx = other.get_read_ctx().derive_add_ctx(2);
+            let op = other.add_item(key, ctx, Item::Single(text("x")));
+            other.apply(op.clone());
+            ops.push(op);
+        }
+
+        let mut third = doc.clone();
+        third.apply(ops.remove(0));
+        let ctx = third.get_read_ctx().derive_add_ctx(3);
+        doc.apply(third.remove_entries(2, ctx, vec![text("b")]));
+        doc.apply(other.remove_record(1));
+        doc
+    }
+
+    #[test]
+    fn layout_matches_crdts() {
+        let doc = with_deferred();
+        let raw: RawMap<RecordKey> =
+            remirror(&doc.records).expect("the map layout changed");
+        assert_eq!(raw.deferred.len(), 1);
+
+        let record = &raw.entries[&2].val;
+        let raw_record: RawOrswot =
+            remirror(record).expect("the orswot layout changed");
+        assert_eq!(raw_record.deferred.len(), 1);
+        assert_eq!(
+            remirror::<_, OrswotRecord>(&raw_record).as_ref(),
+            Some(record)
+        );
+
+        let records: Option<RecordMap<RecordKey>> = remirror(&raw);
+        assert_eq!(records, Some(doc.records));
+    }
+
+    // Removes that race a record's removal and re-creation by another actor
+    // are deferred for good: the adds they cover are gone from the record's
+    // clock and never come back, though the document has seen them all.
+    #[test]
+    fn collects_deferred_removes() {
+        let mut doc = Document::default();
+        let mut stale = Vec::new();
+        for key in 0..50 {
+            let ctx = doc.get_read_ctx().derive_add_ctx(1);
+            let entries = vec![text("a"), text("b")];
+            doc.apply(doc.add_item(key, ctx, Item::Multiple(entries)));
+            let ctx = doc.get_read_ctx().derive_add_ctx(100 + key as DocActor);
+            stale.push(doc.remove_entries(key, ctx, vec![text("a")]));
+        }
+        for key in 0..50 {
+            doc.apply(doc.remove_record(key));
+            let ctx = doc.get_read_ctx().derive_add_ctx(3);
+            doc.apply(doc.add_item(key, ctx, Item::Single(text("c"))));
+        }
+        for op in stale {
+            doc.apply(op);
+        }
+
+        let before = doc.clone();
+        let report = doc.collect_garbage(&VClock::new());
+        assert_eq!(report.deferred_dropped, 0);
+        assert_eq!(report.reclaimed(), 0);
+
+        let stable = doc.get_read_ctx().add_clock;
+        let report = doc.collect_garbage(&stable);
+        assert_eq!(report.deferred_dropped, 50);
+        assert_eq!(report.bytes_before, before.encoded_len());
+        assert_eq!(report.bytes_after, doc.encoded_len());
+        assert!(report.reclaimed() > 40 * 50, "{:?}", report);
+        let raw: RawMap<RecordKey> = remirror(&doc.records).unwrap();
+        for entry in raw.entries.values() {
+            let record: RawOrswot = remirror(&entry.val).unwrap();
+            assert!(record.deferred.is_empty());
+        }
+
+        for key in 0..50 {
+            assert_eq!(
+                doc.get_record(&key).val.map(|record| record.read().val),
+                before.get_record(&key).val.map(|record| record.read().val),
+            );
+        }
+        assert_eq!(doc.collect_garbage(&stable).deferred_dropped, 0);
+    }
+}
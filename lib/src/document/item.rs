@@ -1,18 +1,41 @@
 use serde::{Deserialize, Serialize};
 
-use serde_json;
-
-use crdts::{
-    ctx::{AddCtx, ReadCtx, RmCtx},
-    map::Op,
-    Actor, Causal, CmRDT, CvRDT, FunkyCmRDT, FunkyCvRDT, LWWReg, Map, Orswot,
-    VClock,
-};
-
-use bstr::{ByteSlice, ByteVec};
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Item<T> {
     Multiple(Vec<T>),
     Single(T),
 }
+
+impl<T> Item<T> {
+    pub fn from_vec(mut items: Vec<T>) -> Self {
+        if items.len() == 1 {
+            Item::Single(items.remove(0))
+        } else {
+            Item::Multiple(items)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Item::Multiple(items) => items.len(),
+            Item::Single(_) => 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        match self {
+            Item::Multiple(items) => items,
+            Item::Single(item) => vec![item],
+        }
+    }
+}
+
+impl<T> From<T> for Item<T> {
+    fn from(item: T) -> Self {
+        Item::Single(item)
+    }
+}
@@ -0,0 +1,112 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crdts::Dot;
+
+use super::DocActor;
+
+use std::{
+    fmt::{Debug, Display},
+    hash::Hash,
+    str::FromStr,
+};
+
+pub trait DocKey:
+    Ord
+    + Clone
+    + Hash
+    + Debug
+    + Display
+    + FromStr
+    + Serialize
+    + DeserializeOwned
+    + Send
+    + Sync
+    + 'static
+{
+    /// Names the key type in files that outlive a build, like op logs,
+    /// so they can be read back with the right type.
+    const KEY_TYPE: &'static str;
+
+    fn parse_key(s: &str) -> Option<Self> {
+        s.parse().ok()
+    }
+
+    /// Builds a key that no other actor can produce, from the dot of the
+    /// op that creates the record, or `None` if the dot doesn't fit in
+    /// one.
+    fn from_dot(dot: &Dot<DocActor>) -> Option<Self>;
+
+    /// Whether the key has the form reserved for keys built by
+    /// `from_dot`. Keys chosen by users never do, so they can't collide
+    /// with a generated one.
+    fn is_generated(&self) -> bool;
+}
+
+// Generated keys have the top bit set, then the actor and 31 bits of
+// counter.
+impl DocKey for u64 {
+    const KEY_TYPE: &'static str = "u64";
+
+    fn from_dot(dot: &Dot<DocActor>) -> Option<Self> {
+        if dot.counter > 0x7fff_ffff {
+            return None;
+        }
+        Some(1 << 63 | (dot.actor as u64) << 31 | dot.counter)
+    }
+
+    fn is_generated(&self) -> bool {
+        self >> 63 == 1
+    }
+}
+
+const GENERATED_PREFIX: &str = "#";
+
+impl DocKey for String {
+    const KEY_TYPE: &'static str = "string";
+
+    fn from_dot(dot: &Dot<DocActor>) -> Option<Self> {
+        Some(format!("{}{}:{}", GENERATED_PREFIX, dot.actor, dot.counter))
+    }
+
+    fn is_generated(&self) -> bool {
+        self.starts_with(GENERATED_PREFIX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Document, Item, RecordEntry};
+
+    #[test]
+    fn generated_keys_are_distinct_and_reserved() {
+        let dots = [Dot::new(1, 1), Dot::new(1, 2), Dot::new(2, 1)];
+        let keys: Vec<u64> =
+            dots.iter().map(|dot| u64::from_dot(dot).unwrap()).collect();
+        assert!(keys.iter().all(DocKey::is_generated));
+        assert_ne!(keys[0], keys[1]);
+        assert_ne!(keys[0], keys[2]);
+        assert!(!42u64.is_generated());
+
+        let last = Dot::new(DocActor::MAX, 0x7fff_ffff);
+        assert!(u64::from_dot(&last).is_some());
+        assert_eq!(u64::from_dot(&Dot::new(1, 0x8000_0000)), None);
+
+        let key = String::from_dot(&Dot::new(3, 5)).unwrap();
+        assert!(key.is_generated());
+        assert!(!"3:5".to_string().is_generated());
+    }
+
+    #[test]
+    fn generated_keys_need_their_record() {
+        let doc: Document<String> = Document::default();
+        let initial = Item::Single(RecordEntry::text("a"));
+        let (key, op) = doc.create_record(1, initial).unwrap();
+        assert!(doc.can_write(&"notes".to_string()));
+        assert!(!doc.can_write(&key));
+
+        let mut doc = doc;
+        doc.apply(op);
+        assert!(doc.can_write(&key));
+    }
+}
@@ -0,0 +1,476 @@
+use serde::{Deserialize, Serialize};
+
+use crdts::{ctx::ReadCtx, CvRDT, VClock};
+
+use sha1::{Digest as _, Sha1};
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use super::canonical::encode_record;
+use super::gc;
+use super::{
+    own_clock, DocActor, DocKey, Document, OrswotRecord, PagedRecord, RecordKey,
+};
+
+pub type Hash = [u8; 20];
+
+const FANOUT_BITS: u8 = 4;
+pub const MAX_DEPTH: u8 = 64 / FANOUT_BITS;
+/// Ranges holding at most this many records are sent as their records'
+/// hashes rather than as child ranges.
+pub const LEAF_RECORDS: u64 = 16;
+
+pub fn sha1(bytes: &[u8]) -> Hash {
+    Sha1::digest(bytes).into()
+}
+
+/// Where a key sits in the hash tree: the first bytes of the hash of the
+/// key as displayed, so both ends place it the same way.
+pub fn key_hash<K: DocKey>(key: &K) -> u64 {
+    let hash = sha1(key.to_string().as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// The keys whose hash starts with the `depth` leading nibbles of
+/// `prefix`. The root covers every key and each range is split into 16
+/// children, one level deeper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DigestRange {
+    pub depth: u8,
+    pub prefix: u64,
+}
+
+impl DigestRange {
+    pub fn root() -> Self {
+        DigestRange {
+            depth: 0,
+            prefix: 0,
+        }
+    }
+
+    fn shift(&self) -> u32 {
+        64 - (self.depth.min(MAX_DEPTH) * FANOUT_BITS) as u32
+    }
+
+    /// The first and last key hash in the range.
+    pub fn bounds(&self) -> (u64, u64) {
+        let shift = self.shift();
+        if shift == 64 {
+            return (0, u64::MAX);
+        }
+        let start = self.prefix << shift;
+        (start, start | ((1 << shift) - 1))
+    }
+
+    pub fn contains(&self, key_hash: u64) -> bool {
+        let (start, end) = self.bounds();
+        start <= key_hash && key_hash <= end
+    }
+
+    pub fn children(&self) -> Vec<DigestRange> {
+        if self.depth >= MAX_DEPTH {
+            return Vec::new();
+        }
+        (0..1 << FANOUT_BITS)
+            .map(|i| DigestRange {
+                depth: self.depth + 1,
+                prefix: self.prefix << FANOUT_BITS | i,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChildDigest {
+    pub range: DigestRange,
+    pub hash: Hash,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub enum DigestContent<K: DocKey = RecordKey> {
+    Children(Vec<ChildDigest>),
+    Records(Vec<(K, Hash)>),
+}
+
+/// One node of a replica's hash tree, with what's needed to decide which
+/// of its children, or which of its records, differ from another replica.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Digest<K: DocKey = RecordKey> {
+    pub range: DigestRange,
+    pub hash: Hash,
+    pub count: u64,
+    pub content: DigestContent<K>,
+}
+
+/// What comparing a remote digest with the local tree found: the ranges
+/// to ask the remote about next, and the keys whose records differ.
+#[derive(Debug, Clone)]
+pub struct Diff<K: DocKey = RecordKey> {
+    pub ranges: Vec<DigestRange>,
+    pub keys: Vec<K>,
+}
+
+#[derive(Debug, Clone)]
+struct Leaf<K: DocKey> {
+    key_hash: u64,
+    key: K,
+    hash: Hash,
+}
+
+/// The hashes of a document's records, ordered by key hash, from which
+/// the hash of any range is computed. The hashes are kept in segments
+/// that trees joined from the trees of parts of a document share.
+#[derive(Debug, Clone)]
+pub struct MerkleTree<K: DocKey = RecordKey> {
+    segments: Vec<Arc<[Leaf<K>]>>,
+}
+
+impl<K: DocKey> MerkleTree<K> {
+    /// The tree over `records`, which may come in any order.
+    pub fn of<'a, I>(records: I) -> Self
+    where
+        K: 'a,
+        I: IntoIterator<Item = ReadCtx<(&'a K, &'a OrswotRecord), DocActor>>,
+    {
+        let mut leaves: Vec<_> = records
+            .into_iter()
+            .map(|ctx| {
+                let (key, record) = ctx.val;
+                let encoded = encode_record(key, own_clock(&ctx), record);
+                Leaf {
+                    key_hash: key_hash(key),
+                    key: key.clone(),
+                    hash: sha1(&encoded),
+                }
+            })
+            .collect();
+        leaves.sort_unstable_by(|a, b| {
+            (a.key_hash, &a.key).cmp(&(b.key_hash, &b.key))
+        });
+        MerkleTree {
+            segments: vec![leaves.into()],
+        }
+    }
+
+    /// The trees of the parts of a document, which must hold consecutive
+    /// ranges of key hashes, in order, as the tree of the whole.
+    pub fn join<'a, I>(trees: I) -> Self
+    where
+        K: 'a,
+        I: IntoIterator<Item = &'a MerkleTree<K>>,
+    {
+        let segments = trees
+            .into_iter()
+            .flat_map(|tree| tree.segments.iter().cloned())
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        MerkleTree { segments }
+    }
+
+    fn leaves_in<'a>(
+        &'a self,
+        range: &DigestRange,
+    ) -> impl Iterator<Item = &'a Leaf<K>> + 'a {
+        let (start, end) = range.bounds();
+        self.segments.iter().flat_map(move |leaves| {
+            let from = leaves.partition_point(|leaf| leaf.key_hash < start);
+            let to = leaves.partition_point(|leaf| leaf.key_hash <= end);
+            leaves[from..to].iter()
+        })
+    }
+
+    /// The hash of the record hashes in `range`, and how many there are.
+    pub fn hash(&self, range: &DigestRange) -> (Hash, u64) {
+        let mut hasher = Sha1::new();
+        let mut count = 0;
+        for leaf in self.leaves_in(range) {
+            hasher.update(leaf.hash);
+            count += 1;
+        }
+        (hasher.finalize().into(), count)
+    }
+
+    pub fn root(&self) -> Hash {
+        self.hash(&DigestRange::root()).0
+    }
+
+    pub fn digest(&self, range: DigestRange) -> Digest<K> {
+        let (hash, count) = self.hash(&range);
+        let children = range.children();
+        let content = if count <= LEAF_RECORDS || children.is_empty() {
+            let records = self
+                .leaves_in(&range)
+                .map(|leaf| (leaf.key.clone(), leaf.hash))
+                .collect();
+            DigestContent::Records(records)
+        } else {
+            let children = children
+                .into_iter()
+                .map(|range| {
+                    let (hash, count) = self.hash(&range);
+                    ChildDigest { range, hash, count }
+                })
+                .collect();
+            DigestContent::Children(children)
+        };
+        Digest {
+            range,
+            hash,
+            count,
+            content,
+        }
+    }
+
+    pub fn diff(&self, remote: &Digest<K>) -> Diff<K> {
+        let mut diff = Diff {
+            ranges: Vec::new(),
+            keys: Vec::new(),
+        };
+        match &remote.content {
+            DigestContent::Children(children) => {
+                for child in children {
+                    if self.hash(&child.range).0 != child.hash {
+                        diff.ranges.push(child.range);
+                    }
+                }
+            }
+            DigestContent::Records(records) => {
+                let mut local: BTreeMap<&K, &Hash> = self
+                    .leaves_in(&remote.range)
+                    .map(|leaf| (&leaf.key, &leaf.hash))
+                    .collect();
+                for (key, hash) in records {
+                    if local.remove(key) != Some(hash) {
+                        diff.keys.push(key.clone());
+                    }
+                }
+                diff.keys.extend(local.into_keys().cloned());
+            }
+        }
+        diff
+    }
+}
+
+/// A walk of a remote tree driven by its replies as they arrive, for
+/// clients that can't wait on each round trip. Every digest received
+/// yields the ranges to ask for next; once none are outstanding the walk
+/// is done and `keys` holds the records that differ.
+#[derive(Debug, Clone)]
+pub struct DigestWalk<K: DocKey = RecordKey> {
+    tree: MerkleTree<K>,
+    root: Option<Hash>,
+    outstanding: usize,
+    keys: Vec<K>,
+}
+
+impl<K: DocKey> DigestWalk<K> {
+    /// Starts comparing against `tree`; ask the remote for the root range
+    /// first.
+    pub fn new(tree: MerkleTree<K>) -> Self {
+        DigestWalk {
+            tree,
+            root: None,
+            outstanding: 1,
+            keys: Vec::new(),
+        }
+    }
+
+    /// Compares a digest the remote sent, returning the ranges to ask it
+    /// about next.
+    pub fn receive(&mut self, digest: &Digest<K>) -> Vec<DigestRange> {
+        if self.root.is_none() {
+            self.root = Some(digest.hash);
+        }
+        self.outstanding = self.outstanding.saturating_sub(1);
+        let diff = self.tree.diff(digest);
+        self.keys.extend(diff.keys);
+        self.outstanding += diff.ranges.len();
+        diff.ranges
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.outstanding == 0
+    }
+
+    pub fn keys(&self) -> &[K] {
+        &self.keys
+    }
+
+    /// Whether `set` was taken from the tree this walk compared against,
+    /// so it can be merged with `Document::merge_records`.
+    pub fn matches(&self, set: &RecordSet<K>) -> bool {
+        self.root == Some(set.root)
+    }
+}
+
+/// Records fetched from a replica to be merged into another, along with
+/// the keys they were asked for, so that a key missing from `records` is
+/// known to be absent, and the replica's clock and root hash at the time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct RecordSet<K: DocKey = RecordKey> {
+    pub keys: Vec<K>,
+    pub records: Vec<PagedRecord<K>>,
+    pub clock: VClock<DocActor>,
+    pub root: Hash,
+}
+
+impl<K: DocKey> Document<K> {
+    pub fn merkle_tree(&self) -> MerkleTree<K> {
+        MerkleTree::of(self.records.iter())
+    }
+
+    pub fn record_set(&self, keys: Vec<K>) -> RecordSet<K> {
+        let records = keys
+            .iter()
+            .filter_map(|key| {
+                let ctx = self.get_record(key);
+                Some(PagedRecord {
+                    key: key.clone(),
+                    clock: own_clock(&ctx).clone(),
+                    record: ctx.val?,
+                })
+            })
+            .collect();
+        RecordSet {
+            keys,
+            records,
+            clock: self.get_read_ctx().add_clock,
+            root: self.merkle_tree().root(),
+        }
+    }
+
+    /// Merges the records of `set` as a map merge restricted to its keys,
+    /// so records removed at the other end are removed here too, then
+    /// takes on the other end's clock.
+    ///
+    /// Taking on the clock is only right if every record outside the set
+    /// is the same at both ends, i.e. if the keys were found by comparing
+    /// against the tree whose root is `set.root`; otherwise the records
+    /// merged here may miss ops the clock claims have been seen.
+    pub fn merge_records(&mut self, set: RecordSet<K>) -> Option<()> {
+        let RecordSet {
+            keys,
+            records,
+            clock,
+            ..
+        } = set;
+        let (rest, mut ours) = gc::split_off(&self.records, &keys)?;
+        let records = records
+            .into_iter()
+            .filter(|paged| keys.contains(&paged.key));
+        let theirs = gc::map_of(clock.clone(), records)?;
+        ours.merge(theirs);
+
+        let mut merged_clock = self.get_read_ctx().add_clock;
+        merged_clock.merge(clock);
+        self.records = gc::join([&rest, &ours], merged_clock)?;
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::add;
+
+    fn remove(doc: &mut Document, key: RecordKey) {
+        let op = doc.remove_record(key);
+        doc.apply(op);
+    }
+
+    // walks `remote` from the root the way a client does
+    fn differing_keys(local: &Document, remote: &Document) -> DigestWalk {
+        let tree = remote.merkle_tree();
+        let mut walk = DigestWalk::new(local.merkle_tree());
+        let mut ranges = vec![DigestRange::root()];
+        while let Some(range) = ranges.pop() {
+            ranges.extend(walk.receive(&tree.digest(range)));
+        }
+        assert!(walk.is_done());
+        walk
+    }
+
+    #[test]
+    fn ranges_split_down_to_single_key_hashes() {
+        assert_eq!(DigestRange::root().bounds(), (0, u64::MAX));
+
+        let mut range = DigestRange::root();
+        let hash = 0x0123_4567_89ab_cdef;
+        while range.depth < MAX_DEPTH {
+            let children = range.children();
+            assert_eq!(children.len(), 16);
+            assert_eq!(children[0].bounds().0, range.bounds().0);
+            assert_eq!(children[15].bounds().1, range.bounds().1);
+            for pair in children.windows(2) {
+                assert_eq!(pair[0].bounds().1 + 1, pair[1].bounds().0);
+            }
+            range = children
+                .into_iter()
+                .find(|child| child.contains(hash))
+                .unwrap();
+        }
+        assert_eq!(range.prefix, hash);
+        assert_eq!(range.bounds(), (hash, hash));
+        assert!(range.children().is_empty());
+    }
+
+    #[test]
+    fn diff_finds_the_records_that_differ() {
+        let mut doc = Document::default();
+        for key in 0..100 {
+            add(&mut doc, key, 1);
+        }
+        let mut other = doc.clone();
+        assert!(differing_keys(&doc, &other).keys().is_empty());
+        let tree = doc.merkle_tree();
+        let diff = tree.diff(&other.merkle_tree().digest(DigestRange::root()));
+        assert!(diff.ranges.is_empty() && diff.keys.is_empty());
+
+        add(&mut doc, 200, 1);
+        add(&mut other, 7, 2);
+        remove(&mut other, 5);
+        add(&mut other, 300, 2);
+        let mut keys = differing_keys(&doc, &other).keys().to_vec();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![5, 7, 200, 300]);
+    }
+
+    #[test]
+    fn merged_records_match_the_other_end() {
+        let mut doc = Document::default();
+        for key in 0..100 {
+            add(&mut doc, key, 1);
+        }
+        let mut other = doc.clone();
+        add(&mut doc, 200, 1);
+        add(&mut other, 7, 2);
+        remove(&mut other, 5);
+        add(&mut other, 300, 2);
+
+        let walk = differing_keys(&doc, &other);
+        let set = other.record_set(walk.keys().to_vec());
+        assert!(walk.matches(&set));
+        doc.merge_records(set).unwrap();
+
+        assert!(doc.get_record(&5).val.is_none());
+        for key in [7, 300] {
+            assert_eq!(
+                doc.get_record(&key).val.unwrap().read().val,
+                other.get_record(&key).val.unwrap().read().val
+            );
+        }
+        // records the other end hasn't seen are kept
+        assert!(doc.get_record(&200).val.is_some());
+        let clock = doc.get_read_ctx().add_clock;
+        assert!(clock >= other.get_read_ctx().add_clock);
+
+        remove(&mut doc, 200);
+        assert_eq!(doc.merkle_tree().root(), other.merkle_tree().root());
+    }
+}
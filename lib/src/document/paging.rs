@@ -0,0 +1,200 @@
+use serde::{Deserialize, Serialize};
+
+use crdts::{ctx::ReadCtx, VClock};
+
+use std::collections::BTreeMap;
+
+use super::gc;
+use super::{own_clock, DocActor, DocKey, Document, OrswotRecord, RecordKey};
+
+/// A record together with the map clock of its entry, which is what a
+/// client needs to put the document back together from pages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct PagedRecord<K: DocKey = RecordKey> {
+    pub key: K,
+    pub clock: VClock<DocActor>,
+    pub record: OrswotRecord,
+}
+
+impl<K: DocKey> PagedRecord<K> {
+    pub(super) fn from_ctx(
+        ctx: ReadCtx<(&K, &OrswotRecord), DocActor>,
+    ) -> Self {
+        PagedRecord {
+            key: ctx.val.0.clone(),
+            clock: own_clock(&ctx).clone(),
+            record: ctx.val.1.clone(),
+        }
+    }
+}
+
+impl<K: DocKey> Document<K> {
+    /// Up to `limit` records in key order, starting after `after_key`, and
+    /// the key to continue from if there are more. Pages hold at least one
+    /// record, so that paging always makes progress.
+    pub fn records_page(
+        &self,
+        after_key: Option<&K>,
+        limit: usize,
+    ) -> (Vec<PagedRecord<K>>, Option<K>) {
+        let limit = limit.max(1);
+        let mut entries = self
+            .records
+            .iter()
+            .skip_while(|ctx| Some(ctx.val.0) <= after_key)
+            .map(PagedRecord::from_ctx);
+        let page: Vec<_> = entries.by_ref().take(limit).collect();
+        let next = match entries.next() {
+            Some(_) => page.last().map(|paged| paged.key.clone()),
+            None => None,
+        };
+        (page, next)
+    }
+
+    /// Every record in key order, in chunks of up to `chunk_size`.
+    pub fn record_chunks(
+        &self,
+        chunk_size: usize,
+    ) -> impl Iterator<Item = Vec<PagedRecord<K>>> + '_ {
+        chunks(self.records.iter().map(PagedRecord::from_ctx), chunk_size)
+    }
+
+    pub fn record_count(&self) -> usize {
+        self.records.len().val
+    }
+}
+
+/// `records` in chunks of up to `chunk_size`.
+pub(super) fn chunks<K, I>(
+    mut records: I,
+    chunk_size: usize,
+) -> impl Iterator<Item = Vec<PagedRecord<K>>>
+where
+    K: DocKey,
+    I: Iterator<Item = PagedRecord<K>>,
+{
+    std::iter::from_fn(move || {
+        let chunk: Vec<_> = records.by_ref().take(chunk_size.max(1)).collect();
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(chunk)
+        }
+    })
+}
+
+/// Collects the records of a paged or streamed document until the read
+/// ctx the transfer ends with arrives.
+#[derive(Debug, Clone)]
+pub struct DocumentBuilder<K: DocKey = RecordKey> {
+    records: BTreeMap<K, PagedRecord<K>>,
+}
+
+impl<K: DocKey> Default for DocumentBuilder<K> {
+    fn default() -> Self {
+        DocumentBuilder {
+            records: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: DocKey> DocumentBuilder<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, page: Vec<PagedRecord<K>>) {
+        for paged in page {
+            self.records.insert(paged.key.clone(), paged);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Builds the document at `clock`, the add clock of the read ctx the
+    /// transfer ended with.
+    pub fn finish(self, clock: VClock<DocActor>) -> Option<Document<K>> {
+        Some(Document {
+            records: gc::map_of(clock, self.records.into_values())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Item, RecordEntry};
+
+    fn document(records: RecordKey) -> Document {
+        let mut doc = Document::default();
+        for key in 0..records {
+            let ctx = doc.get_read_ctx().derive_add_ctx(1);
+            let entry = RecordEntry::text(&key.to_string());
+            let op = doc.add_item(key, ctx, Item::Single(entry));
+            doc.apply(op);
+        }
+        doc
+    }
+
+    fn keys(page: &[PagedRecord]) -> Vec<RecordKey> {
+        page.iter().map(|paged| paged.key).collect()
+    }
+
+    #[test]
+    fn pages_end_where_the_records_do() {
+        let doc = document(10);
+        let (page, next) = doc.records_page(None, 4);
+        assert_eq!((keys(&page), next), (vec![0, 1, 2, 3], Some(3)));
+        let (page, next) = doc.records_page(Some(&7), 4);
+        assert_eq!((keys(&page), next), (vec![8, 9], None));
+        // a page that takes exactly the last records has none to follow
+        let (page, next) = doc.records_page(Some(&5), 4);
+        assert_eq!((keys(&page), next), (vec![6, 7, 8, 9], None));
+        let (page, next) = doc.records_page(None, 10);
+        assert_eq!((page.len(), next), (10, None));
+        let (page, next) = doc.records_page(Some(&9), 4);
+        assert_eq!((page.len(), next), (0, None));
+        let (page, next) = doc.records_page(None, 0);
+        assert_eq!((keys(&page), next), (vec![0], Some(0)));
+
+        let (page, next) = document(0).records_page(None, 4);
+        assert_eq!((page.len(), next), (0, None));
+    }
+
+    #[test]
+    fn paged_and_streamed_records_rebuild_the_document() {
+        let doc = document(10);
+        let clock = doc.get_read_ctx().add_clock;
+
+        let mut builder = DocumentBuilder::new();
+        let mut after = None;
+        loop {
+            let (page, next) = doc.records_page(after.as_ref(), 3);
+            builder.push(page);
+            match next {
+                Some(key) => after = Some(key),
+                None => break,
+            }
+        }
+        let paged = builder.finish(clock.clone()).unwrap();
+        assert_eq!(paged.state_hash(), doc.state_hash());
+
+        let sizes: Vec<_> = doc.record_chunks(4).map(|c| c.len()).collect();
+        assert_eq!(sizes, vec![4, 4, 2]);
+        assert_eq!(doc.record_chunks(10).count(), 1);
+        assert_eq!(doc.record_chunks(0).count(), 10);
+        assert_eq!(document(0).record_chunks(4).count(), 0);
+
+        let mut builder = DocumentBuilder::new();
+        doc.record_chunks(4).for_each(|chunk| builder.push(chunk));
+        let streamed = builder.finish(clock).unwrap();
+        assert_eq!(streamed.state_hash(), doc.state_hash());
+    }
+}
@@ -0,0 +1,515 @@
+use crdts::{
+    ctx::{AddCtx, ReadCtx},
+    map::Op,
+    CmRDT, CvRDT, Dot, VClock,
+};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, OnceLock},
+};
+
+use super::canonical::{content_of, state_of};
+use super::merkle::{sha1, Hash, MerkleTree};
+use super::paging::chunks;
+use super::{
+    own_clock, shard_of, split_record_set, DocActor, DocKey, Document,
+    DocumentOp, EntryBlame, GcReport, Item, OrswotRecord, PagedRecord,
+    RecordEntry, RecordKey, RecordSet,
+};
+
+/// How many parts each shard keeps its records in. A write copies a part
+/// it shares with a version a reader still holds, so this bounds how much
+/// a write copies to a fraction of the shard.
+pub const PARTS_PER_SHARD: usize = 64;
+
+/// Records kept as many small documents, each holding a range of key
+/// hashes behind its own `Arc`, see `shard_of`. A copy shares every part
+/// with the original, and writing to a part copies only that part, so a
+/// writer can publish a copy after every batch and go on writing.
+///
+/// Every part starts out with the clock of the document it was split
+/// from, and then only sees the ops on its own records; the clock of the
+/// whole is kept alongside them.
+#[derive(Debug, Clone)]
+pub struct Parts<K: DocKey = RecordKey> {
+    clock: VClock<DocActor>,
+    // the index of the first part out of `total`
+    first: usize,
+    total: usize,
+    parts: Vec<Arc<Part<K>>>,
+}
+
+/// One part of the records, and their hash tree once it has been asked
+/// for. Published versions share the tree along with the part, so it is
+/// only computed again once the part has been written to.
+#[derive(Debug)]
+struct Part<K: DocKey> {
+    doc: Document<K>,
+    tree: OnceLock<MerkleTree<K>>,
+}
+
+// parts are only copied to be written to, so the tree is left behind
+impl<K: DocKey> Clone for Part<K> {
+    fn clone(&self) -> Self {
+        Part {
+            doc: self.doc.clone(),
+            tree: OnceLock::new(),
+        }
+    }
+}
+
+impl<K: DocKey> Part<K> {
+    fn new(doc: Document<K>) -> Arc<Self> {
+        Arc::new(Part {
+            doc,
+            tree: OnceLock::new(),
+        })
+    }
+
+    // copies the part if it is shared, and forgets its tree
+    fn write(part: &mut Arc<Self>) -> &mut Document<K> {
+        let part = Arc::make_mut(part);
+        part.tree = OnceLock::new();
+        &mut part.doc
+    }
+
+    fn tree(&self) -> &MerkleTree<K> {
+        self.tree.get_or_init(|| self.doc.merkle_tree())
+    }
+}
+
+impl<K: DocKey> Default for Parts<K> {
+    fn default() -> Self {
+        Parts {
+            clock: VClock::new(),
+            first: 0,
+            total: PARTS_PER_SHARD,
+            parts: (0..PARTS_PER_SHARD)
+                .map(|_| Part::new(Document::default()))
+                .collect(),
+        }
+    }
+}
+
+impl<K: DocKey> Parts<K> {
+    /// Splits `doc` into `shards` consecutive ranges of parts, one for each
+    /// shard, the way `shard_of` assigns its records to shards. `None` if
+    /// the document couldn't be split, see `Document::split`.
+    pub fn split(doc: &Document<K>, shards: usize) -> Option<Vec<Self>> {
+        let shards = shards.max(1);
+        let total = shards * PARTS_PER_SHARD;
+        let clock = doc.get_read_ctx().add_clock;
+        let mut parts = doc.split(total)?.into_iter().map(Part::new);
+        let shards = (0..shards)
+            .map(|shard| Parts {
+                clock: clock.clone(),
+                first: shard * PARTS_PER_SHARD,
+                total,
+                parts: parts.by_ref().take(PARTS_PER_SHARD).collect(),
+            })
+            .collect();
+        Some(shards)
+    }
+
+    /// The parts of shard `shard` out of `shards`, holding the records of
+    /// `doc` that belong to it.
+    pub fn shard(
+        doc: &Document<K>,
+        shard: usize,
+        shards: usize,
+    ) -> Option<Self> {
+        let total = shards.max(1) * PARTS_PER_SHARD;
+        let first = shard * PARTS_PER_SHARD;
+        let parts = doc
+            .split(total)?
+            .into_iter()
+            .skip(first)
+            .take(PARTS_PER_SHARD)
+            .map(Part::new)
+            .collect();
+        Some(Parts {
+            clock: doc.get_read_ctx().add_clock,
+            first,
+            total,
+            parts,
+        })
+    }
+
+    /// The parts of every shard side by side, in shard order, as one
+    /// whole at `clock`.
+    pub fn concat<'a, I>(shards: I, clock: VClock<DocActor>) -> Self
+    where
+        I: IntoIterator<Item = &'a Parts<K>>,
+    {
+        let mut parts = Vec::new();
+        let mut total = 0;
+        for shard in shards {
+            total = shard.total;
+            parts.extend(shard.parts.iter().cloned());
+        }
+        Parts {
+            clock,
+            first: 0,
+            total,
+            parts,
+        }
+    }
+
+    /// Puts the parts back together into one document, see
+    /// `Document::join`.
+    pub fn join(&self) -> Option<Document<K>> {
+        let parts = self.parts.iter().map(|part| &part.doc);
+        Document::join(parts, self.clock.clone())
+    }
+
+    fn index(&self, key: &K) -> usize {
+        shard_of(key, self.total) - self.first
+    }
+
+    /// The part that holds the record at `key`.
+    pub fn part(&self, key: &K) -> &Document<K> {
+        &self.parts[self.index(key)].doc
+    }
+
+    fn part_mut(&mut self, index: usize) -> &mut Document<K> {
+        Part::write(&mut self.parts[index])
+    }
+
+    pub fn parts(&self) -> impl Iterator<Item = &Document<K>> {
+        self.parts.iter().map(|part| &part.doc)
+    }
+
+    // every record, in no particular order
+    fn records(
+        &self,
+    ) -> impl Iterator<Item = ReadCtx<(&K, &OrswotRecord), DocActor>> {
+        self.parts().flat_map(|part| part.records.iter())
+    }
+
+    pub fn get_read_ctx(&self) -> ReadCtx<(), DocActor> {
+        ReadCtx {
+            add_clock: self.clock.clone(),
+            rm_clock: self.clock.clone(),
+            val: (),
+        }
+    }
+
+    /// The record at `key`, with the clock of the whole as its add clock.
+    pub fn get_record(
+        &self,
+        key: &K,
+    ) -> ReadCtx<Option<OrswotRecord>, DocActor> {
+        let mut record = self.part(key).get_record(key);
+        record.add_clock = self.clock.clone();
+        record
+    }
+
+    pub fn add_item(
+        &self,
+        key: K,
+        ctx: AddCtx<DocActor>,
+        item: Item<RecordEntry>,
+    ) -> DocumentOp<K> {
+        self.part(&key).add_item(key, ctx, item)
+    }
+
+    /// See `Document::create_record`.
+    pub fn create_record(
+        &self,
+        actor: DocActor,
+        initial: Item<RecordEntry>,
+    ) -> Option<(K, DocumentOp<K>)> {
+        let add_ctx = self.get_read_ctx().derive_add_ctx(actor);
+        let key = K::from_dot(&add_ctx.dot)?;
+        let op = self.add_item(key.clone(), add_ctx, initial);
+        Some((key, op))
+    }
+
+    pub fn can_write(&self, key: &K) -> bool {
+        self.part(key).can_write(key)
+    }
+
+    pub fn blame<F>(&self, key: &K, received: F) -> Vec<EntryBlame>
+    where
+        F: Fn(&Dot<DocActor>) -> Option<u64>,
+    {
+        self.part(key).blame(key, received)
+    }
+
+    /// Applies `op` to the parts holding its keys, see
+    /// `Document::apply_part`.
+    pub fn apply(&mut self, op: DocumentOp<K>) {
+        match op {
+            Op::Up { dot, key, op } => {
+                self.clock.apply(dot);
+                let index = self.index(&key);
+                self.part_mut(index).apply_part(Op::Up { dot, key, op });
+            }
+            Op::Rm { clock, keyset } => {
+                let mut parts: BTreeMap<usize, BTreeSet<K>> = BTreeMap::new();
+                for key in keyset {
+                    parts.entry(self.index(&key)).or_default().insert(key);
+                }
+                for (index, keyset) in parts {
+                    let clock = clock.clone();
+                    self.part_mut(index).apply_part(Op::Rm { clock, keyset });
+                }
+            }
+        }
+    }
+
+    /// Merges a full copy of the document, or of the shard these parts
+    /// belong to, into every part. `None` if it couldn't be split.
+    pub fn merge(&mut self, doc: Document<K>) -> Option<()> {
+        let clock = doc.get_read_ctx().add_clock;
+        let others = doc.split(self.total)?.into_iter().skip(self.first);
+        for (part, other) in self.parts.iter_mut().zip(others) {
+            Part::write(part).records.merge(other.records);
+        }
+        self.clock.merge(clock);
+        Some(())
+    }
+
+    /// Merges the records of `set` into the parts holding them, see
+    /// `Document::merge_records`. Parts none of its keys belong to are
+    /// left alone, and only the clock of the whole takes on the set's.
+    pub fn merge_records(&mut self, set: RecordSet<K>) -> Option<()> {
+        let clock = set.clock.clone();
+        let sets = split_record_set(set, self.total)
+            .into_iter()
+            .skip(self.first);
+        for (part, set) in self.parts.iter_mut().zip(sets) {
+            if !set.keys.is_empty() {
+                Part::write(part).merge_records(set)?;
+            }
+        }
+        self.clock.merge(clock);
+        Some(())
+    }
+
+    /// Collects garbage part by part, see `Document::collect_garbage`;
+    /// only the parts that had any are copied.
+    pub fn collect_garbage(&mut self, stable: &VClock<DocActor>) -> GcReport {
+        let mut report = GcReport::default();
+        for part in self.parts.iter_mut() {
+            let (collected, part_report) = part.doc.collected(stable);
+            if let Some(collected) = collected {
+                *part = Part::new(collected);
+            }
+            report.bytes_before += part_report.bytes_before;
+            report.bytes_after += part_report.bytes_after;
+            report.deferred_dropped += part_report.deferred_dropped;
+        }
+        report
+    }
+
+    /// See `Document::records_page`. Each part's records are in key order,
+    /// so no part has more than `limit` of them on the page.
+    pub fn records_page(
+        &self,
+        after_key: Option<&K>,
+        limit: usize,
+    ) -> (Vec<PagedRecord<K>>, Option<K>) {
+        let limit = limit.max(1);
+        let mut records: Vec<_> = self
+            .parts()
+            .flat_map(|part| {
+                part.records
+                    .iter()
+                    .skip_while(|ctx| Some(ctx.val.0) <= after_key)
+                    .take(limit + 1)
+            })
+            .collect();
+        records.sort_unstable_by(|a, b| a.val.0.cmp(b.val.0));
+        let more = records.len() > limit;
+        let page: Vec<_> = records
+            .into_iter()
+            .take(limit)
+            .map(PagedRecord::from_ctx)
+            .collect();
+        let next = if more {
+            page.last().map(|paged| paged.key.clone())
+        } else {
+            None
+        };
+        (page, next)
+    }
+
+    /// See `Document::record_chunks`.
+    pub fn record_chunks(
+        &self,
+        chunk_size: usize,
+    ) -> impl Iterator<Item = Vec<PagedRecord<K>>> + '_ {
+        let mut records: Vec<_> = self.records().collect();
+        records.sort_unstable_by(|a, b| a.val.0.cmp(b.val.0));
+        chunks(records.into_iter().map(PagedRecord::from_ctx), chunk_size)
+    }
+
+    pub fn record_count(&self) -> usize {
+        self.parts().map(|part| part.record_count()).sum()
+    }
+
+    /// The tree of the whole, joined from the trees of the parts, which
+    /// are only computed for parts that changed since they last were.
+    pub fn merkle_tree(&self) -> MerkleTree<K> {
+        MerkleTree::join(self.parts.iter().map(|part| part.tree()))
+    }
+
+    /// See `Document::record_set`.
+    pub fn record_set(&self, keys: Vec<K>) -> RecordSet<K> {
+        let records = keys
+            .iter()
+            .filter_map(|key| {
+                let ctx = self.get_record(key);
+                Some(PagedRecord {
+                    key: key.clone(),
+                    clock: own_clock(&ctx).clone(),
+                    record: ctx.val?,
+                })
+            })
+            .collect();
+        RecordSet {
+            keys,
+            records,
+            clock: self.clock.clone(),
+            root: self.merkle_tree().root(),
+        }
+    }
+
+    /// See `Document::content_hash`.
+    pub fn content_hash(&self) -> Hash {
+        sha1(&content_of(self.records()))
+    }
+
+    /// See `Document::state_hash`.
+    pub fn state_hash(&self) -> Hash {
+        sha1(&state_of(&self.clock, self.records()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::split_op;
+    use crate::fixtures::add_to_parts;
+
+    #[test]
+    fn writes_only_copy_the_part_they_touch() {
+        let mut parts = Parts::default();
+        for key in 0..200 {
+            parts.apply(add_to_parts(&parts, key, 1));
+        }
+        let published = parts.clone();
+        parts.apply(add_to_parts(&parts, 7, 2));
+
+        let index = parts.index(&7);
+        for (i, (part, old)) in
+            parts.parts.iter().zip(published.parts.iter()).enumerate()
+        {
+            assert_eq!(Arc::ptr_eq(part, old), i != index);
+        }
+        assert_eq!(published.get_record(&7).val.unwrap().read().val.len(), 1);
+        assert_eq!(parts.get_record(&7).val.unwrap().read().val.len(), 2);
+    }
+
+    #[test]
+    fn shards_join_into_the_same_document() {
+        let mut doc = Document::default();
+        let mut ops = Vec::new();
+        for key in 0..100 {
+            let op = doc.add_item(
+                key,
+                doc.get_read_ctx().derive_add_ctx(1),
+                Item::Single(RecordEntry::text("initial")),
+            );
+            doc.apply(op);
+        }
+        let mut shards = Parts::split(&doc, 3).unwrap();
+        for key in (0..100).step_by(3) {
+            let ctx = doc.get_read_ctx().derive_add_ctx(2);
+            let op =
+                doc.add_item(key, ctx, Item::Single(RecordEntry::text("more")));
+            doc.apply(op.clone());
+            ops.push(op);
+        }
+        let removed: BTreeSet<_> = (0..100).step_by(5).collect();
+        let rm = Op::Rm {
+            clock: doc.get_read_ctx().add_clock,
+            keyset: removed,
+        };
+        doc.apply(rm.clone());
+        ops.push(rm);
+
+        for op in ops {
+            for (shard, op) in split_op(op, shards.len()) {
+                shards[shard].apply(op);
+            }
+        }
+        let clock = doc.get_read_ctx().add_clock;
+        let joined = Parts::concat(shards.iter(), clock).join().unwrap();
+        assert_eq!(joined.state_hash(), doc.state_hash());
+    }
+
+    #[test]
+    fn reads_match_the_joined_document() {
+        let mut shards = Parts::split(&Document::default(), 2).unwrap();
+        for key in 0..150 {
+            let shard = shard_of(&key, shards.len());
+            let op = add_to_parts(&shards[shard], key, 1);
+            shards[shard].apply(op);
+        }
+        let mut clock = VClock::new();
+        for shard in shards.iter() {
+            clock.merge(shard.get_read_ctx().add_clock);
+        }
+        let parts = Parts::concat(shards.iter(), clock);
+        let doc = parts.join().unwrap();
+
+        let keys = |page: &[PagedRecord]| -> Vec<RecordKey> {
+            page.iter().map(|paged| paged.key).collect()
+        };
+        let mut after = None;
+        loop {
+            let (page, next) = parts.records_page(after.as_ref(), 40);
+            let (doc_page, doc_next) = doc.records_page(after.as_ref(), 40);
+            assert_eq!(keys(&page), keys(&doc_page));
+            assert_eq!(next, doc_next);
+            after = match next {
+                Some(next) => Some(next),
+                None => break,
+            };
+        }
+        let (page, next) = parts.records_page(None, 0);
+        assert_eq!((keys(&page), next), (vec![0], Some(0)));
+        let chunks: Vec<_> =
+            parts.record_chunks(64).map(|c| keys(&c)).collect();
+        let doc_chunks: Vec<_> =
+            doc.record_chunks(64).map(|c| keys(&c)).collect();
+        assert_eq!(chunks, doc_chunks);
+        assert_eq!(parts.record_count(), 150);
+        assert_eq!(parts.merkle_tree().root(), doc.merkle_tree().root());
+        assert_eq!(parts.content_hash(), doc.content_hash());
+        assert_eq!(parts.state_hash(), doc.state_hash());
+    }
+
+    #[test]
+    fn trees_are_kept_for_parts_that_didnt_change() {
+        let mut parts = Parts::default();
+        for key in 0..100 {
+            parts.apply(add_to_parts(&parts, key, 1));
+        }
+        let root = parts.merkle_tree().root();
+        let published = parts.clone();
+        parts.apply(add_to_parts(&parts, 7, 2));
+
+        let index = parts.index(&7);
+        for (i, part) in parts.parts.iter().enumerate() {
+            assert_eq!(part.tree.get().is_some(), i != index);
+        }
+        assert!(published.parts[index].tree.get().is_some());
+        let tree = parts.merkle_tree();
+        assert_ne!(tree.root(), root);
+        assert_eq!(tree.root(), MerkleTree::of(parts.records()).root());
+    }
+}
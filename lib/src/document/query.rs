@@ -0,0 +1,108 @@
+use serde_json::Value;
+
+use super::{DocKey, Document};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonFilter {
+    pub pointer: String,
+    pub value: Value,
+}
+
+impl JsonFilter {
+    pub fn new(pointer: &str, value: Value) -> Self {
+        JsonFilter {
+            pointer: pointer.to_string(),
+            value,
+        }
+    }
+
+    pub fn matches(&self, entry: &Value) -> bool {
+        entry.pointer(&self.pointer) == Some(&self.value)
+    }
+}
+
+impl<K: DocKey> Document<K> {
+    /// Returns the JSON entries of the record at `key` matching `filter`,
+    /// e.g. every entry where `/status` is `"open"`.
+    pub fn query_record(&self, key: &K, filter: &JsonFilter) -> Vec<Value> {
+        let record = match self.get_record(key).val {
+            Some(record) => record,
+            None => return Vec::new(),
+        };
+
+        record
+            .read()
+            .val
+            .iter()
+            .filter_map(|entry| entry.as_json())
+            .filter(|value| filter.matches(value))
+            .collect()
+    }
+
+    /// Like `query_record`, but across every record in the document.
+    pub fn query(&self, filter: &JsonFilter) -> Vec<(K, Value)> {
+        self.records
+            .iter()
+            .flat_map(|item_ctx| {
+                let (key, record) = item_ctx.val;
+                record
+                    .read()
+                    .val
+                    .iter()
+                    .filter_map(|entry| entry.as_json())
+                    .filter(|value| filter.matches(value))
+                    .map(|value| (key.clone(), value))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Item, RecordEntry, RecordKey};
+    use serde_json::json;
+
+    fn doc(records: &[(RecordKey, Vec<RecordEntry>)]) -> Document {
+        let mut doc = Document::default();
+        for (key, entries) in records.iter() {
+            let ctx = doc.get_read_ctx().derive_add_ctx(1);
+            let op = doc.add_item(*key, ctx, Item::Multiple(entries.clone()));
+            doc.apply(op);
+        }
+        doc
+    }
+
+    #[test]
+    fn pointers_match_json_entries_only() {
+        let open = json!({"status": "open", "tags": ["a", "b"]});
+        let closed = json!({"status": "closed", "tags": ["b"]});
+        let doc = doc(&[
+            (
+                1,
+                vec![RecordEntry::json(&open), RecordEntry::json(&closed)],
+            ),
+            (2, vec![RecordEntry::json(&closed)]),
+            // text that reads as matching JSON is still text
+            (3, vec![RecordEntry::text(r#"{"status":"open"}"#)]),
+            (4, vec![RecordEntry::from(vec![0xff])]),
+        ]);
+
+        let filter = JsonFilter::new("/status", json!("open"));
+        assert_eq!(doc.query_record(&1, &filter), vec![open.clone()]);
+        assert_eq!(doc.query_record(&2, &filter), Vec::<Value>::new());
+        assert_eq!(doc.query_record(&3, &filter), Vec::<Value>::new());
+        assert_eq!(doc.query_record(&9, &filter), Vec::<Value>::new());
+        assert_eq!(doc.query(&filter), vec![(1, open.clone())]);
+
+        let second_tag = JsonFilter::new("/tags/1", json!("b"));
+        assert_eq!(doc.query(&second_tag), vec![(1, open)]);
+        let mut closed_ones =
+            doc.query(&JsonFilter::new("/tags/0", json!("b")));
+        closed_ones.sort_by_key(|(key, _)| *key);
+        assert_eq!(closed_ones, vec![(1, closed.clone()), (2, closed)]);
+        let missing = JsonFilter::new("/owner", Value::Null);
+        assert!(doc.query(&missing).is_empty());
+    }
+}
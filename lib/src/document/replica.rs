@@ -0,0 +1,314 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crdts::CvRDT;
+
+use crate::causal::{self, CausalBuffer, Delivery};
+use crate::oplog::{LogStats, OpId, OpLog};
+
+use super::{
+    DocActor, DocKey, Document, DocumentOp, Item, RecordEntry, RecordKey,
+    RecordSet,
+};
+
+/// A local-first replica of a document. Edits made through it are applied
+/// locally at once and queued for sending; they stay unacknowledged until
+/// the server acks them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct DocReplica<K: DocKey = RecordKey> {
+    doc: Document<K>,
+    actor: DocActor,
+    log: OpLog<K>,
+    buffer: CausalBuffer<K>,
+    queued: Vec<DocumentOp<K>>,
+    unacked: Vec<DocumentOp<K>>,
+}
+
+impl<K: DocKey> Default for DocReplica<K> {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<K: DocKey> DocReplica<K> {
+    pub fn new(actor: DocActor) -> Self {
+        Self::from_document(actor, &Document::default())
+    }
+
+    pub fn from_document(actor: DocActor, doc: &Document<K>) -> Self {
+        DocReplica {
+            doc: doc.clone(),
+            actor,
+            log: OpLog::new(),
+            buffer: CausalBuffer::new(),
+            queued: Vec::new(),
+            unacked: Vec::new(),
+        }
+    }
+
+    /// Rebuilds a replica from a stored document and the local ops that
+    /// were never acknowledged, queueing those to be sent again.
+    pub fn restore(
+        actor: DocActor,
+        doc: Document<K>,
+        unacked: Vec<DocumentOp<K>>,
+    ) -> Self {
+        let mut replica = Self::from_document(actor, &doc);
+        replica.queued = unacked;
+        replica
+    }
+
+    pub fn actor(&self) -> DocActor {
+        self.actor
+    }
+
+    /// Makes later edits as `actor`. Edits already made keep their dots
+    /// and are still sent and acknowledged, and remote ops waiting for
+    /// their history keep waiting.
+    pub fn set_actor(&mut self, actor: DocActor) {
+        self.actor = actor;
+    }
+
+    pub fn document(&self) -> &Document<K> {
+        &self.doc
+    }
+
+    pub fn add(&mut self, key: K, entry: RecordEntry) -> DocumentOp<K> {
+        self.add_item(key, Item::Single(entry))
+    }
+
+    pub fn add_item(
+        &mut self,
+        key: K,
+        item: Item<RecordEntry>,
+    ) -> DocumentOp<K> {
+        let add_ctx = self.doc.get_read_ctx().derive_add_ctx(self.actor);
+        let op = self.doc.add_item(key, add_ctx, item);
+        self.local_op(op)
+    }
+
+    /// See `Document::create_record`.
+    pub fn create_record(&mut self, initial: Item<RecordEntry>) -> Option<K> {
+        let (key, op) = self.doc.create_record(self.actor, initial)?;
+        self.local_op(op);
+        Some(key)
+    }
+
+    pub fn remove(&mut self, key: K, entry: RecordEntry) -> DocumentOp<K> {
+        self.remove_entries(key, vec![entry])
+    }
+
+    pub fn remove_entries(
+        &mut self,
+        key: K,
+        entries: Vec<RecordEntry>,
+    ) -> DocumentOp<K> {
+        let add_ctx = self.doc.get_read_ctx().derive_add_ctx(self.actor);
+        let op = self.doc.remove_entries(key, add_ctx, entries);
+        self.local_op(op)
+    }
+
+    pub fn remove_record(&mut self, key: K) -> DocumentOp<K> {
+        let op = self.doc.remove_record(key);
+        self.local_op(op)
+    }
+
+    fn local_op(&mut self, op: DocumentOp<K>) -> DocumentOp<K> {
+        self.apply_op(op.clone());
+        self.queued.push(op.clone());
+        op
+    }
+
+    /// Applies `op`, or buffers it until its causal history has arrived.
+    /// Ops that were already logged or are already pending are counted as
+    /// duplicates and otherwise ignored.
+    pub fn apply_op(&mut self, op: DocumentOp<K>) -> Delivery {
+        let clock = self.doc.get_read_ctx().add_clock;
+        if self.log.contains(&op) || self.buffer.pending().contains(&op) {
+            self.log.record_duplicate();
+            return Delivery::Duplicate;
+        }
+        let delivery = causal::delivery(&clock, &op);
+        for op in self.buffer.deliver(&clock, op) {
+            self.log.push(op.clone());
+            self.doc.apply(op);
+        }
+        delivery
+    }
+
+    /// Merges a full copy of the document, e.g. one fetched from the
+    /// server, and applies whatever buffered ops that makes ready.
+    pub fn merge_document(&mut self, doc: Document<K>) {
+        self.doc.records.merge(doc.records);
+        let pending = self.buffer.pending().to_vec();
+        self.buffer = CausalBuffer::new();
+        for op in pending {
+            self.apply_op(op);
+        }
+    }
+
+    /// Merges records found to differ from the server's, see
+    /// `Document::merge_records`, and applies whatever buffered ops that
+    /// makes ready.
+    pub fn merge_records(&mut self, set: RecordSet<K>) -> Option<()> {
+        self.doc.merge_records(set)?;
+        let pending = self.buffer.pending().to_vec();
+        self.buffer = CausalBuffer::new();
+        for op in pending {
+            self.apply_op(op);
+        }
+        Some(())
+    }
+
+    /// Hands over the queued local ops for sending; they are then kept as
+    /// unacknowledged until `ack` is called for them.
+    pub fn take_queued(&mut self) -> Vec<DocumentOp<K>> {
+        let queued = std::mem::take(&mut self.queued);
+        self.unacked.extend(queued.iter().cloned());
+        queued
+    }
+
+    /// Puts sent but unacknowledged ops back in front of the queue, e.g.
+    /// after the connection they were sent on was lost. Each op is queued
+    /// once, however many times it was handed over.
+    pub fn requeue_unacked(&mut self) {
+        let mut queued = std::mem::take(&mut self.unacked);
+        queued.append(&mut self.queued);
+        let mut seen = HashSet::new();
+        queued.retain(|op| seen.insert(OpId::of(op)));
+        self.queued = queued;
+    }
+
+    /// Every local op the server hasn't acknowledged, oldest first.
+    pub fn unacked_ops(&self) -> Vec<DocumentOp<K>> {
+        self.unacked
+            .iter()
+            .chain(self.queued.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn ack(&mut self, id: &OpId<K>) -> bool {
+        let len = self.pending_local();
+        self.unacked.retain(|op| &OpId::of(op) != id);
+        self.queued.retain(|op| &OpId::of(op) != id);
+        self.pending_local() < len
+    }
+
+    pub fn queued(&self) -> &[DocumentOp<K>] {
+        &self.queued
+    }
+
+    pub fn unacked(&self) -> &[DocumentOp<K>] {
+        &self.unacked
+    }
+
+    /// Local ops the server hasn't acknowledged yet, sent or not.
+    pub fn pending_local(&self) -> usize {
+        self.queued.len() + self.unacked.len()
+    }
+
+    pub fn log(&self) -> &OpLog<K> {
+        &self.log
+    }
+
+    pub fn stats(&self) -> LogStats {
+        self.log.stats(&self.buffer)
+    }
+
+    pub fn pending(&self) -> &[DocumentOp<K>] {
+        self.buffer.pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crdts::map::Op;
+
+    #[test]
+    fn requeued_ops_are_sent_once() {
+        let mut replica = DocReplica::new(1);
+        let op = replica.add(1, RecordEntry::text("a"));
+        replica.add(2, RecordEntry::text("b"));
+        let sent = replica.take_queued();
+        assert_eq!(sent.len(), 2);
+
+        let mut restored =
+            DocReplica::restore(1, replica.document().clone(), sent);
+        restored.queued.push(op);
+        restored.take_queued();
+        restored.requeue_unacked();
+        assert_eq!(restored.queued().len(), 2);
+        let id = OpId::of(&restored.queued()[0]);
+        assert!(restored.ack(&id));
+        assert_eq!(restored.pending_local(), 1);
+    }
+
+    #[test]
+    fn changing_actor_keeps_pending_ops() {
+        let mut replica = DocReplica::new(1);
+        let sent = replica.add(1, RecordEntry::text("a"));
+        replica.take_queued();
+        let queued = replica.add(1, RecordEntry::text("b"));
+        let mut remote = DocReplica::new(2);
+        remote.add(2, RecordEntry::text("c"));
+        let waiting = remote.add(2, RecordEntry::text("d"));
+        replica.apply_op(waiting);
+
+        replica.set_actor(3);
+        assert_eq!(replica.unacked(), &[sent][..]);
+        assert_eq!(replica.queued(), &[queued][..]);
+        assert_eq!(replica.pending().len(), 1);
+        match replica.add(1, RecordEntry::text("e")) {
+            Op::Up { dot, .. } => assert_eq!(dot.actor, 3),
+            op => panic!("expected an update, got {:?}", op),
+        }
+        assert_eq!(replica.pending_local(), 3);
+    }
+
+    #[test]
+    fn acks_drop_sent_and_queued_ops() {
+        let mut replica = DocReplica::new(1);
+        let sent = replica.add(1, RecordEntry::text("a"));
+        replica.take_queued();
+        let queued = replica.add(1, RecordEntry::text("b"));
+        assert_eq!(replica.pending_local(), 2);
+
+        assert!(replica.ack(&OpId::of(&sent)));
+        assert!(!replica.ack(&OpId::of(&sent)));
+        assert!(replica.ack(&OpId::of(&queued)));
+        assert_eq!(replica.pending_local(), 0);
+        assert_eq!(replica.unacked_ops(), Vec::new());
+    }
+
+    #[test]
+    fn remote_ops_wait_for_their_history() {
+        let mut local = DocReplica::new(1);
+        let first = local.add(1, RecordEntry::text("a"));
+        let second = local.add(2, RecordEntry::text("b"));
+        assert!(local.document().get_record(&2).val.is_some());
+        assert_eq!(local.queued().len(), 2);
+
+        let mut remote = DocReplica::new(2);
+        assert_eq!(remote.apply_op(second.clone()), Delivery::Pending);
+        assert!(remote.document().get_record(&2).val.is_none());
+        assert_eq!(remote.apply_op(first.clone()), Delivery::Ready);
+        assert_eq!(remote.pending().len(), 0);
+        assert!(remote.document().get_record(&2).val.is_some());
+        assert_eq!(remote.apply_op(first), Delivery::Duplicate);
+        assert_eq!(remote.stats().duplicates, 1);
+        assert_eq!(remote.pending_local(), 0);
+
+        let mut fetched = DocReplica::new(3);
+        fetched.apply_op(second);
+        fetched.merge_document(local.document().clone());
+        assert_eq!(fetched.pending().len(), 0);
+        assert_eq!(
+            fetched.document().state_hash(),
+            local.document().state_hash()
+        );
+    }
+}
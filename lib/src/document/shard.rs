@@ -0,0 +1,133 @@
+use crdts::{map::Op, VClock};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::gc;
+use super::merkle::key_hash;
+use super::{DocActor, DocKey, Document, DocumentOp, RecordSet};
+
+/// The shard, out of `shards`, that holds the record at `key`. Shards hold
+/// consecutive ranges of `key_hash`, which doesn't change between builds,
+/// so logs written by one server are read back the same by the next.
+pub fn shard_of<K: DocKey>(key: &K, shards: usize) -> usize {
+    let shards = shards.max(1) as u128;
+    ((key_hash(key) as u128 * shards) >> 64) as usize
+}
+
+/// Splits `op` into the ops each shard has to apply. A map remove is split
+/// by key, every part keeping the full clock.
+pub fn split_op<K: DocKey>(
+    op: DocumentOp<K>,
+    shards: usize,
+) -> Vec<(usize, DocumentOp<K>)> {
+    match op {
+        Op::Up { dot, key, op } => {
+            vec![(shard_of(&key, shards), Op::Up { dot, key, op })]
+        }
+        Op::Rm { clock, keyset } => {
+            let mut parts: BTreeMap<usize, BTreeSet<K>> = BTreeMap::new();
+            for key in keyset {
+                parts.entry(shard_of(&key, shards)).or_default().insert(key);
+            }
+            parts
+                .into_iter()
+                .map(|(shard, keyset)| {
+                    let clock = clock.clone();
+                    (shard, Op::Rm { clock, keyset })
+                })
+                .collect()
+        }
+    }
+}
+
+/// Splits `set` into the part each shard has to merge. Every part keeps
+/// the clock and root of the whole set.
+pub fn split_record_set<K: DocKey>(
+    set: RecordSet<K>,
+    shards: usize,
+) -> Vec<RecordSet<K>> {
+    let shards = shards.max(1);
+    let mut parts: Vec<_> = (0..shards)
+        .map(|_| RecordSet {
+            keys: Vec::new(),
+            records: Vec::new(),
+            clock: set.clock.clone(),
+            root: set.root,
+        })
+        .collect();
+    for key in set.keys {
+        parts[shard_of(&key, shards)].keys.push(key);
+    }
+    for paged in set.records {
+        parts[shard_of(&paged.key, shards)].records.push(paged);
+    }
+    parts
+}
+
+impl<K: DocKey> Document<K> {
+    /// Applies `op` to a document holding only some of the records, like a
+    /// shard. A remove's clock may cover ops that went to the other parts;
+    /// everything it covers here has been applied, so the rest is dropped
+    /// rather than deferred until ops that will never arrive here.
+    pub fn apply_part(&mut self, op: DocumentOp<K>) {
+        match op {
+            Op::Rm { mut clock, keyset } => {
+                clock.glb(&self.get_read_ctx().add_clock);
+                self.apply(Op::Rm { clock, keyset });
+            }
+            op => self.apply(op),
+        }
+    }
+
+    /// Partitions the records into `shards` documents by `shard_of`. Every
+    /// part keeps the whole clock, so ops the document has already seen
+    /// are ignored by all of them.
+    pub fn split(&self, shards: usize) -> Option<Vec<Document<K>>> {
+        let shards = shards.max(1);
+        let parts =
+            gc::partition(&self.records, shards, |key| shard_of(key, shards))?;
+        Some(
+            parts
+                .into_iter()
+                .map(|records| Document { records })
+                .collect(),
+        )
+    }
+
+    /// Puts shards of a document back together at `clock`. The parts hold
+    /// disjoint keys, so their entries are taken as they are rather than
+    /// merged, which would drop entries one shard's clock has seen and
+    /// another shard holds.
+    pub fn join<'a, I>(parts: I, clock: VClock<DocActor>) -> Option<Self>
+    where
+        I: IntoIterator<Item = &'a Document<K>>,
+    {
+        let maps = parts.into_iter().map(|part| &part.records);
+        Some(Document {
+            records: gc::join(maps, clock)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::RecordKey;
+
+    // each shard holds a range of key hashes, so splitting a shard further
+    // keeps its records together, and its place only depends on the key
+    #[test]
+    fn shards_are_key_hash_ranges() {
+        let mut keys: Vec<RecordKey> = (0..1000).collect();
+        keys.sort_by_key(key_hash);
+        for pair in keys.windows(2) {
+            assert!(shard_of(&pair[0], 4) <= shard_of(&pair[1], 4));
+        }
+        for key in keys.iter() {
+            assert_eq!(shard_of(key, 16) / 4, shard_of(key, 4));
+            assert!(shard_of(key, 3) < 3);
+        }
+        assert_eq!(shard_of(&7u64, 1), 0);
+        assert_eq!(shard_of(&7u64, 4), (key_hash(&7u64) >> 62) as usize);
+    }
+}
@@ -0,0 +1,34 @@
+//! Ops the unit tests build documents from.
+
+use crate::document::{
+    DocActor, Document, DocumentOp, Item, Parts, RecordEntry, RecordKey,
+};
+
+// what `actor` adds to the record at `key`, different for each of them
+fn entry(key: RecordKey, actor: DocActor) -> Item<RecordEntry> {
+    Item::Single(RecordEntry::text(&format!("{} from {}", key, actor)))
+}
+
+/// An op adding an entry to the record at `key` as `actor`, made against
+/// `doc` without applying it.
+pub fn add_op(doc: &Document, key: RecordKey, actor: DocActor) -> DocumentOp {
+    let ctx = doc.get_read_ctx().derive_add_ctx(actor);
+    doc.add_item(key, ctx, entry(key, actor))
+}
+
+/// Like `add_op`, and applies the op to `doc`.
+pub fn add(doc: &mut Document, key: RecordKey, actor: DocActor) -> DocumentOp {
+    let op = add_op(doc, key, actor);
+    doc.apply(op.clone());
+    op
+}
+
+/// Like `add_op`, made against the parts of a document.
+pub fn add_to_parts(
+    parts: &Parts,
+    key: RecordKey,
+    actor: DocActor,
+) -> DocumentOp {
+    let ctx = parts.get_read_ctx().derive_add_ctx(actor);
+    parts.add_item(key, ctx, entry(key, actor))
+}
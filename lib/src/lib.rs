@@ -1,2 +1,9 @@
+pub mod backoff;
+pub mod causal;
 pub mod cmd;
 pub mod document;
+pub mod oplog;
+pub mod sim;
+
+#[cfg(test)]
+mod fixtures;
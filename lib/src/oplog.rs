@@ -0,0 +1,436 @@
+use serde::{Deserialize, Serialize};
+
+use crdts::{map::Op, orswot, CmRDT, Dot, VClock};
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+};
+
+use crate::causal::CausalBuffer;
+use crate::document::{
+    blame::write_received, DocActor, DocKey, DocumentOp, RecordEntry, RecordKey,
+};
+
+/// Identifies an op for deduplication and acknowledgement. Updates are
+/// identified by their dot; map removes carry no dot, so they are
+/// identified by their clock and keys.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub enum OpId<K: DocKey = RecordKey> {
+    Dot(Dot<DocActor>),
+    Rm {
+        clock: VClock<DocActor>,
+        keyset: BTreeSet<K>,
+    },
+}
+
+impl<K: DocKey> OpId<K> {
+    pub fn of(op: &DocumentOp<K>) -> Self {
+        match op {
+            Op::Up { dot, .. } => OpId::Dot(*dot),
+            Op::Rm { clock, keyset } => OpId::Rm {
+                clock: clock.clone(),
+                keyset: keyset.clone(),
+            },
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct LogStats {
+    pub logged: usize,
+    pub duplicates: u64,
+    pub pending: usize,
+}
+
+impl std::ops::AddAssign for LogStats {
+    fn add_assign(&mut self, other: LogStats) {
+        self.logged += other.logged;
+        self.duplicates += other.duplicates;
+        self.pending += other.pending;
+    }
+}
+
+/// What an op did to a record, e.g. `added "a", "b"`.
+pub fn summarize<K: DocKey>(op: &DocumentOp<K>) -> String {
+    let quoted = |members: &[RecordEntry]| {
+        let quoted: Vec<_> =
+            members.iter().map(|m| format!("\"{}\"", m)).collect();
+        quoted.join(", ")
+    };
+    match op {
+        Op::Up {
+            op: orswot::Op::Add { members, .. },
+            ..
+        } => format!("added {}", quoted(members)),
+        Op::Up {
+            op: orswot::Op::Rm { members, .. },
+            ..
+        } => format!("removed {}", quoted(members)),
+        Op::Rm { .. } => "removed the record".into(),
+    }
+}
+
+/// An op in the history of a record: its place in the history, counting
+/// from the first logged op that touched the record, the actor that made
+/// it, which map removes don't record, and when the server received it,
+/// in microseconds since the Unix epoch, if it knows.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct HistoryEntry<K: DocKey = RecordKey> {
+    pub seq: u64,
+    pub actor: Option<DocActor>,
+    pub received: Option<u64>,
+    pub summary: String,
+    pub op: DocumentOp<K>,
+}
+
+impl<K: DocKey> HistoryEntry<K> {
+    pub fn new(seq: u64, op: DocumentOp<K>, received: Option<u64>) -> Self {
+        let actor = match &op {
+            Op::Up { dot, .. } => Some(dot.actor),
+            Op::Rm { .. } => None,
+        };
+        HistoryEntry {
+            seq,
+            actor,
+            received,
+            summary: summarize(&op),
+            op,
+        }
+    }
+}
+
+impl<K: DocKey> fmt::Display for HistoryEntry<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.seq, self.summary)?;
+        if let Some(actor) = self.actor {
+            write!(f, " by actor {}", actor)?;
+        }
+        if let Some(received) = self.received {
+            write_received(f, received)?;
+        }
+        Ok(())
+    }
+}
+
+/// The ops applied to a document, in the order they were applied, which
+/// is a causal order. Positions count every op ever logged, so they stay
+/// put when `trim` drops the oldest ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct OpLog<K: DocKey = RecordKey> {
+    ops: BTreeMap<u64, DocumentOp<K>>,
+    next: u64,
+    index: HashMap<OpId<K>, u64>,
+    // the positions of the ops touching each record, and how many of them
+    // have been trimmed
+    by_key: HashMap<K, Vec<u64>>,
+    trimmed_by_key: HashMap<K, u64>,
+    // the dots of the trimmed updates, which are still duplicates
+    trimmed: VClock<DocActor>,
+    // the position after the last trimmed op
+    trimmed_to: u64,
+    duplicates: u64,
+}
+
+impl<K: DocKey> Default for OpLog<K> {
+    fn default() -> Self {
+        OpLog {
+            ops: BTreeMap::new(),
+            next: 0,
+            index: HashMap::new(),
+            by_key: HashMap::new(),
+            trimmed_by_key: HashMap::new(),
+            trimmed: VClock::new(),
+            trimmed_to: 0,
+            duplicates: 0,
+        }
+    }
+}
+
+fn keys_of<K: DocKey>(op: &DocumentOp<K>) -> Vec<&K> {
+    match op {
+        Op::Up { key, .. } => vec![key],
+        Op::Rm { keyset, .. } => keyset.iter().collect(),
+    }
+}
+
+impl<K: DocKey> OpLog<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `op` unless it has already been logged, in which case it is
+    /// counted as a duplicate and `false` is returned.
+    pub fn push(&mut self, op: DocumentOp<K>) -> bool {
+        if self.contains(&op) {
+            self.duplicates += 1;
+            return false;
+        }
+        let position = self.next;
+        for key in keys_of(&op) {
+            self.by_key.entry(key.clone()).or_default().push(position);
+        }
+        self.index.insert(OpId::of(&op), position);
+        self.ops.insert(position, op);
+        self.next += 1;
+        true
+    }
+
+    /// Whether `op` has been logged, counting updates that were trimmed
+    /// since.
+    pub fn contains(&self, op: &DocumentOp<K>) -> bool {
+        match op {
+            Op::Up { dot, .. }
+                if self.trimmed.get(&dot.actor) >= dot.counter =>
+            {
+                true
+            }
+            op => self.index.contains_key(&OpId::of(op)),
+        }
+    }
+
+    pub fn get(&self, id: &OpId<K>) -> Option<&DocumentOp<K>> {
+        self.index.get(id).map(|position| &self.ops[position])
+    }
+
+    pub fn record_duplicate(&mut self) {
+        self.duplicates += 1;
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// The position the next op will be logged at.
+    pub fn position(&self) -> u64 {
+        self.next
+    }
+
+    /// Duplicates counted here and by `buffer`, which drops re-sent ops
+    /// that were applied without being logged, e.g. as part of a snapshot.
+    pub fn stats(&self, buffer: &CausalBuffer<K>) -> LogStats {
+        LogStats {
+            logged: self.len(),
+            duplicates: self.duplicates + buffer.duplicates(),
+            pending: buffer.pending_len(),
+        }
+    }
+
+    /// The logged ops a replica at `clock` is missing, in log order. Map
+    /// removes carry no dot, so every one still logged is included, see
+    /// `trim_removes`; applying a remove twice is harmless.
+    pub fn since(&self, clock: &VClock<DocActor>) -> Vec<DocumentOp<K>> {
+        self.iter()
+            .filter(|op| match op {
+                Op::Up { dot, .. } => clock.get(&dot.actor) < dot.counter,
+                Op::Rm { .. } => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The ops logged at `position` or after it, in log order, or `None`
+    /// if some of them have been trimmed.
+    pub fn from_position(&self, position: u64) -> Option<Vec<DocumentOp<K>>> {
+        if self.trimmed_to > position {
+            return None;
+        }
+        Some(
+            self.ops
+                .range(position..)
+                .map(|(_, op)| op.clone())
+                .collect(),
+        )
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DocumentOp<K>> {
+        self.ops.values()
+    }
+
+    /// The logged ops that touched the record at `key`, in log order,
+    /// which is a causal order.
+    pub fn history(
+        &self,
+        key: &K,
+    ) -> impl ExactSizeIterator<Item = &DocumentOp<K>> + DoubleEndedIterator
+    {
+        let positions = self.by_key.get(key).map_or(&[][..], Vec::as_slice);
+        positions.iter().map(move |position| &self.ops[position])
+    }
+
+    /// How many of the ops that touched the record at `key` have been
+    /// trimmed, i.e. where `history` starts in the record's whole history.
+    pub fn history_start(&self, key: &K) -> u64 {
+        self.trimmed_by_key.get(key).copied().unwrap_or(0)
+    }
+
+    /// Drops the oldest updates until at most `max` ops are left, and
+    /// returns them. Their dots are still recognized as duplicates. Map
+    /// removes are kept, since a replica's clock doesn't show whether it
+    /// has seen one; `trim_removes` drops them.
+    pub fn trim(&mut self, max: usize) -> Vec<DocumentOp<K>> {
+        let excess = self.len().saturating_sub(max);
+        let positions: Vec<u64> = self
+            .ops
+            .iter()
+            .filter(|(_, op)| matches!(op, Op::Up { .. }))
+            .map(|(&position, _)| position)
+            .take(excess)
+            .collect();
+        self.trim_positions(positions)
+    }
+
+    /// Drops the map removes whose clock is dominated by `stable`, and
+    /// returns them. Replicas only acknowledge clocks they caught up to
+    /// with the removes logged so far, so once every one of them has
+    /// acknowledged what such a remove covers, none needs it sent again.
+    /// A trimmed remove that is sent again is logged again; applying it
+    /// twice is harmless.
+    pub fn trim_removes(
+        &mut self,
+        stable: &VClock<DocActor>,
+    ) -> Vec<DocumentOp<K>> {
+        let positions = self
+            .ops
+            .iter()
+            .filter(|(_, op)| match op {
+                Op::Rm { clock, .. } => clock <= stable,
+                Op::Up { .. } => false,
+            })
+            .map(|(&position, _)| position)
+            .collect();
+        self.trim_positions(positions)
+    }
+
+    fn trim_positions(&mut self, positions: Vec<u64>) -> Vec<DocumentOp<K>> {
+        let mut trimmed = Vec::with_capacity(positions.len());
+        for position in positions {
+            let op = match self.ops.remove(&position) {
+                Some(op) => op,
+                None => continue,
+            };
+            self.index.remove(&OpId::of(&op));
+            self.trimmed_to = self.trimmed_to.max(position + 1);
+            if let Op::Up { dot, .. } = &op {
+                self.trimmed.apply(*dot);
+            }
+            for key in keys_of(&op) {
+                *self.trimmed_by_key.entry(key.clone()).or_default() += 1;
+                if let Some(positions) = self.by_key.get_mut(key) {
+                    if let Ok(i) = positions.binary_search(&position) {
+                        positions.remove(i);
+                    }
+                    if positions.is_empty() {
+                        self.by_key.remove(key);
+                    }
+                }
+            }
+            trimmed.push(op);
+        }
+        trimmed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::fixtures::add;
+
+    #[test]
+    fn trimming_keeps_removes_positions_and_duplicates() {
+        let mut doc = Document::default();
+        let mut log = OpLog::new();
+        let first = add(&mut doc, 1, 1);
+        log.push(first.clone());
+        let remove = doc.remove_record(1);
+        doc.apply(remove.clone());
+        log.push(remove.clone());
+        let adds: Vec<_> = (0..3).map(|_| add(&mut doc, 1, 2)).collect();
+        for op in adds.iter() {
+            log.push(op.clone());
+        }
+        assert_eq!(log.len(), 5);
+
+        let trimmed = log.trim(3);
+        assert_eq!(trimmed, vec![first.clone(), adds[0].clone()]);
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.position(), 5);
+        assert!(log.contains(&remove));
+        assert_eq!(log.history_start(&1), 2);
+        let history: Vec<_> = log.history(&1).cloned().collect();
+        assert_eq!(
+            history,
+            vec![remove.clone(), adds[1].clone(), adds[2].clone()]
+        );
+        assert_eq!(log.from_position(2), None);
+        assert_eq!(
+            log.from_position(3),
+            Some(vec![adds[1].clone(), adds[2].clone()])
+        );
+
+        assert!(!log.push(first));
+        assert!(!log.push(remove));
+        assert_eq!(log.duplicates(), 2);
+        assert_eq!(log.len(), 3);
+    }
+
+    #[test]
+    fn removes_are_trimmed_once_stable() {
+        let mut doc = Document::default();
+        let mut log = OpLog::new();
+        let first = add(&mut doc, 1, 1);
+        log.push(first.clone());
+        let covered = doc.get_read_ctx().add_clock;
+        let remove = doc.remove_record(1);
+        doc.apply(remove.clone());
+        log.push(remove.clone());
+        let later = add(&mut doc, 2, 2);
+        log.push(later.clone());
+
+        assert!(log.trim_removes(&VClock::new()).is_empty());
+        assert!(log.since(&doc.get_read_ctx().add_clock).contains(&remove));
+        assert_eq!(log.trim_removes(&covered), vec![remove.clone()]);
+        assert_eq!(log.since(&covered), vec![later.clone()]);
+        assert_eq!(log.since(&VClock::new()), vec![first, later.clone()]);
+        assert_eq!(log.history(&1).count(), 1);
+        assert_eq!(log.history_start(&1), 1);
+        assert_eq!(log.from_position(1), None);
+        assert_eq!(log.from_position(2), Some(vec![later]));
+        assert!(log.push(remove));
+        assert_eq!(log.len(), 3);
+    }
+
+    #[test]
+    fn resent_ops_are_logged_once() {
+        let mut doc = Document::default();
+        let mut log = OpLog::new();
+        let added = add(&mut doc, 1, 1);
+        let removed = doc.remove_record(1);
+        assert!(log.push(added.clone()));
+        assert!(log.push(removed.clone()));
+
+        assert!(!log.push(added.clone()));
+        assert!(!log.push(doc.remove_record(1)));
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.get(&OpId::of(&removed)), Some(&removed));
+
+        let mut buffer = CausalBuffer::new();
+        buffer.deliver(&doc.get_read_ctx().add_clock, added);
+        let stats = log.stats(&buffer);
+        assert_eq!((stats.logged, stats.duplicates, stats.pending), (2, 3, 0));
+    }
+}
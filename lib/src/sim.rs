@@ -0,0 +1,315 @@
+//! A deterministic simulation of replicas editing a document and sending
+//! each other their ops over an unreliable network, to check that they
+//! converge.
+//!
+//! Everything random, the edits as well as the network's delays,
+//! duplicates and partitions, comes from one seeded generator, so a run
+//! that fails can be replayed exactly from its seed.
+
+use crate::document::{
+    entry::hex_bytes, DocActor, DocReplica, DocumentOp, Item, RecordEntry,
+    RecordKey,
+};
+
+use std::fmt;
+
+/// A small, seedable generator (SplitMix64). It's written out here rather
+/// than taken from a crate so a seed replays the same run whatever crate
+/// versions are in use.
+#[derive(Debug, Clone)]
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        SimRng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`, or 0 if `n` is 0.
+    pub fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next_u64() % n
+        }
+    }
+
+    /// True with probability `p`.
+    pub fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        items.get(self.below(items.len() as u64) as usize)
+    }
+}
+
+/// How many replicas there are, how much they edit, and how badly the
+/// network between them behaves. Delays are counted in steps.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub replicas: usize,
+    pub steps: u64,
+    pub records: u64,
+    pub edit_chance: f64,
+    pub max_delay: u64,
+    pub duplicate_chance: f64,
+    pub partition_chance: f64,
+    pub max_partition: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            replicas: 4,
+            steps: 300,
+            records: 6,
+            edit_chance: 0.7,
+            max_delay: 12,
+            duplicate_chance: 0.1,
+            partition_chance: 0.02,
+            max_partition: 40,
+        }
+    }
+}
+
+/// What happened during a run that converged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimReport {
+    pub edits: u64,
+    pub delivered: u64,
+    pub duplicated: u64,
+    pub partitions: u64,
+    pub steps_to_heal: u64,
+}
+
+/// A run whose replicas didn't converge; its seed replays it.
+#[derive(Debug, Clone)]
+pub struct SimFailure {
+    pub seed: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for SimFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "seed {}: {}", self.seed, self.reason)
+    }
+}
+
+impl std::error::Error for SimFailure {}
+
+#[derive(Debug, Clone)]
+struct Envelope {
+    from: usize,
+    to: usize,
+    deliver_at: u64,
+    op: DocumentOp<RecordKey>,
+}
+
+/// The replicas, the ops in flight between them, and which side of a
+/// partition each replica is on while there is one.
+struct Sim<'a> {
+    config: &'a SimConfig,
+    rng: SimRng,
+    replicas: Vec<DocReplica<RecordKey>>,
+    in_flight: Vec<Envelope>,
+    sides: Option<(Vec<bool>, u64)>,
+    now: u64,
+    report: SimReport,
+}
+
+impl<'a> Sim<'a> {
+    fn new(seed: u64, config: &'a SimConfig) -> Self {
+        // actor 0 is the server's
+        let replicas = (1..=config.replicas as DocActor)
+            .map(DocReplica::new)
+            .collect();
+        Sim {
+            config,
+            rng: SimRng::new(seed),
+            replicas,
+            in_flight: Vec::new(),
+            sides: None,
+            now: 0,
+            report: SimReport::default(),
+        }
+    }
+
+    fn edit(&mut self) {
+        let from = self.rng.below(self.replicas.len() as u64) as usize;
+        let key = self.rng.below(self.config.records);
+        let replica = &mut self.replicas[from];
+        let mut entries: Vec<_> = replica
+            .document()
+            .get_record(&key)
+            .val
+            .map(|record| record.read().val.into_iter().collect())
+            .unwrap_or_default();
+        // the set's order depends on its hasher, so pick from a sorted copy
+        entries.sort_by(|a: &RecordEntry, b| a.bytes.cmp(&b.bytes));
+
+        let op = match self.rng.below(10) {
+            0..=5 => {
+                let text = format!("{}:{}", from, self.report.edits);
+                replica.add_item(key, Item::Single(RecordEntry::text(&text)))
+            }
+            6..=8 if !entries.is_empty() => {
+                let entry = self.rng.pick(&entries).unwrap().clone();
+                replica.remove(key, entry)
+            }
+            _ => replica.remove_record(key),
+        };
+        replica.take_queued();
+        self.report.edits += 1;
+        self.broadcast(from, op);
+    }
+
+    fn broadcast(&mut self, from: usize, op: DocumentOp<RecordKey>) {
+        for to in 0..self.replicas.len() {
+            if to == from {
+                continue;
+            }
+            let copies = if self.rng.chance(self.config.duplicate_chance) {
+                self.report.duplicated += 1;
+                2
+            } else {
+                1
+            };
+            for _ in 0..copies {
+                let delay = self.rng.below(self.config.max_delay + 1);
+                self.in_flight.push(Envelope {
+                    from,
+                    to,
+                    deliver_at: self.now + delay,
+                    op: op.clone(),
+                });
+            }
+        }
+    }
+
+    // Replicas on different sides of a partition can't reach each other;
+    // what they send is held until it heals.
+    fn partition(&mut self) {
+        match &mut self.sides {
+            Some((_, until)) if *until <= self.now => self.sides = None,
+            Some(_) => (),
+            None if self.rng.chance(self.config.partition_chance) => {
+                let sides = (0..self.replicas.len())
+                    .map(|_| self.rng.chance(0.5))
+                    .collect();
+                let until =
+                    self.now + 1 + self.rng.below(self.config.max_partition);
+                self.sides = Some((sides, until));
+                self.report.partitions += 1;
+            }
+            None => (),
+        }
+    }
+
+    fn reachable(&self, envelope: &Envelope) -> bool {
+        match &self.sides {
+            Some((sides, _)) => sides[envelope.from] == sides[envelope.to],
+            None => true,
+        }
+    }
+
+    // Delivers what is due, in a random order.
+    fn deliver(&mut self) {
+        let mut due = Vec::new();
+        let mut waiting = Vec::new();
+        for envelope in std::mem::take(&mut self.in_flight) {
+            if envelope.deliver_at <= self.now && self.reachable(&envelope) {
+                due.push(envelope);
+            } else {
+                waiting.push(envelope);
+            }
+        }
+        self.in_flight = waiting;
+
+        while !due.is_empty() {
+            let i = self.rng.below(due.len() as u64) as usize;
+            let envelope = due.swap_remove(i);
+            self.replicas[envelope.to].apply_op(envelope.op);
+            self.report.delivered += 1;
+        }
+    }
+
+    fn step(&mut self) {
+        self.partition();
+        if self.rng.chance(self.config.edit_chance) {
+            self.edit();
+        }
+        self.deliver();
+        self.now += 1;
+    }
+
+    // No more edits and no partitions; runs until nothing is in flight.
+    fn heal(&mut self) {
+        self.sides = None;
+        let start = self.now;
+        while !self.in_flight.is_empty() {
+            self.deliver();
+            self.now += 1;
+        }
+        self.report.steps_to_heal = self.now - start;
+    }
+
+    fn check(&self) -> Result<(), String> {
+        for replica in self.replicas.iter() {
+            if !replica.pending().is_empty() {
+                return Err(format!(
+                    "actor {} still holds {} ops it couldn't apply",
+                    replica.actor(),
+                    replica.pending().len()
+                ));
+            }
+        }
+        let first = &self.replicas[0];
+        let content = first.document().content_hash();
+        let state = first.document().state_hash();
+        for replica in self.replicas.iter().skip(1) {
+            let doc = replica.document();
+            if doc.content_hash() != content {
+                return Err(format!(
+                    "actor {} has content {}, actor {} has {}",
+                    first.actor(),
+                    hex_bytes(&content),
+                    replica.actor(),
+                    hex_bytes(&doc.content_hash())
+                ));
+            }
+            if doc.state_hash() != state {
+                return Err(format!(
+                    "actors {} and {} show the same content but have \
+                     different causal state",
+                    first.actor(),
+                    replica.actor()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the simulation for `seed`: `config.steps` steps of random edits
+/// and deliveries, then delivery of everything left once the network has
+/// healed, after which every replica must hold the same document.
+pub fn run(seed: u64, config: &SimConfig) -> Result<SimReport, SimFailure> {
+    let mut sim = Sim::new(seed, config);
+    for _ in 0..config.steps {
+        sim.step();
+    }
+    sim.heal();
+    sim.check()
+        .map(|_| sim.report)
+        .map_err(|reason| SimFailure { seed, reason })
+}
@@ -0,0 +1,44 @@
+//! Runs the network simulation for many seeds and checks that every run
+//! converges.
+//!
+//! `SIM_SEEDS` sets how many seeds are run, starting from 0; `SIM_SEED`
+//! replays a single one, e.g. one printed by a failing run.
+
+use crdts_sandbox_lib::sim::{self, SimConfig};
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+}
+
+#[test]
+fn replicas_converge() {
+    let seeds = match env_u64("SIM_SEED") {
+        Some(seed) => seed..seed + 1,
+        None => 0..env_u64("SIM_SEEDS").unwrap_or(100),
+    };
+    let config = SimConfig::default();
+
+    let mut failed = Vec::new();
+    for seed in seeds {
+        if let Err(failure) = sim::run(seed, &config) {
+            eprintln!("{}", failure);
+            failed.push(seed);
+        }
+    }
+    assert!(
+        failed.is_empty(),
+        "replicas diverged; replay with SIM_SEED=<seed> for seeds {:?}",
+        failed
+    );
+}
+
+#[test]
+fn runs_replay_from_their_seed() {
+    let config = SimConfig::default();
+    let first = sim::run(7, &config).unwrap();
+    let second = sim::run(7, &config).unwrap();
+    assert_eq!(first, second);
+    assert!(first.edits > 0 && first.delivered > 0);
+}
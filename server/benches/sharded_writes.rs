@@ -0,0 +1,107 @@
+//! Write throughput with many concurrent writers as the document is split
+//! across more shards.
+//!
+//! Run with `cargo bench -p server --bench sharded_writes`; `BENCH_SECS`
+//! sets how long each case runs.
+
+use server::shard::Shards;
+
+use crdts_sandbox_lib::document::{
+    Command, DocActor, Document, Item, RecordEntry, RecordKey, SERVER_ACTOR,
+};
+
+use tokio::runtime::Runtime;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+const RECORDS: usize = 2000;
+const WRITERS: u32 = 16;
+
+fn prefilled() -> (Document<RecordKey>, Vec<RecordKey>) {
+    let mut doc = Document::default();
+    let mut keys = Vec::with_capacity(RECORDS);
+    for i in 0..RECORDS {
+        let initial = Item::Single(RecordEntry::text(&format!("entry {}", i)));
+        let (key, op) = doc.create_record(0, initial).unwrap();
+        doc.apply(op);
+        keys.push(key);
+    }
+    (doc, keys)
+}
+
+async fn write(shards: &Shards<RecordKey>, actor: DocActor, key: RecordKey) {
+    let add_ctx = shards.read_ctx().derive_add_ctx(actor);
+    let cmd = Command::Add {
+        add_ctx,
+        key,
+        item: Item::Single(RecordEntry::text(&format!("write by {}", actor))),
+    };
+    shards.handle(cmd).await;
+}
+
+fn run(shard_count: usize, duration: Duration) -> u64 {
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(async {
+        let (doc, keys) = prefilled();
+        let shards =
+            Arc::new(Shards::spawn(SERVER_ACTOR, doc, shard_count).unwrap());
+        let keys = Arc::new(keys);
+        let stop = Arc::new(AtomicBool::new(false));
+        let writes = Arc::new(AtomicU64::new(0));
+
+        let writers: Vec<_> = (1..=WRITERS)
+            .map(|actor| {
+                let shards = shards.clone();
+                let keys = keys.clone();
+                let stop = stop.clone();
+                let writes = writes.clone();
+                tokio::spawn(async move {
+                    let mut i = actor as usize;
+                    while !stop.load(Ordering::Relaxed) {
+                        // a stride coprime to the key count spreads each
+                        // writer over every shard
+                        i = (i + 7919) % keys.len();
+                        write(&shards, actor, keys[i]).await;
+                        writes.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+
+        tokio::time::delay_for(duration).await;
+        stop.store(true, Ordering::Relaxed);
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        writes.load(Ordering::Relaxed)
+    })
+}
+
+fn main() {
+    let secs = std::env::var("BENCH_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(3);
+    let duration = Duration::from_secs(secs);
+    println!(
+        "{} records, {} writers, {}s per case",
+        RECORDS, WRITERS, secs
+    );
+
+    let mut base = None;
+    for &shard_count in [1, 2, 4, 8].iter() {
+        let writes = run(shard_count, duration);
+        let rate = writes as f64 / duration.as_secs_f64();
+        let speedup = rate / *base.get_or_insert(rate);
+        println!(
+            "{:>2} shards {:>10.0} writes/s {:>6.2}x",
+            shard_count, rate, speedup
+        );
+    }
+}
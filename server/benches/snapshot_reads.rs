@@ -0,0 +1,203 @@
+//! Read throughput while a writer keeps adding entries, with readers going
+//! through the writer's lock versus loading published document versions.
+//!
+//! Run with `cargo bench -p server`; `BENCH_SECS` sets how long each case
+//! runs. Every case is run with readers and again with the writer alone.
+//!
+//! Writes per second on a single core, 3s per case, before and after the
+//! writer stopped copying the parts it writes to after every publish:
+//!
+//! | readers | case     | copying parts | two copies |
+//! |---------|----------|---------------|------------|
+//! | 4       | locked   |        36 807 |     40 437 |
+//! | 4       | batch 1  |         2 733 |      4 866 |
+//! | 4       | batch 64 |         4 232 |      5 781 |
+//! | 0       | locked   |       161 798 |    195 623 |
+//! | 0       | batch 1  |         8 484 |    122 470 |
+//! | 0       | batch 64 |         9 859 |    149 331 |
+//!
+//! Publishing itself, the `ArcSwap` store, costs about a microsecond.
+//! What cost the writer was copying a part, some 30 records and their
+//! entries, on its first write after each publish; the keys written are
+//! spread over every part, so batching hardly saved any copies. Now the
+//! writer takes back the version it replaces and makes the batch's
+//! changes to it again, see `State`. With readers, the writer shares the
+//! core with four threads that never block, unlike the ones waiting on
+//! the lock, so those rows mostly measure the scheduler.
+
+use server::state::State;
+
+use crdts_sandbox_lib::document::{
+    Command, DocResponse, Item, Parts, RecordEntry, RecordKey, SERVER_ACTOR,
+};
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+const RECORDS: usize = 2000;
+const READERS: usize = 4;
+const WRITER_ACTOR: u32 = 1;
+
+fn prefilled() -> (State<RecordKey>, Vec<RecordKey>) {
+    let mut state = State::new(SERVER_ACTOR, Parts::default());
+    let mut keys = Vec::with_capacity(RECORDS);
+    for i in 0..RECORDS {
+        let initial = Item::Single(RecordEntry::text(&format!("entry {}", i)));
+        if let Some(DocResponse::RecordCreated { key }) =
+            state.handle(Command::CreateRecord { initial })
+        {
+            keys.push(key);
+        }
+    }
+    state.publish();
+    (state, keys)
+}
+
+fn add_command(state: &State<RecordKey>, key: RecordKey, i: u64) -> Command {
+    let read_ctx = state.parts().get_read_ctx();
+    Command::Add {
+        add_ctx: read_ctx.derive_add_ctx(WRITER_ACTOR),
+        key,
+        item: Item::Single(RecordEntry::text(&format!("write {}", i))),
+    }
+}
+
+struct Throughput {
+    reads: u64,
+    writes: u64,
+}
+
+fn run<R, W>(
+    duration: Duration,
+    readers: usize,
+    read: R,
+    mut write: W,
+) -> Throughput
+where
+    R: Fn(usize) + Send + Sync + 'static,
+    W: FnMut(u64) + Send + 'static,
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicU64::new(0));
+    let read = Arc::new(read);
+
+    let readers: Vec<_> = (0..readers)
+        .map(|reader| {
+            let stop = stop.clone();
+            let reads = reads.clone();
+            let read = read.clone();
+            thread::spawn(move || {
+                let mut i = reader;
+                while !stop.load(Ordering::Relaxed) {
+                    read(i);
+                    i = i.wrapping_add(READERS);
+                    reads.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+
+    let writer = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut writes = 0;
+            while !stop.load(Ordering::Relaxed) {
+                write(writes);
+                writes += 1;
+            }
+            writes
+        })
+    };
+
+    thread::sleep(duration);
+    stop.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    let writes = writer.join().unwrap();
+
+    Throughput {
+        reads: reads.load(Ordering::Relaxed),
+        writes,
+    }
+}
+
+fn report(name: &str, duration: Duration, throughput: &Throughput) {
+    let secs = duration.as_secs_f64();
+    println!(
+        "{:<10} {:>12.0} reads/s {:>10.0} writes/s",
+        name,
+        throughput.reads as f64 / secs,
+        throughput.writes as f64 / secs
+    );
+}
+
+fn main() {
+    let secs = std::env::var("BENCH_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(3);
+    let duration = Duration::from_secs(secs);
+    println!("{} records, 1 writer, {}s per case", RECORDS, secs);
+    for &readers in [READERS, 0].iter() {
+        println!("{} readers:", readers);
+        cases(duration, readers);
+    }
+}
+
+fn cases(duration: Duration, readers: usize) {
+    let (state, keys) = prefilled();
+    let keys = Arc::new(keys);
+    let state = Arc::new(Mutex::new(state));
+    let read_state = state.clone();
+    let read_keys = keys.clone();
+    let write_keys = keys.clone();
+    let throughput = run(
+        duration,
+        readers,
+        move |i| {
+            let key = read_keys[i % read_keys.len()];
+            let state = read_state.lock().unwrap();
+            let _ = state.parts().get_record(&key);
+        },
+        move |i| {
+            let key = write_keys[i as usize % write_keys.len()];
+            let mut state = state.lock().unwrap();
+            let cmd = add_command(&state, key, i);
+            state.handle(cmd);
+        },
+    );
+    report("locked", duration, &throughput);
+
+    // the writer publishes once per batch; one write per batch is the
+    // worst case
+    for &batch in [1, 64].iter() {
+        let (mut state, keys) = prefilled();
+        let versions = state.versions();
+        let read_keys = Arc::new(keys);
+        let write_keys = read_keys.clone();
+        let throughput = run(
+            duration,
+            readers,
+            move |i| {
+                let key = read_keys[i % read_keys.len()];
+                let _ = versions.load().get_record(&key);
+            },
+            move |i| {
+                let key = write_keys[i as usize % write_keys.len()];
+                let cmd = add_command(&state, key, i);
+                state.handle(cmd);
+                if (i + 1) % batch == 0 {
+                    state.publish();
+                }
+            },
+        );
+        report(&format!("batch {}", batch), duration, &throughput);
+    }
+}
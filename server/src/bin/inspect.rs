@@ -0,0 +1,414 @@
+//! Looks at the op log and snapshot of a shard, see `server::persist`.
+//!
+//! ```text
+//! inspect list <log> [--actor <actor>] [--key <key>]
+//! inspect export <log> [--actor <actor>] [--key <key>]
+//! inspect show <log> <position>
+//! inspect verify <log>
+//! ```
+//!
+//! `list` prints one line per op, and per merge, with where it is in the
+//! log, when it was received, its actor and counter, the record it
+//! targets and the entries it adds or removes; `export` writes the same as
+//! JSON Lines. `show` prints the document after the first `position`
+//! entries, starting from the snapshot when it's early enough. `verify`
+//! checks that every frame is intact, that no op is logged twice, that
+//! each actor's counters only go up, and that the snapshot matches the
+//! log, and exits with 1 if anything doesn't.
+
+use server::persist::{self, Change, StoredLog};
+
+use crdts_sandbox_lib::document::{
+    entry::hex_bytes, DocActor, DocKey, Document, DocumentOp, RecordEntry,
+    RecordKey,
+};
+use crdts_sandbox_lib::oplog::OpId;
+
+use crdts::{map::Op, orswot};
+
+use serde_json::json;
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    io::{self, Write},
+    path::Path,
+};
+
+type InspectError = Box<dyn Error>;
+
+const USAGE: &str = "usage:
+    inspect list <log> [--actor <actor>] [--key <key>]
+    inspect export <log> [--actor <actor>] [--key <key>]
+    inspect show <log> <position>
+    inspect verify <log>";
+
+/// One line of a listing: an op, or a merge, which has no actor.
+struct Row<K: DocKey> {
+    kind: &'static str,
+    actor: Option<DocActor>,
+    counter: Option<u64>,
+    keys: Vec<K>,
+    entries: Vec<RecordEntry>,
+}
+
+fn row<K: DocKey>(change: &Change<K>) -> Row<K> {
+    match change {
+        Change::Op(Op::Up { dot, key, op }) => {
+            let (kind, entries) = match op {
+                orswot::Op::Add { members, .. } => ("add", members.clone()),
+                orswot::Op::Rm { members, .. } => ("remove", members.clone()),
+            };
+            Row {
+                kind,
+                actor: Some(dot.actor),
+                counter: Some(dot.counter),
+                keys: vec![key.clone()],
+                entries,
+            }
+        }
+        Change::Op(Op::Rm { keyset, .. }) => Row {
+            kind: "remove records",
+            actor: None,
+            counter: None,
+            keys: keyset.iter().cloned().collect(),
+            entries: Vec::new(),
+        },
+        Change::Merge(doc) => Row {
+            kind: "merge",
+            actor: None,
+            counter: None,
+            keys: doc.doc_keys().map(|key| key.val.clone()).collect(),
+            entries: Vec::new(),
+        },
+        Change::MergeRecords(set) => Row {
+            kind: "merge records",
+            actor: None,
+            counter: None,
+            keys: set.keys.clone(),
+            entries: Vec::new(),
+        },
+    }
+}
+
+struct Filter<K: DocKey> {
+    actor: Option<DocActor>,
+    key: Option<K>,
+}
+
+impl<K: DocKey> Filter<K> {
+    fn parse(args: &[String]) -> Result<Self, InspectError> {
+        let mut filter = Filter {
+            actor: None,
+            key: None,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or(USAGE)?;
+            match arg.as_str() {
+                "--actor" => filter.actor = Some(value.parse()?),
+                "--key" => {
+                    let key = K::parse_key(value);
+                    filter.key = Some(key.ok_or("invalid key")?);
+                }
+                _ => return Err(USAGE.into()),
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, row: &Row<K>) -> bool {
+        self.actor.is_none_or(|actor| row.actor == Some(actor))
+            && self.key.as_ref().is_none_or(|key| row.keys.contains(key))
+    }
+}
+
+fn joined<T: ToString>(values: &[T]) -> String {
+    let strings: Vec<_> = values.iter().map(T::to_string).collect();
+    strings.join(", ")
+}
+
+fn list<K: DocKey>(
+    log: &StoredLog<K>,
+    filter: &Filter<K>,
+    json: bool,
+) -> Result<(), InspectError> {
+    for (position, entry) in log.entries.iter().enumerate() {
+        let row = row(&entry.change);
+        if !filter.matches(&row) {
+            continue;
+        }
+        if json {
+            let line = json!({
+                "position": position,
+                "received": entry.received,
+                "kind": row.kind,
+                "actor": row.actor,
+                "counter": row.counter,
+                "keys": row.keys.iter().map(K::to_string).collect::<Vec<_>>(),
+                "entries": row.entries,
+            });
+            println!("{}", line);
+            continue;
+        }
+        let dot = match (row.actor, row.counter) {
+            (Some(actor), Some(counter)) => format!("{}.{}", actor, counter),
+            _ => "-".into(),
+        };
+        let time = entry.received.saturating_sub(log.header.created);
+        print!(
+            "{:>6} +{:.3}s {:>8} {:<14} [{}]",
+            position,
+            time as f64 / 1e6,
+            dot,
+            row.kind,
+            joined(&row.keys)
+        );
+        if !row.entries.is_empty() {
+            print!(" {}", joined(&row.entries));
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// The document after the first `position` entries of the log, replayed
+/// from the snapshot if it isn't past `position`, and from the base
+/// document otherwise.
+fn document_at<K: DocKey>(
+    path: &Path,
+    log: &StoredLog<K>,
+    position: usize,
+) -> Result<Document<K>, InspectError> {
+    if position > log.entries.len() {
+        let len = log.entries.len();
+        return Err(format!("the log only has {} entries", len).into());
+    }
+    let (mut doc, from) = match persist::read_snapshot::<K>(path)? {
+        Some(snapshot) if snapshot.position as usize <= position => {
+            (snapshot.document, snapshot.position as usize)
+        }
+        _ => (log.base.clone(), 0),
+    };
+    for entry in &log.entries[from..position] {
+        entry.change.clone().apply_to(&mut doc);
+    }
+    Ok(doc)
+}
+
+fn show<K: DocKey>(
+    out: &mut impl Write,
+    path: &Path,
+    log: &StoredLog<K>,
+    position: usize,
+) -> Result<(), InspectError> {
+    let doc = document_at(path, log, position)?;
+    writeln!(out, "clock: {:?}", doc.get_read_ctx().add_clock)?;
+    writeln!(out, "content hash: {}", hex_bytes(&doc.content_hash()))?;
+    for records in doc.record_chunks(100) {
+        for paged in records {
+            let mut entries: Vec<_> =
+                paged.record.read().val.into_iter().collect();
+            entries.sort_by_key(RecordEntry::display);
+            writeln!(out, "{}: [{}]", paged.key, joined(&entries))?;
+        }
+    }
+    Ok(())
+}
+
+// every problem found in the log, in the order they appear
+fn problems<K: DocKey>(path: &Path, log: &StoredLog<K>) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut seen: HashSet<OpId<K>> = HashSet::new();
+    let mut counters: HashMap<DocActor, u64> = HashMap::new();
+    for (position, entry) in log.entries.iter().enumerate() {
+        let op: &DocumentOp<K> = match &entry.change {
+            Change::Op(op) => op,
+            _ => continue,
+        };
+        if !seen.insert(OpId::of(op)) {
+            problems.push(format!("{}: op logged twice", position));
+        }
+        if let Op::Up { dot, .. } = op {
+            let last = counters.entry(dot.actor).or_default();
+            if dot.counter <= *last {
+                problems.push(format!(
+                    "{}: counter {} of actor {} follows {}",
+                    position, dot.counter, dot.actor, last
+                ));
+            }
+            *last = dot.counter.max(*last);
+        }
+    }
+    if let Some(damage) = &log.damage {
+        problems.push(format!(
+            "{}: unreadable from byte {}: {}",
+            damage.position, damage.offset, damage.reason
+        ));
+    }
+
+    match persist::read_snapshot::<K>(path) {
+        Ok(Some(snapshot)) => {
+            let position = snapshot.position as usize;
+            if position > log.entries.len() {
+                problems.push(format!(
+                    "the snapshot is at {}, past the end of the log",
+                    position
+                ));
+            } else {
+                let mut replayed = log.base.clone();
+                for entry in &log.entries[..position] {
+                    entry.change.clone().apply_to(&mut replayed);
+                }
+                if replayed.state_hash() != snapshot.document.state_hash() {
+                    problems.push(format!(
+                        "the snapshot differs from the log replayed to {}",
+                        position
+                    ));
+                }
+            }
+        }
+        Ok(None) => (),
+        Err(err) => problems.push(format!("unreadable snapshot: {}", err)),
+    }
+    problems
+}
+
+fn verify<K: DocKey>(
+    path: &Path,
+    log: &StoredLog<K>,
+) -> Result<bool, InspectError> {
+    let problems = problems(path, log);
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    println!("{} entries, {} problems", log.entries.len(), problems.len());
+    Ok(problems.is_empty())
+}
+
+/// Runs the command in `args`, returning whether the log passed if it was
+/// verified.
+fn inspect<K: DocKey>(args: &[String]) -> Result<bool, InspectError> {
+    let path = Path::new(&args[1]);
+    let log = persist::read_log::<K>(path)?;
+    match (args[0].as_str(), &args[2..]) {
+        ("list", rest) => list(&log, &Filter::parse(rest)?, false)?,
+        ("export", rest) => list(&log, &Filter::parse(rest)?, true)?,
+        ("show", [position]) => {
+            let out = &mut io::stdout().lock();
+            show(out, path, &log, position.parse()?)?
+        }
+        ("verify", []) => return verify(path, &log),
+        _ => return Err(USAGE.into()),
+    }
+    Ok(true)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+
+    let result = match persist::read_header(Path::new(&args[1])) {
+        Ok(header) if header.key_type == RecordKey::KEY_TYPE => {
+            inspect::<RecordKey>(&args)
+        }
+        Ok(header) if header.key_type == String::KEY_TYPE => {
+            inspect::<String>(&args)
+        }
+        Ok(header) => {
+            Err(format!("unknown key type {}", header.key_type).into())
+        }
+        Err(err) => Err(err.into()),
+    };
+    match result {
+        Ok(true) => (),
+        Ok(false) => std::process::exit(1),
+        Err(err) => {
+            eprintln!("couldn't inspect {}: {}", args[1], err);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crdts_sandbox_lib::document::Item;
+    use std::{fs, path::PathBuf};
+
+    // a log of adds of `count` records, each with its key as its entry,
+    // in a fresh directory, with a snapshot after the first two, and the
+    // document after each of them
+    fn write_log(name: &str, count: RecordKey) -> (PathBuf, Vec<Document>) {
+        let dir = std::env::temp_dir().join(format!(
+            "inspect-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut doc = Document::default();
+        let mut recovered =
+            persist::open(&dir, "doc", 0, 1, 0, doc.clone()).unwrap();
+        let mut docs = vec![doc.clone()];
+        for key in 0..count {
+            let ctx = doc.get_read_ctx().derive_add_ctx(1);
+            let entry = RecordEntry::text(&key.to_string());
+            let op = doc.add_item(key, ctx, Item::Single(entry));
+            doc.apply(op.clone());
+            recovered.writer.append(key, &Change::Op(op)).unwrap();
+            if key == 1 {
+                recovered.writer.snapshot(&doc).unwrap();
+            }
+            docs.push(doc.clone());
+        }
+        recovered.writer.sync().unwrap();
+        (persist::log_path(&dir, "doc", 0), docs)
+    }
+
+    #[test]
+    fn show_prints_the_document_at_a_position() {
+        let (path, docs) = write_log("show", 4);
+        let log = persist::read_log::<RecordKey>(&path).unwrap();
+        let mut out = Vec::new();
+        show(&mut out, &path, &log, 3).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let hash = hex_bytes(&docs[3].content_hash());
+        assert!(out.contains(&format!("content hash: {}", hash)));
+        assert!(out.contains("0: [0]") && out.contains("2: [2]"));
+        assert!(!out.contains("3: [3]"));
+
+        let mut out = Vec::new();
+        show(&mut out, &path, &log, 1).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let hash = hex_bytes(&docs[1].content_hash());
+        assert!(out.contains(&format!("content hash: {}", hash)));
+        assert!(!out.contains("1: [1]"));
+        assert!(show(&mut Vec::new(), &path, &log, 5).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn verify_reports_a_corrupted_checksum() {
+        let (path, _) = write_log("verify", 4);
+        let log = persist::read_log::<RecordKey>(&path).unwrap();
+        assert!(verify(&path, &log).unwrap());
+
+        // flip a payload byte of the last entry
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        let log = persist::read_log::<RecordKey>(&path).unwrap();
+        assert_eq!(log.entries.len(), 3);
+        let problems = problems(&path, &log);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("3: unreadable from byte"));
+        assert!(problems[0].ends_with("checksum mismatch"));
+        assert!(!verify(&path, &log).unwrap());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
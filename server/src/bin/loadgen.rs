@@ -0,0 +1,438 @@
+//! Simulates many clients editing a document on a running server, and
+//! reports how quickly the server answered them.
+//!
+//! Each client opens its own websocket connection, issues a random mix of
+//! commands at its share of the target rate and waits for each reply
+//! before sending the next. Once every client is done, they all catch up
+//! with `Resume` and their documents are compared with each other and
+//! with the server's.
+//!
+//! Run with `cargo run --release -p server --bin loadgen`, configured with:
+//!
+//! - `URL`: the document to load, `ws://127.0.0.1:3030/numeric` by default
+//! - `CLIENTS`: how many connections to open, 16 by default
+//! - `RATE`: requests per second across all clients, 500 by default
+//! - `SECS`: how long to run, 10 by default
+//! - `RECORDS`: how many record keys the requests spread over, 100 by
+//!   default
+//! - `MIX`: the weight of each command, by default
+//!   `get_document:1,get_record:4,add:3,apply:2`
+//! - `FIRST_ACTOR`: the actor of the first client, 100 by default; each
+//!   client uses two actors, one for `Apply` and one for `Add`
+//! - `SEED`: what the clients' random choices are derived from
+
+use crdts_sandbox_lib::document::{
+    entry::hex_bytes, Command, DocActor, DocReplica, DocResponse, Hash, Item,
+    RecordEntry, RecordKey,
+};
+use crdts_sandbox_lib::sim::SimRng;
+
+use crdts::{ctx::AddCtx, CmRDT, Dot};
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+
+use tokio::sync::Barrier;
+
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+type LoadError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    GetDocument,
+    GetRecord,
+    Add,
+    Apply,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::GetDocument => "get_document",
+            Kind::GetRecord => "get_record",
+            Kind::Add => "add",
+            Kind::Apply => "apply",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [Kind::GetDocument, Kind::GetRecord, Kind::Add, Kind::Apply]
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LoadConfig {
+    url: String,
+    clients: u32,
+    rate: f64,
+    duration: Duration,
+    records: u64,
+    mix: Vec<(Kind, u64)>,
+    first_actor: DocActor,
+    seed: u64,
+}
+
+fn env_var<T: FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|var| var.parse().ok())
+}
+
+// e.g. `get_record:4,add:1`; unknown commands are skipped
+fn parse_mix(mix: &str) -> Vec<(Kind, u64)> {
+    mix.split(',')
+        .filter_map(|part| {
+            let mut parts = part.trim().splitn(2, ':');
+            let kind = Kind::parse(parts.next()?)?;
+            let weight = parts.next().map_or(Some(1), |w| w.parse().ok())?;
+            Some((kind, weight))
+        })
+        .filter(|(_, weight)| *weight > 0)
+        .collect()
+}
+
+impl LoadConfig {
+    fn from_env() -> Self {
+        let mix = std::env::var("MIX").unwrap_or_else(|_| {
+            "get_document:1,get_record:4,add:3,apply:2".into()
+        });
+        LoadConfig {
+            url: env_var("URL")
+                .unwrap_or_else(|| "ws://127.0.0.1:3030/numeric".into()),
+            clients: env_var("CLIENTS").unwrap_or(16).max(1),
+            rate: env_var("RATE").unwrap_or(500.0),
+            duration: Duration::from_secs(env_var("SECS").unwrap_or(10)),
+            records: env_var("RECORDS").unwrap_or(100).max(1),
+            mix: parse_mix(&mix),
+            first_actor: env_var("FIRST_ACTOR").unwrap_or(100),
+            seed: env_var("SEED").unwrap_or(0),
+        }
+    }
+}
+
+/// What one client saw: the latency of every request it got the
+/// expected reply to, the ones it didn't, and its document's content
+/// hash once it had caught up.
+#[derive(Debug, Default)]
+struct ClientReport {
+    latencies: BTreeMap<Kind, Vec<Duration>>,
+    errors: u64,
+    disconnected: Option<String>,
+    content: Option<Hash>,
+}
+
+/// A simulated client. `Apply`s are made by its replica; `Add`s are made
+/// by the server from a dot the client hands out, as another actor, so
+/// the two never claim the same dot.
+struct Client {
+    replica: DocReplica<RecordKey>,
+    add_dot: Dot<DocActor>,
+    rng: SimRng,
+}
+
+impl Client {
+    fn pick(&mut self, mix: &[(Kind, u64)]) -> Kind {
+        let total = mix.iter().map(|(_, weight)| weight).sum();
+        let mut n = self.rng.below(total);
+        for (kind, weight) in mix {
+            if n < *weight {
+                return *kind;
+            }
+            n -= weight;
+        }
+        Kind::GetRecord
+    }
+
+    fn command(&mut self, kind: Kind, records: u64) -> Command<RecordKey> {
+        let key = self.rng.below(records);
+        let clock = self.replica.document().get_read_ctx().add_clock;
+        match kind {
+            Kind::GetDocument => Command::GetDocument { clock: Some(clock) },
+            Kind::GetRecord => Command::GetRecord { key },
+            Kind::Add => {
+                self.add_dot.counter += 1;
+                let dot = self.add_dot;
+                let mut clock = clock;
+                clock.apply(dot);
+                Command::Add {
+                    add_ctx: AddCtx { clock, dot },
+                    key,
+                    item: Item::Single(self.entry(dot)),
+                }
+            }
+            Kind::Apply => {
+                let actor = self.replica.actor();
+                let dot = Dot::new(actor, clock.get(&actor) + 1);
+                let item = Item::Single(self.entry(dot));
+                self.replica.add_item(key, item);
+                let op = self.replica.take_queued().pop().unwrap();
+                Command::Apply { op }
+            }
+        }
+    }
+
+    fn entry(&self, dot: Dot<DocActor>) -> RecordEntry {
+        RecordEntry::text(&format!("load {}.{}", dot.actor, dot.counter))
+    }
+
+    // whether `resp` is the reply `kind` expects
+    fn handle(&mut self, kind: Kind, resp: DocResponse<RecordKey>) -> bool {
+        match (kind, resp) {
+            (Kind::GetDocument, DocResponse::Document(doc)) => {
+                self.replica.merge_document(doc);
+                true
+            }
+            (Kind::GetDocument, DocResponse::NotModified) => true,
+            (Kind::GetRecord, DocResponse::Record(_)) => true,
+            (Kind::Add, DocResponse::Ack { .. }) => true,
+            (Kind::Apply, DocResponse::Ack { id, .. }) => {
+                self.replica.ack(&id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn catch_up(&mut self, resp: DocResponse<RecordKey>) -> Option<Hash> {
+        match resp {
+            DocResponse::Missed { ops } => {
+                for op in ops {
+                    self.replica.apply_op(op);
+                }
+            }
+            DocResponse::Document(doc) => self.replica.merge_document(doc),
+            _ => return None,
+        }
+        if !self.replica.pending().is_empty() {
+            return None;
+        }
+        Some(self.replica.document().content_hash())
+    }
+}
+
+async fn send_command(
+    ws: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    cmd: &Command<RecordKey>,
+) -> Result<(), LoadError> {
+    let bytes = cmd.to_bytes().ok_or("couldn't encode command")?;
+    ws.send(Message::binary(bytes)).await?;
+    Ok(())
+}
+
+/// Sends `cmd` and waits for the server's reply to it.
+async fn request(
+    ws: &mut (impl Sink<Message, Error = tungstenite::Error>
+              + Stream<Item = Result<Message, tungstenite::Error>>
+              + Unpin),
+    cmd: &Command<RecordKey>,
+) -> Result<DocResponse<RecordKey>, LoadError> {
+    send_command(ws, cmd).await?;
+    let reply = async {
+        while let Some(msg) = ws.next().await {
+            if let Message::Binary(bytes) = msg? {
+                return DocResponse::from_bytes(&bytes)
+                    .ok_or_else(|| "couldn't decode response".into());
+            }
+        }
+        Err("connection closed".into())
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, reply)
+        .await
+        .map_err(|_| "timed out")?
+}
+
+async fn run_client(
+    config: Arc<LoadConfig>,
+    index: u32,
+    done: Arc<Barrier>,
+) -> ClientReport {
+    let mut report = ClientReport::default();
+    let actor = config.first_actor + index;
+    let mut client = Client {
+        replica: DocReplica::new(actor),
+        add_dot: Dot::new(actor + config.clients, 0),
+        rng: SimRng::new(config.seed.wrapping_add(index as u64)),
+    };
+
+    let mut ws =
+        match tokio_tungstenite::connect_async(config.url.as_str()).await {
+            Ok((ws, _)) => Some(ws),
+            Err(err) => {
+                report.disconnected = Some(err.to_string());
+                None
+            }
+        };
+
+    if let Some(conn) = ws.as_mut() {
+        let period = config.clients as f64 / config.rate.max(0.001);
+        let mut ticks = tokio::time::interval(Duration::from_secs_f64(period));
+        let end = Instant::now() + config.duration;
+        while Instant::now() < end {
+            ticks.tick().await;
+            let kind = client.pick(&config.mix);
+            let cmd = client.command(kind, config.records);
+            let start = Instant::now();
+            match request(conn, &cmd).await {
+                Ok(resp) => {
+                    let latency = start.elapsed();
+                    if client.handle(kind, resp) {
+                        report.latencies.entry(kind).or_default().push(latency);
+                    } else {
+                        report.errors += 1;
+                    }
+                }
+                // replies would no longer match requests
+                Err(err) => {
+                    report.errors += 1;
+                    report.disconnected = Some(err.to_string());
+                    break;
+                }
+            }
+        }
+    }
+
+    // every client's writes have been acknowledged past this point
+    done.wait().await;
+    if report.disconnected.is_none() {
+        if let Some(conn) = ws.as_mut() {
+            let clock = client.replica.document().get_read_ctx().add_clock;
+            if let Ok(resp) = request(conn, &Command::Resume { clock }).await {
+                report.content = client.catch_up(resp);
+            }
+        }
+    }
+    report
+}
+
+async fn server_content(url: &str) -> Result<Hash, LoadError> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(url).await?;
+    match request(&mut ws, &Command::GetHashes).await? {
+        DocResponse::Hashes { content, .. } => Ok(content),
+        _ => Err("unexpected reply to GetHashes".into()),
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let i = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[i]
+}
+
+fn print_latencies(kind: &str, latencies: &mut [Duration]) {
+    latencies.sort_unstable();
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    println!(
+        "{:<14}{:>9}{:>9.2}{:>9.2}{:>9.2}{:>9.2}",
+        kind,
+        latencies.len(),
+        ms(percentile(latencies, 0.5)),
+        ms(percentile(latencies, 0.9)),
+        ms(percentile(latencies, 0.99)),
+        ms(latencies.last().copied().unwrap_or_default()),
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let config = Arc::new(LoadConfig::from_env());
+    if config.mix.is_empty() {
+        eprintln!("MIX has no known commands");
+        std::process::exit(2);
+    }
+    println!(
+        "{} clients, {} req/s, {}s against {}",
+        config.clients,
+        config.rate,
+        config.duration.as_secs(),
+        config.url
+    );
+
+    let started = Instant::now();
+    let done = Arc::new(Barrier::new(config.clients as usize));
+    let clients: Vec<_> = (0..config.clients)
+        .map(|index| {
+            tokio::spawn(run_client(config.clone(), index, done.clone()))
+        })
+        .collect();
+    let mut reports = Vec::new();
+    for client in clients {
+        reports.push(client.await.unwrap_or_default());
+    }
+    let elapsed = config.duration.min(started.elapsed());
+
+    println!(
+        "{:<14}{:>9}{:>9}{:>9}{:>9}{:>9}",
+        "command", "count", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+    let mut all = Vec::new();
+    let mut by_kind: BTreeMap<Kind, Vec<Duration>> = BTreeMap::new();
+    for report in reports.iter() {
+        for (kind, latencies) in report.latencies.iter() {
+            by_kind.entry(*kind).or_default().extend(latencies);
+            all.extend(latencies);
+        }
+    }
+    for (kind, latencies) in by_kind.iter_mut() {
+        print_latencies(kind.name(), latencies);
+    }
+    print_latencies("all", &mut all);
+
+    let errors: u64 = reports.iter().map(|report| report.errors).sum();
+    println!(
+        "{} requests, {:.0} req/s, {} errors",
+        all.len(),
+        all.len() as f64 / elapsed.as_secs_f64(),
+        errors
+    );
+    for (index, report) in reports.iter().enumerate() {
+        if let Some(err) = &report.disconnected {
+            println!("client {} disconnected: {}", index, err);
+        }
+    }
+
+    let server = server_content(&config.url).await;
+    let mut mismatched = 0;
+    for (index, report) in reports.iter().enumerate() {
+        match (&report.content, &server) {
+            (Some(content), Ok(server)) if content == server => (),
+            (Some(content), _) => {
+                mismatched += 1;
+                println!("client {} has content {}", index, hex_bytes(content));
+            }
+            (None, _) => mismatched += 1,
+        }
+    }
+    match server {
+        Ok(server) if mismatched == 0 => println!(
+            "all {} documents match the server's, {}",
+            reports.len(),
+            hex_bytes(&server)
+        ),
+        Ok(server) => {
+            println!(
+                "{} of {} documents don't match the server's, {}",
+                mismatched,
+                reports.len(),
+                hex_bytes(&server)
+            );
+            std::process::exit(1);
+        }
+        Err(err) => {
+            println!("couldn't get the server's document: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Replays recorded sessions, see `server::record`, against a fresh
+//! server in this process.
+//!
+//! The server starts from the document the earliest of the sessions was
+//! served from, and the commands of all of them are fed to it one at a
+//! time, in the order they were received. Without `--diff`, every command
+//! is printed with the responses to it; with it, only the responses that
+//! differ from the recorded ones are, and the exit status says whether
+//! there were any.
+//!
+//! ```text
+//! RECORD_DIR=sessions cargo run -p server
+//! cargo run -p server --bin replay -- --diff sessions/numeric-000003.session
+//! ```
+
+use server::record::{self, Event, Session};
+use server::service;
+use server::shard::Shards;
+
+use crdts_sandbox_lib::document::{
+    canonical::{encode_orswot, encode_record, write_clock},
+    entry::hex_bytes,
+    Command, DocKey, DocResponse, PagedRecord, RecordKey,
+};
+
+use std::{error::Error, fmt::Debug};
+
+const SUMMARY_CHARS: usize = 160;
+
+type ReplayError = Box<dyn Error>;
+
+/// A recorded command, when it was received, in microseconds since the
+/// Unix epoch, and what the server sent in response.
+struct Received<K: DocKey> {
+    at: u64,
+    connection: u64,
+    cmd: Command<K>,
+    recorded: Vec<DocResponse<K>>,
+}
+
+fn summary(value: &impl Debug) -> String {
+    let debug = format!("{:?}", value);
+    if debug.chars().count() <= SUMMARY_CHARS {
+        debug
+    } else {
+        let short: String = debug.chars().take(SUMMARY_CHARS).collect();
+        format!("{}...", short)
+    }
+}
+
+fn write_records<K: DocKey>(records: &[PagedRecord<K>], out: &mut Vec<u8>) {
+    for paged in records {
+        out.extend(encode_record(&paged.key, &paged.clock, &paged.record));
+    }
+}
+
+/// The response in a form that is equal whenever the responses are: the
+/// canonical encoding where it carries records, whose bincode depends on
+/// the order of hash maps, and bincode otherwise. Receive times are left
+/// out, since the replaying server stamps ops with times of its own.
+fn fingerprint<K: DocKey>(resp: &DocResponse<K>) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    match resp {
+        DocResponse::Document(doc) => {
+            out.extend(b"document");
+            out.extend(doc.canonical_state());
+        }
+        DocResponse::Record(ctx) => {
+            out.extend(b"record");
+            write_clock(&ctx.add_clock, &mut out);
+            write_clock(&ctx.rm_clock, &mut out);
+            if let Some(record) = &ctx.val {
+                out.extend(encode_orswot(record));
+            }
+        }
+        DocResponse::Records { records, next } => {
+            out.extend(b"records");
+            write_records(records, &mut out);
+            out.extend(bincode::serialize(next).ok()?);
+        }
+        DocResponse::RecordChunk {
+            records,
+            sent,
+            total,
+        } => {
+            out.extend(b"chunk");
+            write_records(records, &mut out);
+            out.extend(bincode::serialize(&(sent, total)).ok()?);
+        }
+        DocResponse::RecordSet(set) => {
+            out.extend(b"set");
+            write_records(&set.records, &mut out);
+            let rest = (&set.keys, &set.clock, &set.root);
+            out.extend(bincode::serialize(&rest).ok()?);
+        }
+        DocResponse::Blame { key, entries } => {
+            out.extend(b"blame");
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|blame| {
+                    let dots: Vec<_> =
+                        blame.dots.iter().map(|blamed| blamed.dot).collect();
+                    (&blame.entry, dots)
+                })
+                .collect();
+            out.extend(bincode::serialize(&(key, entries)).ok()?);
+        }
+        DocResponse::History {
+            key,
+            entries,
+            earlier,
+        } => {
+            out.extend(b"history");
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|entry| (&entry.op, entry.actor, &entry.summary))
+                .collect();
+            out.extend(bincode::serialize(&(key, entries, earlier)).ok()?);
+        }
+        resp => out.extend(bincode::serialize(resp).ok()?),
+    }
+    Some(out)
+}
+
+// The commands of every session, each with the responses recorded after
+// it and before the session's next command.
+fn received<K: DocKey>(sessions: Vec<Session<K>>) -> Vec<Received<K>> {
+    let mut received: Vec<Received<K>> = Vec::new();
+    for session in sessions {
+        let first = received.len();
+        for recorded in session.events {
+            let at = session.info.started + recorded.micros;
+            match recorded.event {
+                Event::Command(cmd) => received.push(Received {
+                    at,
+                    connection: session.info.connection,
+                    cmd,
+                    recorded: Vec::new(),
+                }),
+                Event::Response(resp) if received.len() > first => {
+                    received.last_mut().unwrap().recorded.push(resp);
+                }
+                Event::Response(_) => (),
+            }
+        }
+    }
+    received.sort_by_key(|received| received.at);
+    received
+}
+
+/// Replays the sessions in `paths`, returning how many responses differ
+/// from the recorded ones.
+async fn replay<K: DocKey>(
+    paths: &[String],
+    diff: bool,
+) -> Result<usize, ReplayError> {
+    let mut sessions = Vec::new();
+    for path in paths {
+        sessions.push(record::read_session::<K>(path)?);
+    }
+    sessions.sort_by_key(|session| session.info.started);
+    let info = sessions[0].info.clone();
+    if sessions
+        .iter()
+        .any(|session| session.info.path != info.path)
+    {
+        return Err("the sessions were recorded on different documents".into());
+    }
+    let shards =
+        Shards::spawn(info.actor, sessions[0].document.clone(), info.shards)?;
+
+    let mut differing = 0;
+    for received in received(sessions) {
+        let resps = service::respond(&shards, received.cmd.clone()).await;
+        let secs = received.at.saturating_sub(info.started) as f64 / 1e6;
+        if !diff {
+            println!(
+                "[{} +{:.3}s] {}",
+                received.connection,
+                secs,
+                summary(&received.cmd)
+            );
+            for resp in resps.iter() {
+                println!("    {}", summary(resp));
+            }
+            continue;
+        }
+        for i in 0..resps.len().max(received.recorded.len()) {
+            let recorded = received.recorded.get(i);
+            let replayed = resps.get(i);
+            if recorded.and_then(fingerprint) == replayed.and_then(fingerprint)
+            {
+                continue;
+            }
+            differing += 1;
+            println!(
+                "[{} +{:.3}s] response {} to {}",
+                received.connection,
+                secs,
+                i,
+                summary(&received.cmd)
+            );
+            println!("    recorded: {}", summary(&recorded));
+            println!("    replayed: {}", summary(&replayed));
+        }
+    }
+
+    let doc = shards
+        .document()
+        .ok_or("the replayed shards couldn't be joined")?;
+    println!(
+        "replayed document: content {}, clock {:?}",
+        hex_bytes(&doc.content_hash()),
+        doc.get_read_ctx().add_clock
+    );
+    Ok(differing)
+}
+
+#[tokio::main]
+async fn main() {
+    let mut diff = false;
+    let mut paths = Vec::new();
+    for arg in std::env::args().skip(1) {
+        if arg == "--diff" {
+            diff = true;
+        } else {
+            paths.push(arg);
+        }
+    }
+    if paths.is_empty() {
+        eprintln!("usage: replay [--diff] <session file>...");
+        std::process::exit(2);
+    }
+
+    let result = match record::read_info(&paths[0]) {
+        Ok(info) if info.key_type == RecordKey::KEY_TYPE => {
+            replay::<RecordKey>(&paths, diff).await
+        }
+        Ok(info) if info.key_type == String::KEY_TYPE => {
+            replay::<String>(&paths, diff).await
+        }
+        Ok(info) => Err(format!("unknown key type {}", info.key_type).into()),
+        Err(err) => Err(err.into()),
+    };
+    match result {
+        Ok(0) => (),
+        Ok(differing) => {
+            println!("{} responses differ from the recording", differing);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("couldn't replay: {}", err);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crdts_sandbox_lib::document::{
+        Document, Item, RecordEntry, SERVER_ACTOR,
+    };
+    use server::frame::write_value;
+    use server::record::{Recorded, SessionInfo};
+    use std::{fs::File, time::Duration};
+
+    #[tokio::test]
+    async fn blame_and_history_replay_without_differences() {
+        let doc = Document::<RecordKey>::example(1);
+        let shards = Shards::spawn(SERVER_ACTOR, doc.clone(), 2).unwrap();
+        let cmds = vec![
+            Command::Add {
+                add_ctx: shards.read_ctx().derive_add_ctx(2),
+                key: 1,
+                item: Item::Single(RecordEntry::text("added")),
+            },
+            Command::Blame { key: 1 },
+            Command::GetHistory {
+                key: 1,
+                limit: 10,
+                before: None,
+            },
+        ];
+        let mut events = Vec::new();
+        for cmd in cmds {
+            events.push(Event::Command(cmd.clone()));
+            for resp in service::respond(&shards, cmd).await {
+                events.push(Event::Response(resp));
+            }
+        }
+        let stamped = events.iter().any(|event| match event {
+            Event::Response(DocResponse::Blame { entries, .. }) => entries
+                .iter()
+                .any(|blame| blame.dots.iter().any(|b| b.received.is_some())),
+            _ => false,
+        });
+        assert!(stamped);
+
+        let dir = std::env::temp_dir()
+            .join(format!("replay-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("numeric-000001.session");
+        let mut out = File::create(&path).unwrap();
+        let info = SessionInfo {
+            path: "numeric".into(),
+            key_type: RecordKey::KEY_TYPE.into(),
+            connection: 1,
+            actor: SERVER_ACTOR,
+            shards: 2,
+            started: 0,
+        };
+        write_value(&mut out, &info).unwrap();
+        write_value(&mut out, &doc).unwrap();
+        for (micros, event) in events.into_iter().enumerate() {
+            let recorded = Recorded {
+                micros: micros as u64,
+                event,
+            };
+            write_value(&mut out, &recorded).unwrap();
+        }
+        drop(out);
+
+        // so the replayed ops are stamped with later receive times
+        tokio::time::delay_for(Duration::from_millis(2)).await;
+        let paths = vec![path.to_string_lossy().into_owned()];
+        let differing = replay::<RecordKey>(&paths, true).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(differing, 0);
+    }
+}
@@ -0,0 +1,264 @@
+//! Faults the server can inject into what it sends clients, to exercise
+//! their reconnect and causal buffering logic. Peers, which connect to
+//! `peer::PEER_PATH`, are sent their replies as they are. Each fault has
+//! its own probability, checked for every outgoing response; the
+//! generator of each connection is seeded from the configured seed and
+//! the order the connection was accepted in, so a session misbehaves the
+//! same way when it is repeated.
+
+use crate::config::env_var;
+
+use crdts_sandbox_lib::document::{DocKey, DocResponse};
+use crdts_sandbox_lib::sim::SimRng;
+
+use std::time::Duration;
+
+/// Read from the environment; chaos mode is on when `CHAOS_SEED` is set:
+///
+/// - `CHAOS_SEED`: the seed the connections' generators are derived from
+/// - `CHAOS_LATENCY`: the chance a response is held back, for up to
+///   `CHAOS_MAX_LATENCY_MS` milliseconds, 500 by default
+/// - `CHAOS_DROP`: the chance a response is never sent
+/// - `CHAOS_DUPLICATE`: the chance a response is sent twice
+/// - `CHAOS_REORDER`: the chance a response is held back and sent after
+///   the next one, or on its own once the connection has been idle for
+///   `CHAOS_MAX_LATENCY_MS`; also the chance the ops sent in a `Missed`
+///   are shuffled
+/// - `CHAOS_DISCONNECT`: the chance the connection is closed instead of
+///   a response being sent
+///
+/// Probabilities are between 0 and 1 and are 0 unless set.
+#[derive(Debug, Clone, Default)]
+pub struct ChaosConfig {
+    pub seed: u64,
+    pub latency: f64,
+    pub max_latency: Duration,
+    pub drop: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    pub disconnect: f64,
+}
+
+impl ChaosConfig {
+    pub fn from_env() -> Option<Self> {
+        let chance = |name| env_var(name).unwrap_or(0.0);
+        Some(ChaosConfig {
+            seed: env_var("CHAOS_SEED")?,
+            latency: chance("CHAOS_LATENCY"),
+            max_latency: Duration::from_millis(
+                env_var("CHAOS_MAX_LATENCY_MS").unwrap_or(500),
+            ),
+            drop: chance("CHAOS_DROP"),
+            duplicate: chance("CHAOS_DUPLICATE"),
+            reorder: chance("CHAOS_REORDER"),
+            disconnect: chance("CHAOS_DISCONNECT"),
+        })
+    }
+}
+
+/// What to do with one outgoing response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Send {
+        delay: Option<Duration>,
+        copies: u32,
+    },
+    Disconnect,
+}
+
+/// The faults of one connection.
+#[derive(Debug, Clone)]
+pub struct Chaos {
+    config: ChaosConfig,
+    rng: SimRng,
+}
+
+impl Chaos {
+    /// The faults of the `connection`th connection the server accepted.
+    pub fn for_connection(config: &ChaosConfig, connection: u64) -> Self {
+        Chaos {
+            config: config.clone(),
+            rng: SimRng::new(config.seed.wrapping_add(connection)),
+        }
+    }
+
+    /// Whether to hold a response back until the next one is sent.
+    pub fn hold(&mut self) -> bool {
+        self.rng.chance(self.config.reorder)
+    }
+
+    /// How long a held back response waits for another to overtake it.
+    pub fn max_hold(&self) -> Duration {
+        self.config.max_latency
+    }
+
+    /// Decides the fate of a response, shuffling the ops it carries if
+    /// it's to be reordered.
+    pub fn fault<K: DocKey>(&mut self, resp: &mut DocResponse<K>) -> Fault {
+        if self.rng.chance(self.config.disconnect) {
+            return Fault::Disconnect;
+        }
+        if let DocResponse::Missed { ops } = resp {
+            if self.rng.chance(self.config.reorder) {
+                for i in (1..ops.len()).rev() {
+                    let j = self.rng.below(i as u64 + 1) as usize;
+                    ops.swap(i, j);
+                }
+            }
+        }
+        let delay = if self.rng.chance(self.config.latency) {
+            let max = self.config.max_latency.as_millis() as u64;
+            Some(Duration::from_millis(self.rng.below(max + 1)))
+        } else {
+            None
+        };
+        let copies = if self.rng.chance(self.config.drop) {
+            0
+        } else if self.rng.chance(self.config.duplicate) {
+            2
+        } else {
+            1
+        };
+        Fault::Send { delay, copies }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crdts_sandbox_lib::document::{
+        Document, DocumentOp, Item, RecordEntry, RecordKey, SERVER_ACTOR,
+    };
+
+    fn config(seed: u64) -> ChaosConfig {
+        ChaosConfig {
+            seed,
+            max_latency: Duration::from_millis(20),
+            ..ChaosConfig::default()
+        }
+    }
+
+    fn faults(chaos: &mut Chaos, count: usize) -> Vec<Fault> {
+        (0..count)
+            .map(|_| chaos.fault(&mut DocResponse::<RecordKey>::NotModified))
+            .collect()
+    }
+
+    fn missed(count: u64) -> Vec<DocumentOp<RecordKey>> {
+        let mut doc = Document::default();
+        (0..count)
+            .map(|key| {
+                let ctx = doc.get_read_ctx().derive_add_ctx(SERVER_ACTOR);
+                let item = Item::Single(RecordEntry::text("entry"));
+                let op = doc.add_item(key, ctx, item);
+                doc.apply(op.clone());
+                op
+            })
+            .collect()
+    }
+
+    /// The ops in a comparable form, sorted if `sort`.
+    fn listed(ops: &[DocumentOp<RecordKey>], sort: bool) -> Vec<String> {
+        let mut listed: Vec<_> =
+            ops.iter().map(|op| format!("{:?}", op)).collect();
+        if sort {
+            listed.sort();
+        }
+        listed
+    }
+
+    #[test]
+    fn a_seed_and_connection_repeat_their_faults() {
+        let config = ChaosConfig {
+            latency: 0.3,
+            drop: 0.2,
+            duplicate: 0.2,
+            reorder: 0.5,
+            disconnect: 0.1,
+            ..config(7)
+        };
+        let mut first = Chaos::for_connection(&config, 3);
+        let mut again = Chaos::for_connection(&config, 3);
+        let mut other = Chaos::for_connection(&config, 4);
+        let expected = faults(&mut first, 200);
+        assert_eq!(faults(&mut again, 200), expected);
+        assert_ne!(faults(&mut other, 200), expected);
+        let holds = |chaos: &mut Chaos| {
+            (0..200).map(|_| chaos.hold()).collect::<Vec<_>>()
+        };
+        assert_eq!(holds(&mut first), holds(&mut again));
+    }
+
+    #[test]
+    fn certain_and_impossible_faults_always_and_never_fire() {
+        let fault_of = |config: ChaosConfig| {
+            let mut chaos = Chaos::for_connection(&config, 0);
+            let all = faults(&mut chaos, 100);
+            assert!(all.iter().all(|fault| *fault == all[0]));
+            all[0]
+        };
+        let none = Fault::Send {
+            delay: None,
+            copies: 1,
+        };
+        assert_eq!(fault_of(config(1)), none);
+        let disconnect = ChaosConfig {
+            disconnect: 1.0,
+            ..config(1)
+        };
+        assert_eq!(fault_of(disconnect), Fault::Disconnect);
+        let drop = ChaosConfig {
+            drop: 1.0,
+            duplicate: 1.0,
+            ..config(1)
+        };
+        assert!(matches!(fault_of(drop), Fault::Send { copies: 0, .. }));
+        let duplicate = ChaosConfig {
+            duplicate: 1.0,
+            ..config(1)
+        };
+        assert!(matches!(fault_of(duplicate), Fault::Send { copies: 2, .. }));
+
+        let latency = ChaosConfig {
+            latency: 1.0,
+            ..config(1)
+        };
+        let mut chaos = Chaos::for_connection(&latency, 0);
+        for fault in faults(&mut chaos, 100) {
+            match fault {
+                Fault::Send {
+                    delay: Some(delay),
+                    copies: 1,
+                } => assert!(delay <= latency.max_latency),
+                fault => panic!("expected a delay, got {:?}", fault),
+            }
+        }
+
+        let mut never = Chaos::for_connection(&config(1), 0);
+        assert!((0..100).all(|_| !never.hold()));
+        let reorder = ChaosConfig {
+            reorder: 1.0,
+            ..config(1)
+        };
+        let mut always = Chaos::for_connection(&reorder, 0);
+        assert!((0..100).all(|_| always.hold()));
+    }
+
+    #[test]
+    fn shuffled_missed_ops_keep_the_same_ops() {
+        let ops = missed(20);
+        let reorder = ChaosConfig {
+            reorder: 1.0,
+            ..config(5)
+        };
+        let mut chaos = Chaos::for_connection(&reorder, 0);
+        let mut resp = DocResponse::Missed { ops: ops.clone() };
+        chaos.fault(&mut resp);
+        let shuffled = match resp {
+            DocResponse::Missed { ops } => ops,
+            resp => panic!("expected missed ops, got {:?}", resp),
+        };
+        assert_ne!(listed(&shuffled, false), listed(&ops, false));
+        assert_eq!(listed(&shuffled, true), listed(&ops, true));
+    }
+}
@@ -0,0 +1,141 @@
+use crate::chaos::ChaosConfig;
+
+use crdts_sandbox_lib::document::{DocActor, SERVER_ACTOR};
+
+use std::{error::Error, fmt, net::SocketAddr, path::PathBuf, str::FromStr};
+
+pub const DEFAULT_SHARDS: usize = 4;
+
+/// How a server is set up, read from the environment:
+///
+/// - `PORT`: the port to listen on, 3030 by default
+/// - `SERVER_ACTOR`: the actor the server writes as; servers replicating to
+///   each other need distinct ones, which clients mustn't use, so it must
+///   be set to something other than the default whenever `PEERS` is
+/// - `SHARDS`: how many shards each document is split into
+/// - `PEERS`: comma separated addresses of servers to replicate with, e.g.
+///   `127.0.0.1:3031,127.0.0.1:3032`
+/// - `DATA_DIR`: a directory to keep each shard's op log and snapshots
+///   in, see `persist`; without it nothing survives a restart
+/// - `RECORD_DIR`: a directory to record every session into, see
+///   `record`
+/// - `CHAOS_SEED` and the other `CHAOS_` variables: faults to inject into
+///   responses, see `ChaosConfig`
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: u16,
+    pub actor: DocActor,
+    pub shards: usize,
+    pub peers: Vec<SocketAddr>,
+    pub data_dir: Option<PathBuf>,
+    pub record_dir: Option<PathBuf>,
+    pub chaos: Option<ChaosConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 3030,
+            actor: SERVER_ACTOR,
+            shards: DEFAULT_SHARDS,
+            peers: Vec::new(),
+            data_dir: None,
+            record_dir: None,
+            chaos: None,
+        }
+    }
+}
+
+pub(crate) fn env_var<T: FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|var| var.parse().ok())
+}
+
+/// Why the environment doesn't describe a server that can run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// An entry of `PEERS` that isn't a socket address.
+    InvalidPeer(String),
+    /// `SERVER_ACTOR` isn't an actor.
+    InvalidActor(String),
+    /// `PEERS` is set but `SERVER_ACTOR` isn't, so this server would make
+    /// the same dots as its peers.
+    SharedActor,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidPeer(peer) => {
+                write!(f, "PEERS has an invalid address {:?}", peer)
+            }
+            ConfigError::InvalidActor(actor) => {
+                write!(f, "SERVER_ACTOR {:?} isn't an actor", actor)
+            }
+            ConfigError::SharedActor => write!(
+                f,
+                "replicating servers need a SERVER_ACTOR of their own, \
+                 other than {}",
+                SERVER_ACTOR
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+fn parse_peers(peers: &str) -> Result<Vec<SocketAddr>, ConfigError> {
+    peers
+        .split(',')
+        .map(str::trim)
+        .filter(|peer| !peer.is_empty())
+        .map(|peer| {
+            peer.parse()
+                .map_err(|_| ConfigError::InvalidPeer(peer.to_string()))
+        })
+        .collect()
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let default = Config::default();
+        let peers = match std::env::var("PEERS") {
+            Ok(peers) => parse_peers(&peers)?,
+            Err(_) => Vec::new(),
+        };
+        let actor = match std::env::var("SERVER_ACTOR") {
+            Ok(actor) => actor
+                .trim()
+                .parse()
+                .map_err(|_| ConfigError::InvalidActor(actor))?,
+            Err(_) => default.actor,
+        };
+        if !peers.is_empty() && actor == SERVER_ACTOR {
+            return Err(ConfigError::SharedActor);
+        }
+        Ok(Config {
+            port: env_var("PORT").unwrap_or(default.port),
+            actor,
+            shards: env_var("SHARDS").unwrap_or(default.shards),
+            peers,
+            data_dir: env_var("DATA_DIR"),
+            record_dir: env_var("RECORD_DIR"),
+            chaos: ChaosConfig::from_env(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_must_all_parse() {
+        let peers = parse_peers("127.0.0.1:3031, 127.0.0.1:3032,").unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[1].port(), 3032);
+        assert_eq!(
+            parse_peers("127.0.0.1:3031,localhost"),
+            Err(ConfigError::InvalidPeer("localhost".into()))
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! The framing of the files the server writes, op logs and session
+//! recordings alike. A frame is a big endian `u32` length, the first four
+//! bytes of the SHA-1 of the payload, then the payload, which is bincode.
+
+use crdts_sandbox_lib::document::merkle::sha1;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use std::io::{self, Read, Write};
+
+pub fn invalid(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.into())
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = sha1(payload);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+pub fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    bincode::serialize(value).map_err(|err| invalid(err.to_string()))
+}
+
+/// Writes `payload`, already encoded, as a frame.
+pub fn write_frame(out: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    out.write_all(&(payload.len() as u32).to_be_bytes())?;
+    out.write_all(&checksum(payload))?;
+    out.write_all(payload)
+}
+
+pub fn write_value<T: Serialize>(
+    out: &mut impl Write,
+    value: &T,
+) -> io::Result<()> {
+    write_frame(out, &encode(value)?)
+}
+
+/// The next frame's value and the frame's length, or `None` at the end of
+/// the input. A frame that is cut short, fails its checksum or doesn't
+/// decode is an error.
+pub fn read_frame<T: DeserializeOwned>(
+    input: &mut impl Read,
+) -> io::Result<Option<(T, u64)>> {
+    let mut len = [0; 4];
+    match input.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Ok(None)
+        }
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_be_bytes(len);
+    let mut sum = [0; 4];
+    input.read_exact(&mut sum)?;
+    let mut payload = vec![0; len as usize];
+    input.read_exact(&mut payload)?;
+    if checksum(&payload) != sum {
+        return Err(invalid("checksum mismatch"));
+    }
+    let value = bincode::deserialize(&payload)
+        .map_err(|err| invalid(err.to_string()))?;
+    Ok(Some((value, 8 + len as u64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip_and_catch_damage() {
+        let mut out = Vec::new();
+        write_value(&mut out, &"first".to_string()).unwrap();
+        write_value(&mut out, &42u64).unwrap();
+
+        let mut input = &out[..];
+        let (first, len): (String, u64) =
+            read_frame(&mut input).unwrap().unwrap();
+        assert_eq!((first.as_str(), len), ("first", 8 + 8 + 5));
+        let (second, _): (u64, _) = read_frame(&mut input).unwrap().unwrap();
+        assert_eq!(second, 42);
+        assert!(read_frame::<u64>(&mut input).unwrap().is_none());
+
+        let mut flipped = out.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 1;
+        let mut input = &flipped[..];
+        read_frame::<String>(&mut input).unwrap();
+        assert!(read_frame::<u64>(&mut input).is_err());
+
+        let mut input = &out[..out.len() - 3];
+        read_frame::<String>(&mut input).unwrap();
+        assert!(read_frame::<u64>(&mut input).is_err());
+    }
+}
@@ -0,0 +1,9 @@
+pub mod chaos;
+pub mod config;
+pub mod frame;
+pub mod peer;
+pub mod persist;
+pub mod record;
+pub mod service;
+pub mod shard;
+pub mod state;
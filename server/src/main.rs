@@ -1,317 +1,247 @@
+use server::chaos::{Chaos, Fault};
+use server::config::Config;
+use server::peer;
+use server::record::{Event, Recorder};
+use server::service;
+use server::shard::Shards;
+
 use crdts_sandbox_lib::document::{
-    Command, DocActor, DocResponse, Document, DocumentOp,
+    Command, DocKey, DocResponse, Document, RecordKey,
 };
 
-use futures::{lock, FutureExt, Sink, SinkExt, Stream, StreamExt};
-
-use warp::{ws::Message, Filter};
+use futures::{Sink, SinkExt, Stream, StreamExt};
 
-use tokio::{
-    // io::{AsyncBufRead, AsyncBufReadExt},
-    net::{TcpListener, TcpStream},
-    prelude::*,
-};
+use tokio::time::timeout;
+use warp::{path::Tail, ws::Message, Filter};
 
-use bytes::Bytes;
 use std::{
-    collections::{HashMap, HashSet},
-    sync::{Arc, Mutex},
+    error::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
-type Db = Arc<Mutex<Document>>;
-type LatestActor = Arc<Mutex<DocActor>>;
-
-struct State {
-    doc: Document,
-    ops: Vec<DocumentOp>,
-    latest_actor: DocActor,
-    clients: HashSet<DocActor>,
-}
-
-impl State {
-    fn new() -> Self {
-        State {
-            doc: Document::example(),
-            ops: Vec::new(),
-            latest_actor: 0,
-            clients: HashSet::new(),
-        }
-    }
-}
-
-fn parse_command(msg: Message) -> Option<Command> {
+fn parse_command<K: DocKey>(msg: Message) -> Option<Command<K>> {
     if msg.is_binary() {
         let bytes = msg.as_bytes();
-        Command::from_bytes(&bytes)
+        Command::from_bytes(bytes)
     } else {
         None
     }
 }
 
-fn command_into_message(cmd: Command) -> Message {
-    let bytes_vec = cmd.to_bytes().unwrap();
-    Message::binary(bytes_vec)
-}
-
-fn docresp_into_message(resp: DocResponse) -> Message {
+fn docresp_into_message<K: DocKey>(resp: DocResponse<K>) -> Message {
     let bytes = bincode::serialize(&resp).unwrap();
     Message::binary(bytes)
 }
 
-async fn handle_connection_wrapper(
-    state: Arc<lock::Mutex<State>>,
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+async fn handle_connection_wrapper<K: DocKey>(
+    shared: Arc<Shards<K>>,
+    path: &'static str,
+    config: Arc<Config>,
+    from_peer: bool,
     // mut sink: impl Sink<Message, Error = warp::Error> + Unpin,
     sink: impl Sink<Message, Error = warp::Error> + Unpin,
     stream: impl Stream<Item = Result<Message, warp::Error>> + Unpin,
 ) {
-    let _ = handle_connection(state, sink, stream).await;
+    let connection = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    // a peer pairs each request with the next reply, so it couldn't
+    // recover from a dropped or duplicated one
+    let chaos = config
+        .chaos
+        .as_ref()
+        .filter(|_| !from_peer)
+        .map(|chaos| Chaos::for_connection(chaos, connection));
+    let recorder = config.record_dir.as_ref().and_then(|dir| {
+        let doc = match shared.document() {
+            Some(doc) => doc,
+            None => {
+                println!(
+                    "couldn't record connection {}: no document",
+                    connection
+                );
+                return None;
+            }
+        };
+        let recorder = Recorder::create(
+            dir,
+            path,
+            connection,
+            config.actor,
+            config.shards,
+            &doc,
+        );
+        if let Err(err) = &recorder {
+            println!("couldn't record connection {}: {}", connection, err);
+        }
+        recorder.ok()
+    });
+    let _ = handle_connection(shared, chaos, recorder, sink, stream).await;
+}
+
+/// Sends a response, or, in chaos mode, may hold it back in `held` to be
+/// sent after the next one.
+async fn send_response<K: DocKey>(
+    sink: &mut (impl Sink<Message, Error = warp::Error> + Unpin),
+    chaos: &mut Option<Chaos>,
+    held: &mut Option<DocResponse<K>>,
+    recorder: Option<&Recorder<K>>,
+    resp: DocResponse<K>,
+) -> Result<(), Box<dyn Error>> {
+    if held.is_none() && chaos.as_mut().is_some_and(Chaos::hold) {
+        *held = Some(resp);
+        return Ok(());
+    }
+    send_faulty(sink, chaos, recorder, resp).await?;
+    if let Some(resp) = held.take() {
+        send_faulty(sink, chaos, recorder, resp).await?;
+    }
+    Ok(())
+}
+
+/// Sends a response, or, in chaos mode, delays, drops or duplicates it,
+/// or closes the connection instead.
+async fn send_faulty<K: DocKey>(
+    sink: &mut (impl Sink<Message, Error = warp::Error> + Unpin),
+    chaos: &mut Option<Chaos>,
+    recorder: Option<&Recorder<K>>,
+    mut resp: DocResponse<K>,
+) -> Result<(), Box<dyn Error>> {
+    let (delay, copies) = match chaos.as_mut().map(|c| c.fault(&mut resp)) {
+        None => (None, 1),
+        Some(Fault::Send { delay, copies }) => (delay, copies),
+        Some(Fault::Disconnect) => return Err("chaos: disconnected".into()),
+    };
+    if let Some(delay) = delay {
+        tokio::time::delay_for(delay).await;
+    }
+    for _ in 0..copies {
+        if let Some(recorder) = recorder {
+            recorder.record(Event::Response(resp.clone()));
+        }
+        sink.send(docresp_into_message(resp.clone())).await?;
+    }
+    Ok(())
 }
 
-async fn handle_connection(
-    state: Arc<lock::Mutex<State>>,
+async fn handle_connection<K: DocKey>(
+    shared: Arc<Shards<K>>,
+    mut chaos: Option<Chaos>,
+    recorder: Option<Recorder<K>>,
     // mut sink: impl Sink<Message, Error = warp::Error> + Unpin,
     mut sink: impl Sink<Message, Error = warp::Error> + Unpin,
     mut stream: impl Stream<Item = Result<Message, warp::Error>> + Unpin,
-) -> Result<(), Box<dyn std::error::Error>> {
-    while let Some(Ok(msg)) = stream.next().await {
-        if let Some(cmd) = parse_command(msg) {
-            let mut state = state.lock().await;
-            match cmd {
-                Command::GetDocument => {
-                    let resp = DocResponse::Document(state.doc.clone());
-                    let msg = docresp_into_message(resp);
-                    sink.send(msg).await?;
-                    sink.flush().await?;
-                }
-                Command::GetRecord { key } => {
-                    let record_ctx = state.doc.get_record(key);
-                    let resp = DocResponse::Record(record_ctx);
-                    let msg = docresp_into_message(resp);
-                    sink.send(msg).await?;
-                    sink.flush().await?;
-                }
-                Command::GetReadCtx => {
-                    let ctx = state.doc.get_read_ctx();
-                    let resp = DocResponse::ReadCtx(ctx);
-                    let msg = docresp_into_message(resp);
-                    sink.send(msg).await?;
+) -> Result<(), Box<dyn Error>> {
+    let mut held = None;
+    loop {
+        // a held back response is sent on its own if nothing overtakes it
+        let wait = chaos
+            .as_ref()
+            .filter(|_| held.is_some())
+            .map(Chaos::max_hold);
+        let next = match wait {
+            Some(wait) => match timeout(wait, stream.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    let resp = held.take().expect("a held response");
+                    let recorder = recorder.as_ref();
+                    send_faulty(&mut sink, &mut chaos, recorder, resp).await?;
                     sink.flush().await?;
+                    continue;
                 }
-                Command::Add {
-                    add_ctx,
-                    key,
-                    content,
-                } => {
-                    let content = Vec::from(content.as_bytes());
-                    let op =
-                        state.doc.update_record(key, add_ctx, |set, ctx| {
-                            set.add(content, ctx)
-                        });
-                    state.ops.push(op.clone());
-                    state.doc.apply(op);
-                }
-                Command::Apply { op } => {
-                    state.ops.push(op.clone());
-                    state.doc.apply(op);
-                }
+            },
+            None => stream.next().await,
+        };
+        let msg = match next {
+            Some(Ok(msg)) => msg,
+            _ => break,
+        };
+        if let Some(cmd) = parse_command(msg) {
+            if let Some(recorder) = &recorder {
+                recorder.record(Event::Command(cmd.clone()));
+            }
+            for resp in service::respond(&shared, cmd).await {
+                let recorder = recorder.as_ref();
+                send_response(&mut sink, &mut chaos, &mut held, recorder, resp)
+                    .await?;
             }
+            sink.flush().await?;
         }
     }
 
     Ok(())
 }
 
-/*
-async fn websocket_connected(ws: warp::ws::WebSocket) {
-    let (tx, rx) = ws.split();
-
-    tokio::spawn(async move {
-
-    });
-
-}
-*/
-
-#[tokio::main]
-async fn main() {
-    // pretty_env_logger::init();
-
-    // let state = Arc::new(Mutex::new(State::new()));
+fn document_service<K: DocKey>(
+    path: &'static str,
+    doc: Document<K>,
+    config: &Config,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let shards = match &config.data_dir {
+        Some(dir) => Shards::open(config.actor, doc, config.shards, dir, path)
+            .unwrap_or_else(|err| {
+                eprintln!("couldn't open the op log of {}: {}", path, err);
+                std::process::exit(1)
+            }),
+        None => Shards::spawn(config.actor, doc, config.shards).unwrap_or_else(
+            |err| {
+                eprintln!("couldn't shard {}: {}", path, err);
+                std::process::exit(1)
+            },
+        ),
+    };
+    let shared = Arc::new(shards);
+    for addr in config.peers.iter() {
+        let url = format!("ws://{}/{}/{}", addr, path, peer::PEER_PATH);
+        tokio::spawn(peer::replicate(shared.clone(), url, config.actor));
+    }
+    let shared = warp::any().map(move || shared.clone());
+    let config = Arc::new(config.clone());
+    let config = warp::any().map(move || config.clone());
 
-    let echo = warp::path("echo")
-        // The `ws()` filter will prepare the Websocket handshake.
+    warp::path(path)
+        .and(warp::path::tail())
         .and(warp::ws())
-        .map(|ws: warp::ws::Ws| {
-            // And then our closure will be called when it completes...
-            ws.on_upgrade(|websocket| {
-                let (tx, rx) = websocket.split();
-
-                rx.inspect(|msg| {
-                    if let Ok(m) = msg {
-                        if let Ok(string) = m.to_str() {
-                            println!("received: {}", string);
-                        }
-                    }
-                })
-                .forward(tx)
-                .map(|result| {
-                    if let Err(e) = result {
-                        eprintln!("websocket error: {:?}", e);
-                    }
-                })
-            })
-        });
-
-    let state = Arc::new(lock::Mutex::new(State::new()));
-    let state = warp::any().map(move || state.clone());
-
-    let service = warp::path("service").and(warp::ws()).and(state).map(
-        |ws: warp::ws::Ws, state| {
+        .and(shared)
+        .and(config)
+        .map(move |tail: Tail, ws: warp::ws::Ws, shared, config| {
+            let from_peer = tail.as_str() == peer::PEER_PATH;
             ws.on_upgrade(move |websocket| {
                 let (tx, rx) = websocket.split();
-                handle_connection_wrapper(state, tx, rx)
-            })
-        },
-    );
-
-    // let routes = index
-
-    /*
-    let routes = warp::path("echo")
-        // The `ws()` filter will prepare the Websocket handshake.
-        .and(warp::ws())
-        .map(|ws: warp::ws::Ws| {
-            // And then our closure will be called when it completes...
-            ws.on_upgrade(|websocket| {
-                let (tx, rx) = websocket.split();
-
-                rx.inspect(|msg| {
-                    if let Ok(m) = msg {
-                        if let Ok(string) = m.to_str() {
-                            println!("received: {}", string);
-                        }
-                    }
-                })
-                .forward(tx)
-                .map(|result| {
-                    if let Err(e) = result {
-                        eprintln!("websocket error: {:?}", e);
-                    }
-                })
+                handle_connection_wrapper::<K>(
+                    shared, path, config, from_peer, tx, rx,
+                )
             })
-        });
-    */
-
-    // warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-    warp::serve(service).run(([127, 0, 0, 1], 3030)).await;
+        })
 }
 
-// #[tokio::main]
-async fn old_main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut listener = TcpListener::bind("127.0.0.1:8080").await.unwrap();
-
-    let state = Arc::new(Mutex::new(State::new()));
-
-    loop {
-        let (mut socket, _) = listener.accept().await?;
-
-        let state = Arc::clone(&state);
-
-        tokio::spawn(async move {
-            let mut buf = [0; 1024];
-            let mut out_buf: Vec<u8> = Vec::with_capacity(1024);
-
-            println!("client connected");
-
-            loop {
-                let n = match socket.read(&mut buf).await {
-                    Ok(n) if n == 0 => return,
-                    Ok(n) => n,
-                    Err(e) => {
-                        eprintln!("failed to read from socket; err = {:?}", e);
-                        return;
-                    }
-                };
-
-                println!("received {} bytes", n);
-                if n > 0 {
-                    if let Some(cmd) = Command::from_bytes(&buf[0..n]) {
-                        match cmd {
-                            Command::GetDocument => {
-                                let state = state.lock().unwrap();
-                                println!("received GetDocument");
-                                let doc =
-                                    DocResponse::Document(state.doc.clone());
-                                match bincode::serialize(&doc) {
-                                    Ok(bytes) => {
-                                        out_buf.extend_from_slice(&bytes);
-                                        println!("Transmitting document");
-                                    }
-                                    Err(e) => {
-                                        eprintln!(
-                                            "error serializing doc: {:?}",
-                                            e
-                                        );
-                                    }
-                                }
-                            }
-                            Command::GetRecord { key } => {
-                                let state = state.lock().unwrap();
-                                let record_ctx = state.doc.get_record(key);
-                                let record = DocResponse::Record(record_ctx);
-                                let bytes =
-                                    bincode::serialize(&record).unwrap();
-                                out_buf.extend_from_slice(&bytes);
-                                println!("received GetRecord");
-                            }
-                            Command::GetReadCtx => {
-                                let state = state.lock().unwrap();
-                                let ctx = state.doc.get_read_ctx();
-                                let ctx = DocResponse::ReadCtx(ctx);
-                                let bytes = bincode::serialize(&ctx).unwrap();
-                                out_buf.extend_from_slice(&bytes);
-                                println!("received GetReadCtx");
-                            }
-                            Command::Add {
-                                add_ctx,
-                                key,
-                                content,
-                            } => {
-                                let content = Vec::from(content.as_bytes());
-                                let mut state = state.lock().unwrap();
-                                let op = state.doc.update_record(
-                                    key,
-                                    add_ctx,
-                                    |set, ctx| set.add(content, ctx),
-                                );
-                                state.ops.push(op.clone());
-                                state.doc.apply(op);
-                            }
-                            Command::Apply { op } => {
-                                let mut state = state.lock().unwrap();
-                                state.ops.push(op.clone());
-                                state.doc.apply(op);
-                            }
-                        }
-                    } else {
-                        out_buf.extend_from_slice(&buf[0..n]);
-                    }
-                }
-
-                if !out_buf.is_empty() {
-                    if let Err(e) = socket.write_all(&out_buf[..]).await {
-                        eprintln!("failed to read from socket; err = {:?}", e);
-                        return;
-                    }
-                    out_buf.clear();
-                }
-                /*
-                if let Err(e) = socket.write_all(&buf[0..n]).await {
-                    eprintln!("failed to read from socket; err = {:?}", e);
-                    return;
-                }
-                */
-            }
-        });
+#[tokio::main]
+async fn main() {
+    let config = Config::from_env().unwrap_or_else(|err| {
+        eprintln!("invalid configuration: {}", err);
+        std::process::exit(1)
+    });
+    if let Some(chaos) = &config.chaos {
+        println!("chaos mode: {:?}", chaos);
+    }
+    if let Some(dir) = &config.data_dir {
+        println!("persisting op logs to {}", dir.display());
+    }
+    if let Some(dir) = &config.record_dir {
+        println!("recording sessions to {}", dir.display());
     }
+    let service = document_service::<String>(
+        "service",
+        Document::example("1".into()),
+        &config,
+    );
+    let numeric =
+        document_service::<RecordKey>("numeric", Document::example(1), &config);
+
+    warp::serve(service.or(numeric))
+        .run(([127, 0, 0, 1], config.port))
+        .await;
 }
@@ -0,0 +1,244 @@
+//! Replication between servers. A server connects to each of its peers
+//! like a client would and, every `SYNC_INTERVAL`, compares the root of
+//! the hash trees over their records. If they differ, it pulls the ops it
+//! is missing with `Resume` and pushes the ops the peer is missing with
+//! `Apply`: on the first round after connecting, the ops the peer's clock
+//! doesn't cover, and after that only the ops logged since the last push.
+//! When its op log can't cover the difference it walks the peer's tree to
+//! find the records that differ and pushes only those.
+//! Since every round starts from the state at either end, peers catch up
+//! on their own after a partition.
+//!
+//! To run three replicating servers on localhost:
+//!
+//! ```text
+//! PORT=3030 SERVER_ACTOR=1000 PEERS=127.0.0.1:3031,127.0.0.1:3032 cargo run -p server
+//! PORT=3031 SERVER_ACTOR=1001 PEERS=127.0.0.1:3030,127.0.0.1:3032 cargo run -p server
+//! PORT=3032 SERVER_ACTOR=1002 PEERS=127.0.0.1:3030,127.0.0.1:3031 cargo run -p server
+//! ```
+
+use crate::shard::Shards;
+
+use crdts_sandbox_lib::backoff::Backoff;
+use crdts_sandbox_lib::document::{
+    Command, Digest, DigestRange, DocActor, DocKey, DocResponse, DocumentOp,
+    MerkleTree,
+};
+
+use crdts::map::Op;
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use std::{error::Error, sync::Arc, time::Duration};
+
+pub const SYNC_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What peers append to a document's path when they connect, so the
+/// server spares them the faults of chaos mode.
+pub const PEER_PATH: &str = "peer";
+
+type PeerError = Box<dyn Error + Send + Sync>;
+
+/// Keeps `shards` in sync with the document served at `url`, reconnecting
+/// with backoff whenever the connection is lost.
+pub async fn replicate<K: DocKey>(
+    shards: Arc<Shards<K>>,
+    url: String,
+    actor: DocActor,
+) {
+    let mut backoff = Backoff::default();
+    loop {
+        if let Ok((ws_stream, _)) =
+            tokio_tungstenite::connect_async(url.as_str()).await
+        {
+            backoff.reset();
+            println!("peer {}: connected", url);
+            let (mut sink, mut stream) = ws_stream.split();
+            // the peer may have restarted, so the first round pushes
+            // whatever its clock shows it's missing
+            let mut pushed = None;
+            loop {
+                let synced = sync_round(
+                    &shards,
+                    actor,
+                    &mut pushed,
+                    &mut sink,
+                    &mut stream,
+                )
+                .await;
+                if let Err(err) = synced {
+                    println!("peer {}: disconnected, {}", url, err);
+                    break;
+                }
+                tokio::time::delay_for(SYNC_INTERVAL).await;
+            }
+        }
+
+        tokio::time::delay_for(backoff.next_delay()).await;
+    }
+}
+
+async fn sync_round<K: DocKey>(
+    shards: &Shards<K>,
+    actor: DocActor,
+    pushed: &mut Option<Vec<u64>>,
+    sink: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    stream: &mut (impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin),
+) -> Result<(), PeerError> {
+    // everything logged before these positions is in the snapshot
+    let logged = shards.log_positions().await;
+    // while nothing changes a round is a single digest of the root, and
+    // the trees of the parts that haven't changed are kept from the last
+    let snapshot = shards.snapshot();
+    let tree = snapshot.merkle_tree();
+    let root = match request(sink, stream, root_digest()).await? {
+        DocResponse::Digest(root) => root,
+        _ => return Ok(()),
+    };
+    if root.hash == tree.root() {
+        *pushed = Some(logged);
+        return Ok(());
+    }
+
+    let clock = shards.read_ctx().add_clock;
+    match request(sink, stream, Command::Resume { clock }).await? {
+        DocResponse::Missed { ops } => {
+            for op in ops {
+                shards.apply_op(op).await;
+            }
+        }
+        DocResponse::Document(doc) => shards.merge(doc).await,
+        _ => (),
+    }
+    let clock = shards.read_ctx().add_clock;
+    let ack = Command::<K>::AckClock { actor, clock };
+    send_command(sink, &ack).await?;
+
+    let peer_clock =
+        match request(sink, stream, Command::<K>::GetReadCtx).await? {
+            DocResponse::ReadCtx(read_ctx) => read_ctx.add_clock,
+            _ => return Ok(()),
+        };
+    // after the first round only what was logged since the last push is
+    // sent, less the updates the peer's clock shows it has, like the ones
+    // just pulled from it
+    let suffix = match pushed.as_ref() {
+        Some(from) => shards.log_since(from).await,
+        None => None,
+    };
+    if let Some((ops, next)) = suffix {
+        let ops = ops
+            .into_iter()
+            .filter(|op| match op {
+                Op::Up { dot, .. } => peer_clock.get(&dot.actor) < dot.counter,
+                Op::Rm { .. } => true,
+            })
+            .collect();
+        apply_all(ops, sink, stream).await?;
+        *pushed = Some(next);
+        return Ok(());
+    }
+
+    match shards.handle(Command::Resume { clock: peer_clock }).await {
+        // map removes carry no dot, so all of them are sent this once
+        Some(DocResponse::Missed { ops }) => {
+            apply_all(ops, sink, stream).await?;
+        }
+        // our log can't bring the peer up to date, so send it the records
+        // that differ instead of the whole document
+        Some(DocResponse::Document(_)) => {
+            let keys = differing_keys(&tree, root, sink, stream).await?;
+            let set = snapshot.record_set(keys);
+            send_command(sink, &Command::MergeRecords { set }).await?;
+        }
+        _ => return Ok(()),
+    }
+    *pushed = Some(logged);
+    Ok(())
+}
+
+/// Sends `ops` to the peer all at once, then waits for its acks.
+async fn apply_all<K: DocKey>(
+    ops: Vec<DocumentOp<K>>,
+    sink: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    stream: &mut (impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin),
+) -> Result<(), PeerError> {
+    let count = ops.len();
+    for op in ops {
+        send_command(sink, &Command::Apply { op }).await?;
+    }
+    for _ in 0..count {
+        response::<K>(stream).await?;
+    }
+    Ok(())
+}
+
+fn root_digest<K: DocKey>() -> Command<K> {
+    Command::GetDigest {
+        range: DigestRange::root(),
+    }
+}
+
+/// Walks the peer's hash tree down from `root`, a level per round trip,
+/// into the ranges whose hashes differ from `tree`'s.
+async fn differing_keys<K: DocKey>(
+    tree: &MerkleTree<K>,
+    root: Digest<K>,
+    sink: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    stream: &mut (impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin),
+) -> Result<Vec<K>, PeerError> {
+    let mut keys = Vec::new();
+    let mut level = vec![root];
+    while !level.is_empty() {
+        let mut ranges = Vec::new();
+        for digest in level {
+            let diff = tree.diff(&digest);
+            keys.extend(diff.keys);
+            ranges.extend(diff.ranges);
+        }
+        for range in ranges.iter() {
+            let cmd = Command::<K>::GetDigest { range: *range };
+            send_command(sink, &cmd).await?;
+        }
+        level = Vec::with_capacity(ranges.len());
+        for _ in ranges {
+            if let DocResponse::Digest(digest) = response(stream).await? {
+                level.push(digest);
+            }
+        }
+    }
+    Ok(keys)
+}
+
+async fn send_command<K: DocKey>(
+    sink: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    cmd: &Command<K>,
+) -> Result<(), PeerError> {
+    let bytes = cmd.to_bytes().ok_or("couldn't encode command")?;
+    sink.send(Message::binary(bytes)).await?;
+    Ok(())
+}
+
+/// Sends `cmd` and waits for the peer's reply to it.
+async fn request<K: DocKey>(
+    sink: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    stream: &mut (impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin),
+    cmd: Command<K>,
+) -> Result<DocResponse<K>, PeerError> {
+    send_command(sink, &cmd).await?;
+    response(stream).await
+}
+
+async fn response<K: DocKey>(
+    stream: &mut (impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin),
+) -> Result<DocResponse<K>, PeerError> {
+    while let Some(msg) = stream.next().await {
+        if let Message::Binary(bytes) = msg? {
+            return DocResponse::from_bytes(&bytes)
+                .ok_or_else(|| "couldn't decode response".into());
+        }
+    }
+    Err("connection closed".into())
+}
@@ -0,0 +1,514 @@
+//! The op log of each shard, kept on disk so a restarted server carries on
+//! from where it stopped and the `inspect` binary can look at what it
+//! applied.
+//!
+//! With `DATA_DIR` set, the writer of shard `n` of the document at `path`
+//! appends every change it makes to `{path}-{n}.oplog` in that directory,
+//! and syncs the log to disk once per batch of writes, before publishing
+//! the batch or acknowledging any of it, so an acknowledged write survives
+//! a crash. Every
+//! `SNAPSHOT_EVERY` changes it also replaces `{path}-{n}.snapshot` with
+//! the whole shard, which a restart then only has to replay the rest of
+//! the log on top of.
+//!
+//! A log is a sequence of frames, see `frame`: a `LogHeader`, the
+//! `Document` the log starts from, then `LogEntry`s. A snapshot is a
+//! single frame holding a `Snapshot`.
+//! A log position is a number of entries, so position 0 is the document
+//! the log starts from.
+
+use crate::frame::{invalid, read_frame, write_value};
+
+use crdts_sandbox_lib::document::{
+    DocActor, DocKey, Document, DocumentOp, Parts, RecordSet,
+};
+
+use crdts::{CvRDT, VClock};
+
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How many changes a shard logs between snapshots.
+pub const SNAPSHOT_EVERY: u64 = 1000;
+
+/// What a log was written by: the document's path and key type, as its
+/// `DocKey::KEY_TYPE`, the
+/// shard and how many there were, the server's actor, and when the log was
+/// created, in microseconds since the Unix epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogHeader {
+    pub path: String,
+    pub key_type: String,
+    pub shard: usize,
+    pub shards: usize,
+    pub actor: DocActor,
+    pub created: u64,
+}
+
+/// A change a shard's writer made to its document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub enum Change<K: DocKey> {
+    Op(DocumentOp<K>),
+    Merge(Document<K>),
+    MergeRecords(RecordSet<K>),
+}
+
+impl<K: DocKey> Change<K> {
+    /// Applies the change to `doc` the way the writer did when it was
+    /// logged.
+    pub fn apply_to(self, doc: &mut Document<K>) {
+        match self {
+            Change::Op(op) => doc.apply_part(op),
+            Change::Merge(other) => doc.records.merge(other.records),
+            Change::MergeRecords(set) => {
+                doc.merge_records(set);
+            }
+        }
+    }
+
+    /// Applies the change to the parts of a shard, the way `apply_to`
+    /// applies it to the whole shard. `None` if a merge couldn't be split
+    /// between the parts.
+    pub fn apply_to_parts(self, parts: &mut Parts<K>) -> Option<()> {
+        match self {
+            Change::Op(op) => parts.apply(op),
+            Change::Merge(other) => parts.merge(other)?,
+            Change::MergeRecords(set) => parts.merge_records(set)?,
+        }
+        Some(())
+    }
+
+    /// The clock the change brings into the document, for merges, whose
+    /// ops aren't in the log.
+    pub fn merged_clock(&self) -> Option<VClock<DocActor>> {
+        match self {
+            Change::Op(_) => None,
+            Change::Merge(doc) => Some(doc.get_read_ctx().add_clock),
+            Change::MergeRecords(set) => Some(set.clock.clone()),
+        }
+    }
+}
+
+/// A logged change and when the writer received it, in microseconds since
+/// the Unix epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct LogEntry<K: DocKey> {
+    pub received: u64,
+    pub change: Change<K>,
+}
+
+/// The document after the first `position` entries of its log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Snapshot<K: DocKey> {
+    pub position: u64,
+    pub document: Document<K>,
+}
+
+/// Where a log stops being readable: the position of the entry that
+/// couldn't be read, its byte offset in the file, and why.
+#[derive(Debug, Clone)]
+pub struct Damage {
+    pub position: u64,
+    pub offset: u64,
+    pub reason: String,
+}
+
+/// A log as read back. A damaged log, e.g. one whose last entry was cut
+/// short by the server being killed, ends at the last entry that could be
+/// read.
+#[derive(Debug, Clone)]
+pub struct StoredLog<K: DocKey> {
+    pub header: LogHeader,
+    pub base: Document<K>,
+    pub entries: Vec<LogEntry<K>>,
+    pub damage: Option<Damage>,
+    // the length of the readable part of the file
+    len: u64,
+}
+
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
+
+pub fn log_path(dir: &Path, path: &str, shard: usize) -> PathBuf {
+    dir.join(format!("{}-{}.oplog", path, shard))
+}
+
+/// The snapshot kept next to the log at `log`.
+pub fn snapshot_path(log: &Path) -> PathBuf {
+    log.with_extension("snapshot")
+}
+
+/// Reads only what a log was written by, e.g. to find out which key type
+/// to read the rest with.
+pub fn read_header(log: &Path) -> io::Result<LogHeader> {
+    let mut input = BufReader::new(File::open(log)?);
+    let (header, _) =
+        read_frame(&mut input)?.ok_or_else(|| invalid("no log header"))?;
+    Ok(header)
+}
+
+pub fn read_log<K: DocKey>(log: &Path) -> io::Result<StoredLog<K>> {
+    let mut input = BufReader::new(File::open(log)?);
+    let (header, header_len) =
+        read_frame(&mut input)?.ok_or_else(|| invalid("no log header"))?;
+    let (base, base_len) =
+        read_frame(&mut input)?.ok_or_else(|| invalid("no base document"))?;
+    let mut len = header_len + base_len;
+    let mut entries = Vec::new();
+    let mut damage = None;
+    loop {
+        match read_frame(&mut input) {
+            Ok(Some((entry, entry_len))) => {
+                entries.push(entry);
+                len += entry_len;
+            }
+            Ok(None) => break,
+            Err(err) => {
+                damage = Some(Damage {
+                    position: entries.len() as u64,
+                    offset: len,
+                    reason: err.to_string(),
+                });
+                break;
+            }
+        }
+    }
+    Ok(StoredLog {
+        header,
+        base,
+        entries,
+        damage,
+        len,
+    })
+}
+
+/// The snapshot kept next to the log at `log`, if one has been written.
+pub fn read_snapshot<K: DocKey>(log: &Path) -> io::Result<Option<Snapshot<K>>> {
+    let file = match File::open(snapshot_path(log)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let (snapshot, _) = read_frame(&mut BufReader::new(file))?
+        .ok_or_else(|| invalid("empty snapshot"))?;
+    Ok(Some(snapshot))
+}
+
+/// Appends to a shard's log and replaces its snapshot.
+pub struct LogWriter<K: DocKey> {
+    out: BufWriter<File>,
+    snapshot: PathBuf,
+    position: u64,
+    since_snapshot: u64,
+    key: PhantomData<K>,
+}
+
+impl<K: DocKey> LogWriter<K> {
+    /// How many entries the log holds.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Logs `change`, received at `received` microseconds since the Unix
+    /// epoch.
+    pub fn append(
+        &mut self,
+        received: u64,
+        change: &Change<K>,
+    ) -> io::Result<()> {
+        #[derive(Serialize)]
+        #[serde(bound = "")]
+        struct EntryRef<'a, K: DocKey> {
+            received: u64,
+            change: &'a Change<K>,
+        }
+
+        let entry = EntryRef { received, change };
+        write_value(&mut self.out, &entry)?;
+        self.position += 1;
+        self.since_snapshot += 1;
+        Ok(())
+    }
+
+    /// Writes what has been appended through to the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.out.get_ref().sync_data()
+    }
+
+    pub fn snapshot_due(&self) -> bool {
+        self.since_snapshot >= SNAPSHOT_EVERY
+    }
+
+    /// Replaces the snapshot with `doc`, which must be the document at the
+    /// end of the log.
+    pub fn snapshot(&mut self, doc: &Document<K>) -> io::Result<()> {
+        #[derive(Serialize)]
+        #[serde(bound = "")]
+        struct SnapshotRef<'a, K: DocKey> {
+            position: u64,
+            document: &'a Document<K>,
+        }
+
+        let tmp = self.snapshot.with_extension("snapshot.tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        let snapshot = SnapshotRef {
+            position: self.position,
+            document: doc,
+        };
+        write_value(&mut out, &snapshot)?;
+        out.flush()?;
+        out.get_ref().sync_data()?;
+        fs::rename(tmp, &self.snapshot)?;
+        self.since_snapshot = 0;
+        Ok(())
+    }
+}
+
+/// What a shard logged before the server was restarted, and the writer to
+/// carry on logging with.
+pub struct Recovered<K: DocKey> {
+    pub base: Document<K>,
+    pub snapshot: Option<Snapshot<K>>,
+    pub entries: Vec<LogEntry<K>>,
+    pub writer: LogWriter<K>,
+}
+
+/// Opens the log of shard `shard` of the document at `path`, creating it
+/// with `initial` as its base document if there is none yet. A damaged
+/// end is cut off so new entries follow the last readable one.
+pub fn open<K: DocKey>(
+    dir: &Path,
+    path: &str,
+    shard: usize,
+    shards: usize,
+    actor: DocActor,
+    initial: Document<K>,
+) -> io::Result<Recovered<K>> {
+    let log = log_path(dir, path, shard);
+    let snapshot = snapshot_path(&log);
+    if !log.exists() {
+        let header = LogHeader {
+            path: path.into(),
+            key_type: K::KEY_TYPE.into(),
+            shard,
+            shards,
+            actor,
+            created: now_micros(),
+        };
+        let mut out = BufWriter::new(File::create(&log)?);
+        write_value(&mut out, &header)?;
+        write_value(&mut out, &initial)?;
+        out.flush()?;
+        out.get_ref().sync_data()?;
+        return Ok(Recovered {
+            base: initial,
+            snapshot: None,
+            entries: Vec::new(),
+            writer: LogWriter {
+                out,
+                snapshot,
+                position: 0,
+                since_snapshot: 0,
+                key: PhantomData,
+            },
+        });
+    }
+
+    let stored = read_log::<K>(&log)?;
+    if stored.header.key_type != K::KEY_TYPE {
+        return Err(invalid(format!(
+            "{} holds {} keys",
+            log.display(),
+            stored.header.key_type
+        )));
+    }
+    if stored.header.shards != shards {
+        return Err(invalid(format!(
+            "{} was written with {} shards, not {}",
+            log.display(),
+            stored.header.shards,
+            shards
+        )));
+    }
+    if let Some(damage) = &stored.damage {
+        println!(
+            "{}: dropping what follows entry {}: {}",
+            log.display(),
+            damage.position,
+            damage.reason
+        );
+    }
+    let file = OpenOptions::new().append(true).open(&log)?;
+    file.set_len(stored.len)?;
+    let out = BufWriter::new(file);
+
+    // a snapshot beyond the readable part of the log can't be trusted
+    let position = stored.entries.len() as u64;
+    let read = read_snapshot::<K>(&log)?
+        .filter(|snapshot| snapshot.position <= position);
+    let since_snapshot =
+        position - read.as_ref().map_or(0, |snapshot| snapshot.position);
+    Ok(Recovered {
+        base: stored.base,
+        snapshot: read,
+        entries: stored.entries,
+        writer: LogWriter {
+            out,
+            snapshot,
+            position,
+            since_snapshot,
+            key: PhantomData,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+    use crdts_sandbox_lib::document::{Item, RecordEntry, RecordKey};
+
+    // a fresh directory for one test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "persist-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn add(doc: &mut Document, key: RecordKey) -> Change<RecordKey> {
+        let ctx = doc.get_read_ctx().derive_add_ctx(1);
+        let entry = RecordEntry::text(&key.to_string());
+        let op = doc.add_item(key, ctx, Item::Single(entry));
+        doc.apply(op.clone());
+        Change::Op(op)
+    }
+
+    // logs `count` adds to a new log in `dir`, returning the document they
+    // lead to
+    fn write_log(dir: &Path, count: RecordKey) -> Document {
+        let mut doc = Document::default();
+        let mut recovered = open(dir, "doc", 0, 1, 0, doc.clone()).unwrap();
+        for key in 0..count {
+            recovered.writer.append(key, &add(&mut doc, key)).unwrap();
+        }
+        recovered.writer.sync().unwrap();
+        doc
+    }
+
+    fn reopen(dir: &Path, shards: usize) -> io::Result<Recovered<RecordKey>> {
+        open(dir, "doc", 0, shards, 0, Document::default())
+    }
+
+    fn cut(log: &Path, bytes: u64) {
+        let file = OpenOptions::new().write(true).open(log).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - bytes).unwrap();
+    }
+
+    #[test]
+    fn a_cut_off_tail_is_dropped_and_logged_over() {
+        let dir = test_dir("tail");
+        let mut doc = write_log(&dir, 3);
+        let log = log_path(&dir, "doc", 0);
+        cut(&log, 2);
+        assert_eq!(
+            read_log::<RecordKey>(&log)
+                .unwrap()
+                .damage
+                .unwrap()
+                .position,
+            2
+        );
+
+        let mut recovered = reopen(&dir, 1).unwrap();
+        assert_eq!(recovered.entries.len(), 2);
+        assert_eq!(recovered.writer.position(), 2);
+        recovered.writer.append(3, &add(&mut doc, 3)).unwrap();
+        recovered.writer.sync().unwrap();
+        drop(recovered);
+
+        let stored = read_log::<RecordKey>(&log).unwrap();
+        assert!(stored.damage.is_none());
+        let received: Vec<_> =
+            stored.entries.iter().map(|e| e.received).collect();
+        assert_eq!(received, vec![0, 1, 3]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshots_past_the_readable_log_are_ignored() {
+        let dir = test_dir("snapshot");
+        let doc = write_log(&dir, 3);
+        let log = log_path(&dir, "doc", 0);
+        let mut recovered = reopen(&dir, 1).unwrap();
+        recovered.writer.snapshot(&doc).unwrap();
+        drop(recovered);
+        assert!(!snapshot_path(&log).with_extension("snapshot.tmp").exists());
+        let snapshot = read_snapshot::<RecordKey>(&log).unwrap().unwrap();
+        assert_eq!(snapshot.position, 3);
+
+        let recovered = reopen(&dir, 1).unwrap();
+        assert_eq!(recovered.snapshot.as_ref().unwrap().position, 3);
+        drop(recovered);
+        cut(&log, 2);
+        let recovered = reopen(&dir, 1).unwrap();
+        assert!(recovered.snapshot.is_none());
+        assert_eq!(recovered.entries.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn logs_of_another_key_type_or_shard_count_are_refused() {
+        let dir = test_dir("header");
+        write_log(&dir, 1);
+        let err = open::<String>(&dir, "doc", 0, 1, 0, Document::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = reopen(&dir, 2).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(reopen(&dir, 1).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovery_rebuilds_the_logged_document() {
+        let dir = test_dir("recover");
+        let mut doc = Document::default();
+        let mut recovered = open(&dir, "doc", 0, 1, 0, doc.clone()).unwrap();
+        for key in 0..10 {
+            recovered.writer.append(key, &add(&mut doc, key)).unwrap();
+            if key == 5 {
+                recovered.writer.snapshot(&doc).unwrap();
+            }
+        }
+        recovered.writer.sync().unwrap();
+        drop(recovered);
+
+        let recovered = reopen(&dir, 1).unwrap();
+        assert_eq!(recovered.snapshot.as_ref().unwrap().position, 6);
+        let state = State::recover(0, recovered, 0, 1).unwrap();
+        assert_eq!(state.parts().content_hash(), doc.content_hash());
+        assert_eq!(state.parts().state_hash(), doc.state_hash());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! Recordings of client sessions, for reproducing what a client saw.
+//!
+//! With `RECORD_DIR` set, the server writes every connection to its own
+//! file in that directory: first what the session was served from, then
+//! every command received and every response sent, with the time since
+//! the connection was opened. Responses are recorded as they were sent,
+//! so in chaos mode dropped ones are missing and duplicated ones appear
+//! twice. The `replay` binary reads them back.
+//!
+//! A file is a sequence of frames, see `frame`: a `SessionInfo`, the
+//! `Document` the server held when the connection was opened, then
+//! `Recorded` events.
+
+use crate::frame::{encode, invalid, read_frame, write_frame, write_value};
+
+use crdts_sandbox_lib::document::{
+    Command, DocActor, DocKey, DocResponse, Document,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Where a session was recorded: the document's path and key type, as
+/// its `DocKey::KEY_TYPE`, the
+/// server's setup, and when the connection was opened, in microseconds
+/// since the Unix epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub path: String,
+    pub key_type: String,
+    pub connection: u64,
+    pub actor: DocActor,
+    pub shards: usize,
+    pub started: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub enum Event<K: DocKey> {
+    Command(Command<K>),
+    Response(DocResponse<K>),
+}
+
+/// An event and when it happened, in microseconds since the session
+/// started.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Recorded<K: DocKey> {
+    pub micros: u64,
+    pub event: Event<K>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Session<K: DocKey> {
+    pub info: SessionInfo,
+    pub document: Document<K>,
+    pub events: Vec<Recorded<K>>,
+}
+
+/// Records one connection. Events are encoded by the caller and written
+/// by a thread of the recorder's own, so recording never blocks the
+/// connection on the disk.
+pub struct Recorder<K: DocKey> {
+    start: Instant,
+    frames: mpsc::Sender<Vec<u8>>,
+    key: PhantomData<K>,
+}
+
+impl<K: DocKey> Recorder<K> {
+    /// Starts recording a session into a new file in `dir`.
+    pub fn create(
+        dir: &Path,
+        path: &str,
+        connection: u64,
+        actor: DocActor,
+        shards: usize,
+        document: &Document<K>,
+    ) -> io::Result<Self> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as u64);
+        let info = SessionInfo {
+            path: path.into(),
+            key_type: K::KEY_TYPE.into(),
+            connection,
+            actor,
+            shards,
+            started,
+        };
+        let file_name = format!("{}-{:06}.session", path, connection);
+        let mut out = BufWriter::new(File::create(dir.join(file_name))?);
+        write_value(&mut out, &info)?;
+        write_value(&mut out, document)?;
+        out.flush()?;
+
+        let (frames, received) = mpsc::channel::<Vec<u8>>();
+        std::thread::spawn(move || {
+            for frame in received {
+                if write_frame(&mut out, &frame)
+                    .and_then(|_| out.flush())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        Ok(Recorder {
+            start: Instant::now(),
+            frames,
+            key: PhantomData,
+        })
+    }
+
+    pub fn record(&self, event: Event<K>) {
+        let recorded = Recorded {
+            micros: self.start.elapsed().as_micros() as u64,
+            event,
+        };
+        if let Ok(frame) = encode(&recorded) {
+            let _ = self.frames.send(frame);
+        }
+    }
+}
+
+fn missing(what: &str) -> io::Error {
+    invalid(format!("no {}", what))
+}
+
+// None at the end of the file
+fn read_value<T: DeserializeOwned>(
+    input: &mut impl Read,
+) -> io::Result<Option<T>> {
+    Ok(read_frame(input)?.map(|(value, _)| value))
+}
+
+/// Reads only what a session was recorded from, e.g. to find out which
+/// key type to read the rest with.
+pub fn read_info(path: impl Into<PathBuf>) -> io::Result<SessionInfo> {
+    let mut input = BufReader::new(File::open(path.into())?);
+    read_value(&mut input)?.ok_or_else(|| missing("session info"))
+}
+
+/// Reads a whole session. A recording cut short, e.g. by the server
+/// being killed, ends at its last complete event.
+pub fn read_session<K: DocKey>(
+    path: impl Into<PathBuf>,
+) -> io::Result<Session<K>> {
+    let mut input = BufReader::new(File::open(path.into())?);
+    let info =
+        read_value(&mut input)?.ok_or_else(|| missing("session info"))?;
+    let document =
+        read_value(&mut input)?.ok_or_else(|| missing("document"))?;
+    let mut events = Vec::new();
+    while let Ok(Some(event)) = read_value(&mut input) {
+        events.push(event);
+    }
+    Ok(Session {
+        info,
+        document,
+        events,
+    })
+}
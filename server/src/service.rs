@@ -0,0 +1,106 @@
+use crate::shard::Shards;
+
+use crdts_sandbox_lib::document::{Command, DocKey, DocResponse};
+
+/// The responses to a command from a client, in the order they are sent.
+/// Reads are answered from the published versions, reads over every
+/// record from the shards' parts without joining them; everything else
+/// goes through the shards' writers.
+pub async fn respond<K: DocKey>(
+    shared: &Shards<K>,
+    cmd: Command<K>,
+) -> Vec<DocResponse<K>> {
+    let resp = match cmd {
+        Command::GetDocument { clock } => match clock {
+            Some(clock) if shared.read_ctx().add_clock <= clock => {
+                DocResponse::NotModified
+            }
+            _ => shared.document_response(),
+        },
+        Command::GetRecord { key } => {
+            DocResponse::Record(shared.get_record(&key))
+        }
+        Command::GetReadCtx => DocResponse::ReadCtx(shared.read_ctx()),
+        Command::GetRecords { after_key, limit } => {
+            let (records, next) = shared
+                .snapshot()
+                .records_page(after_key.as_ref(), limit as usize);
+            DocResponse::Records { records, next }
+        }
+        Command::StreamDocument { chunk_size } => {
+            let snapshot = shared.snapshot();
+            let total = snapshot.record_count() as u64;
+            let mut sent = 0;
+            let mut resps: Vec<_> = snapshot
+                .record_chunks(chunk_size as usize)
+                .map(|records| {
+                    sent += records.len() as u64;
+                    DocResponse::RecordChunk {
+                        records,
+                        sent,
+                        total,
+                    }
+                })
+                .collect();
+            let read_ctx = snapshot.get_read_ctx();
+            resps.push(DocResponse::DocumentEnd { read_ctx });
+            return resps;
+        }
+        Command::GetDigest { range } => {
+            let tree = shared.snapshot().merkle_tree();
+            DocResponse::Digest(tree.digest(range))
+        }
+        Command::GetRecordSet { keys } => {
+            DocResponse::RecordSet(shared.snapshot().record_set(keys))
+        }
+        Command::GetHashes => {
+            let snapshot = shared.snapshot();
+            DocResponse::Hashes {
+                content: snapshot.content_hash(),
+                state: snapshot.state_hash(),
+                clock: snapshot.get_read_ctx().add_clock,
+            }
+        }
+        cmd => return shared.handle(cmd).await.into_iter().collect(),
+    };
+    vec![resp]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crdts::{CmRDT, Dot};
+    use crdts_sandbox_lib::document::{
+        Document, Item, RecordEntry, RecordKey, SERVER_ACTOR,
+    };
+
+    #[tokio::test]
+    async fn documents_are_only_sent_to_clocks_missing_ops() {
+        let doc = Document::<RecordKey>::example(1);
+        let shards = Shards::spawn(SERVER_ACTOR, doc, 2).unwrap();
+        let get = |clock| Command::GetDocument { clock };
+        let seen = shards.read_ctx().add_clock;
+
+        let resps = respond(&shards, get(Some(seen.clone()))).await;
+        assert!(matches!(resps[..], [DocResponse::NotModified]));
+        let mut ahead = seen.clone();
+        ahead.apply(Dot::new(9, 1));
+        let resps = respond(&shards, get(Some(ahead))).await;
+        assert!(matches!(resps[..], [DocResponse::NotModified]));
+
+        let add = Command::Add {
+            add_ctx: shards.read_ctx().derive_add_ctx(2),
+            key: 5,
+            item: Item::Single(RecordEntry::text("new")),
+        };
+        respond(&shards, add).await;
+        for clock in [Some(seen), None] {
+            match &respond(&shards, get(clock)).await[..] {
+                [DocResponse::Document(doc)] => {
+                    assert!(doc.get_record(&5).val.is_some())
+                }
+                resps => panic!("expected the document, got {:?}", resps),
+            }
+        }
+    }
+}
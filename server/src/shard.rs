@@ -0,0 +1,508 @@
+use crate::persist;
+use crate::state::{
+    ack, out_of_keys, run_writer, State, Versions, WriteRequest,
+};
+
+use crdts_sandbox_lib::causal::{self, CausalBuffer, Delivery};
+use crdts_sandbox_lib::document::{
+    shard_of, split_op, split_record_set, Command, DocActor, DocKey,
+    DocResponse, Document, DocumentOp, GcReport, OrswotRecord, Parts,
+    RecordSet,
+};
+use crdts_sandbox_lib::oplog::{LogStats, OpId};
+
+use crdts::{ctx::AddCtx, ctx::ReadCtx, map::Op, CmRDT, CvRDT, VClock};
+
+use futures::future::join_all;
+
+use tokio::sync::{mpsc, oneshot};
+
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// One shard of a document: the published versions of its records and
+/// the writer that owns them.
+pub struct Shard<K: DocKey> {
+    versions: Arc<Versions<K>>,
+    writer: mpsc::UnboundedSender<WriteRequest<K>>,
+}
+
+impl<K: DocKey> Shard<K> {
+    /// Runs the writer of `state`.
+    pub fn run(state: State<K>) -> Self {
+        let (writer, requests) = mpsc::unbounded_channel();
+        let versions = state.versions();
+        tokio::spawn(run_writer(state, requests));
+        Shard { versions, writer }
+    }
+
+    pub fn versions(&self) -> &Versions<K> {
+        &self.versions
+    }
+
+    /// Sends `cmd` to the shard's writer; the reply comes once the writes
+    /// it made have been published.
+    pub async fn request(&self, cmd: Command<K>) -> Option<DocResponse<K>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.writer.send((cmd, reply_tx)).ok()?;
+        reply_rx.await.ok()?
+    }
+}
+
+// a document can only fail to split if the crdts version has changed
+// under the mirrors in `gc`
+fn layout_changed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "document layout didn't match the crdts version",
+    )
+}
+
+/// What the shards have applied between them, and the ops that can't be
+/// applied yet. This is the only state all writes share, and it is only
+/// held to check and record clocks, never while a shard applies an op.
+struct Frontier<K: DocKey> {
+    clock: VClock<DocActor>,
+    buffer: CausalBuffer<K>,
+    // server dots handed out for records being created, which may not
+    // have been applied yet
+    server_counter: u64,
+}
+
+/// A document whose records are partitioned by key across shards, each
+/// with its own writer and op log, so writes to different shards don't
+/// wait on each other.
+///
+/// An op is only sent to its shard once everything it depends on has been
+/// applied by the other shards, and its dot only joins the global clock
+/// once it has been published, so every op the global `ReadCtx` covers is
+/// visible to readers.
+pub struct Shards<K: DocKey> {
+    actor: DocActor,
+    shards: Vec<Shard<K>>,
+    frontier: Mutex<Frontier<K>>,
+}
+
+impl<K: DocKey> Shards<K> {
+    /// Splits `doc` into `count` shards, each with its own writer, which
+    /// make their own writes as `actor`.
+    pub fn spawn(
+        actor: DocActor,
+        doc: Document<K>,
+        count: usize,
+    ) -> io::Result<Self> {
+        let states = Parts::split(&doc, count)
+            .ok_or_else(layout_changed)?
+            .into_iter()
+            .map(|parts| State::new(actor, parts))
+            .collect();
+        Ok(Shards::run(actor, states))
+    }
+
+    /// Like `spawn`, but each shard logs its changes to `dir`, see
+    /// `persist`, and carries on from what it logged there before if
+    /// anything; `doc` only seeds the shards that have no log yet.
+    pub fn open(
+        actor: DocActor,
+        doc: Document<K>,
+        count: usize,
+        dir: &Path,
+        path: &str,
+    ) -> io::Result<Self> {
+        let parts = doc.split(count).ok_or_else(layout_changed)?;
+        let mut states = Vec::new();
+        for (shard, part) in parts.into_iter().enumerate() {
+            let recovered =
+                persist::open(dir, path, shard, count, actor, part)?;
+            states.push(State::recover(actor, recovered, shard, count)?);
+        }
+        Ok(Shards::run(actor, states))
+    }
+
+    // the shards' clocks are joined, as each shard's only covers what was
+    // applied to it since the document was split
+    fn run(actor: DocActor, states: Vec<State<K>>) -> Self {
+        let mut clock = VClock::new();
+        for state in states.iter() {
+            clock.merge(state.parts().get_read_ctx().add_clock);
+        }
+        let shards = states.into_iter().map(Shard::run).collect();
+        Shards {
+            actor,
+            shards,
+            frontier: Mutex::new(Frontier {
+                server_counter: clock.get(&actor),
+                clock,
+                buffer: CausalBuffer::new(),
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    pub fn shard(&self, key: &K) -> &Shard<K> {
+        &self.shards[shard_of(key, self.shards.len())]
+    }
+
+    fn clock(&self) -> VClock<DocActor> {
+        self.frontier.lock().unwrap().clock.clone()
+    }
+
+    pub fn read_ctx(&self) -> ReadCtx<(), DocActor> {
+        let clock = self.clock();
+        ReadCtx {
+            add_clock: clock.clone(),
+            rm_clock: clock,
+            val: (),
+        }
+    }
+
+    /// The record at `key`, with the global clock as its add clock.
+    pub fn get_record(
+        &self,
+        key: &K,
+    ) -> ReadCtx<Option<OrswotRecord>, DocActor> {
+        let clock = self.clock();
+        let mut record = self.shard(key).versions().load().get_record(key);
+        record.add_clock = clock;
+        record
+    }
+
+    /// The published parts of every shard as one whole at the global
+    /// clock, which reads are answered from. It may hold writes that are
+    /// still being applied beyond its clock; those are sent again to a
+    /// client that resumes from it, and applying them twice is harmless.
+    pub fn snapshot(&self) -> Parts<K> {
+        let clock = self.clock();
+        let shards: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.versions().load_full())
+            .collect();
+        Parts::concat(shards.iter().map(|parts| parts.as_ref()), clock)
+    }
+
+    /// The snapshot joined into one document, for the few reads that need
+    /// all of it. `None` if the parts couldn't be put back together, see
+    /// `Document::join`.
+    pub fn document(&self) -> Option<Document<K>> {
+        self.snapshot().join()
+    }
+
+    /// The whole document for a client, or why it can't have it.
+    pub fn document_response(&self) -> DocResponse<K> {
+        match self.document() {
+            Some(doc) => DocResponse::Document(doc),
+            None => DocResponse::Rejected {
+                reason: "the shards couldn't be joined".into(),
+            },
+        }
+    }
+
+    /// Handles a command that needs the writers, returning the reply if
+    /// the command has one.
+    pub async fn handle(&self, cmd: Command<K>) -> Option<DocResponse<K>> {
+        match cmd {
+            Command::Add { add_ctx, .. } if add_ctx.dot.actor == self.actor => {
+                Some(self.actor_claimed())
+            }
+            Command::Add { add_ctx, key, item } => {
+                let doc = self.shard(&key).versions().load_full();
+                if !doc.can_write(&key) {
+                    return Some(DocResponse::Rejected {
+                        reason: format!(
+                            "{} is reserved for created records",
+                            key
+                        ),
+                    });
+                }
+                let op = doc.add_item(key, add_ctx, item);
+                Some(self.apply_op(op).await)
+            }
+            Command::Apply { op } if self.claims_server_dot(&op) => {
+                Some(self.actor_claimed())
+            }
+            Command::Apply { op } => Some(self.apply_op(op).await),
+            Command::CreateRecord { initial } => {
+                let add_ctx = self.server_add_ctx();
+                let key = match K::from_dot(&add_ctx.dot) {
+                    Some(key) => key,
+                    None => return Some(out_of_keys()),
+                };
+                let op = self.shard(&key).versions().load().add_item(
+                    key.clone(),
+                    add_ctx,
+                    initial,
+                );
+                self.apply_op(op).await;
+                Some(DocResponse::RecordCreated { key })
+            }
+            Command::AckClock { actor, .. } if actor == self.actor => None,
+            Command::AckClock { actor, clock } => {
+                self.broadcast(Command::AckClock { actor, clock }).await;
+                None
+            }
+            Command::Resume { clock } => {
+                let mut missed = Vec::new();
+                let resps = self.broadcast(Command::Resume { clock }).await;
+                for resp in resps {
+                    match resp {
+                        Some(DocResponse::Missed { mut ops }) => {
+                            missed.append(&mut ops)
+                        }
+                        _ => return Some(self.document_response()),
+                    }
+                }
+                Some(DocResponse::Missed { ops: missed })
+            }
+            Command::Merge { doc } => {
+                self.merge(doc).await;
+                None
+            }
+            Command::MergeRecords { set } => {
+                self.merge_records(set).await;
+                None
+            }
+            Command::GetStats => Some(DocResponse::Stats(self.stats().await)),
+            Command::Blame { key } => {
+                self.shard(&key).request(Command::Blame { key }).await
+            }
+            Command::GetHistory { key, limit, before } => {
+                let cmd = Command::GetHistory {
+                    key: key.clone(),
+                    limit,
+                    before,
+                };
+                self.shard(&key).request(cmd).await
+            }
+            Command::CollectGarbage => {
+                let (stable, report) = self.collect_garbage().await;
+                Some(DocResponse::GarbageCollected { stable, report })
+            }
+            _ => None,
+        }
+    }
+
+    /// The ops each shard logged from its position in `from` on, and the
+    /// positions after them. `None` if a shard has trimmed some of them.
+    pub async fn log_since(
+        &self,
+        from: &[u64],
+    ) -> Option<(Vec<DocumentOp<K>>, Vec<u64>)> {
+        if from.len() != self.shards.len() {
+            return None;
+        }
+        let requests = self
+            .shards
+            .iter()
+            .zip(from)
+            .map(|(shard, &from)| shard.request(Command::GetLog { from }));
+        let mut logged = Vec::new();
+        let mut positions = Vec::new();
+        for resp in join_all(requests).await {
+            match resp {
+                Some(DocResponse::Log { mut ops, next }) => {
+                    logged.append(&mut ops);
+                    positions.push(next);
+                }
+                _ => return None,
+            }
+        }
+        Some((logged, positions))
+    }
+
+    /// Where each shard's op log is up to, to pass to `log_since` later.
+    pub async fn log_positions(&self) -> Vec<u64> {
+        let end = vec![u64::MAX; self.shards.len()];
+        self.log_since(&end)
+            .await
+            .map_or_else(Vec::new, |(_, positions)| positions)
+    }
+
+    async fn broadcast(&self, cmd: Command<K>) -> Vec<Option<DocResponse<K>>> {
+        join_all(self.shards.iter().map(|shard| shard.request(cmd.clone())))
+            .await
+    }
+
+    /// Whether `op` has a dot of the server's actor that the server hasn't
+    /// made. Peers send back the server's own ops, but a client writing as
+    /// the server would make dots that collide with the server's.
+    fn claims_server_dot(&self, op: &DocumentOp<K>) -> bool {
+        match op {
+            Op::Up { dot, .. } if dot.actor == self.actor => {
+                let frontier = self.frontier.lock().unwrap();
+                let made = frontier
+                    .server_counter
+                    .max(frontier.clock.get(&self.actor));
+                dot.counter > made
+            }
+            _ => false,
+        }
+    }
+
+    fn actor_claimed(&self) -> DocResponse<K> {
+        DocResponse::Rejected {
+            reason: format!("actor {} is the server's", self.actor),
+        }
+    }
+
+    fn server_add_ctx(&self) -> AddCtx<DocActor> {
+        let mut frontier = self.frontier.lock().unwrap();
+        let counter =
+            frontier.server_counter.max(frontier.clock.get(&self.actor)) + 1;
+        frontier.server_counter = counter;
+        let mut clock = frontier.clock.clone();
+        let dot = crdts::Dot::new(self.actor, counter);
+        clock.apply(dot);
+        AddCtx { clock, dot }
+    }
+
+    /// Applies `op` and whatever buffered ops it releases, each in its
+    /// shard, and acknowledges `op` once it has been published.
+    pub async fn apply_op(&self, op: DocumentOp<K>) -> DocResponse<K> {
+        let id = OpId::of(&op);
+        let (delivery, ready) = {
+            let mut frontier = self.frontier.lock().unwrap();
+            let frontier = &mut *frontier;
+            let delivery = if frontier.buffer.pending().contains(&op) {
+                Delivery::Duplicate
+            } else {
+                causal::delivery(&frontier.clock, &op)
+            };
+            (
+                delivery,
+                frontier.buffer.deliver(&frontier.clock, op.clone()),
+            )
+        };
+
+        let duplicate = self.apply_ready(ready, Some(&id)).await;
+        let delivery = if duplicate {
+            Delivery::Duplicate
+        } else {
+            delivery
+        };
+        ack(&op, delivery)
+    }
+
+    /// Applies ops the frontier has released, each in its shard, then
+    /// those their dots release in turn. Returns whether the op `own`
+    /// identifies turned out to be a duplicate.
+    async fn apply_ready(
+        &self,
+        mut ready: Vec<DocumentOp<K>>,
+        own: Option<&OpId<K>>,
+    ) -> bool {
+        let mut duplicate = false;
+        while !ready.is_empty() {
+            let mut dots = Vec::new();
+            for ready_op in ready {
+                let is_own = own == Some(&OpId::of(&ready_op));
+                if let Op::Up { dot, .. } = &ready_op {
+                    dots.push(*dot);
+                }
+                for (shard, part) in split_op(ready_op, self.shards.len()) {
+                    let cmd = Command::Apply { op: part };
+                    let resp = self.shards[shard].request(cmd).await;
+                    if let Some(DocResponse::Ack {
+                        duplicate: true, ..
+                    }) = resp
+                    {
+                        duplicate |= is_own;
+                    }
+                }
+            }
+
+            let mut frontier = self.frontier.lock().unwrap();
+            for dot in dots {
+                frontier.clock.apply(dot);
+            }
+            let clock = frontier.clock.clone();
+            ready = frontier.buffer.release(&clock);
+        }
+        duplicate
+    }
+
+    /// Merges a full copy of the document into every shard, then applies
+    /// the buffered ops that were waiting for what it brought.
+    pub async fn merge(&self, doc: Document<K>) {
+        let clock = doc.get_read_ctx().add_clock;
+        let parts = match doc.split(self.shards.len()) {
+            Some(parts) => parts,
+            None => return,
+        };
+        let merges = self
+            .shards
+            .iter()
+            .zip(parts)
+            .map(|(shard, doc)| shard.request(Command::Merge { doc }));
+        join_all(merges).await;
+        self.merged(clock).await;
+    }
+
+    /// Merges records found to differ from another replica into their
+    /// shards, see `Document::merge_records`.
+    pub async fn merge_records(&self, set: RecordSet<K>) {
+        let clock = set.clock.clone();
+        let parts = split_record_set(set, self.shards.len());
+        let merges =
+            self.shards.iter().zip(parts).map(|(shard, set)| {
+                shard.request(Command::MergeRecords { set })
+            });
+        join_all(merges).await;
+        self.merged(clock).await;
+    }
+
+    // takes on the clock of a merged state once every shard has published
+    // it, and applies the buffered ops that were waiting for it
+    async fn merged(&self, clock: VClock<DocActor>) {
+        let ready = {
+            let mut frontier = self.frontier.lock().unwrap();
+            frontier.clock.merge(clock);
+            let clock = frontier.clock.clone();
+            frontier.buffer.release(&clock)
+        };
+        self.apply_ready(ready, None).await;
+    }
+
+    async fn stats(&self) -> LogStats {
+        let mut stats = LogStats::default();
+        for resp in self.broadcast(Command::GetStats).await {
+            if let Some(DocResponse::Stats(shard_stats)) = resp {
+                stats += shard_stats;
+            }
+        }
+        let frontier = self.frontier.lock().unwrap();
+        stats.duplicates += frontier.buffer.duplicates();
+        stats.pending += frontier.buffer.pending_len();
+        stats
+    }
+
+    // each shard only collects up to the stable clock of the actors that
+    // wrote to it, so the document's is their join
+    async fn collect_garbage(&self) -> (VClock<DocActor>, GcReport) {
+        let mut stable = VClock::new();
+        let mut report = GcReport::default();
+        for resp in self.broadcast(Command::CollectGarbage).await {
+            if let Some(DocResponse::GarbageCollected {
+                stable: shard_stable,
+                report: shard_report,
+            }) = resp
+            {
+                stable.merge(shard_stable);
+                report.bytes_before += shard_report.bytes_before;
+                report.bytes_after += shard_report.bytes_after;
+                report.deferred_dropped += shard_report.deferred_dropped;
+            }
+        }
+        (stable, report)
+    }
+}
@@ -0,0 +1,512 @@
+use crate::frame::invalid;
+use crate::persist::{now_micros, Change, LogWriter, Recovered};
+
+use crdts_sandbox_lib::causal::Delivery;
+use crdts_sandbox_lib::document::{
+    stable_clock, Command, DocActor, DocKey, DocResponse, Document, DocumentOp,
+    GcReport, Parts,
+};
+use crdts_sandbox_lib::oplog::{HistoryEntry, LogStats, OpId, OpLog};
+
+use crdts::{map::Op, CmRDT, CvRDT, Dot, VClock};
+
+use arc_swap::ArcSwap;
+
+use tokio::sync::{mpsc, oneshot};
+
+use std::{collections::HashMap, io, sync::Arc};
+
+/// The published versions of a document's parts. Readers load the current
+/// one without taking a lock; only the writer stores new ones.
+pub type Versions<K> = ArcSwap<Parts<K>>;
+
+/// How many ops a writer keeps in memory to catch clients up from and
+/// answer history with. Older updates are only in the op log on disk, and
+/// a client that hasn't seen them is sent the whole document instead.
+pub const MAX_LOGGED_OPS: usize = 100_000;
+
+pub type WriteRequest<K> =
+    (Command<K>, oneshot::Sender<Option<DocResponse<K>>>);
+
+/// The state owned by a document's single writer, or by the writer of one
+/// shard of it. The records are kept in `Parts`, twice over: publishing
+/// hands the writer's copy to readers and takes back the version they had
+/// before, which is caught up by making the batch's changes to it again.
+/// Writes then don't copy anything, unless a reader still held on to the
+/// old version when it was taken back, in which case the first write to
+/// a part it shares with that reader copies the part.
+///
+/// Ops must arrive in causal order; `Shards` holds back the ones that
+/// don't.
+pub struct State<K: DocKey> {
+    actor: DocActor,
+    parts: Parts<K>,
+    versions: Arc<Versions<K>>,
+    // the changes made since the last publish, to be made again to the
+    // version it replaces
+    unpublished: Vec<Unpublished<K>>,
+    ops: OpLog<K>,
+    // the document's clock when the op log was started; a client that has
+    // seen less than this can't catch up from the log alone
+    log_base: VClock<DocActor>,
+    acked: HashMap<DocActor, VClock<DocActor>>,
+    // when each op in the log was received, in microseconds since the Unix
+    // epoch; ops that came in through a merge have none
+    received: HashMap<OpId<K>, u64>,
+    log: Option<LogWriter<K>>,
+}
+
+impl<K: DocKey> State<K> {
+    /// A writer whose own writes, like created records, are made as
+    /// `actor`. Servers replicating to each other need distinct actors.
+    pub fn new(actor: DocActor, parts: Parts<K>) -> Self {
+        State {
+            actor,
+            log_base: parts.get_read_ctx().add_clock,
+            versions: Arc::new(ArcSwap::from_pointee(parts.clone())),
+            parts,
+            unpublished: Vec::new(),
+            ops: OpLog::new(),
+            acked: HashMap::new(),
+            received: HashMap::new(),
+            log: None,
+        }
+    }
+
+    /// A writer carrying on from what shard `shard` out of `shards` logged
+    /// before a restart, logging its changes to the same log. Only the
+    /// entries after the snapshot are applied again; the ones before it
+    /// are only taken back into the op log.
+    pub fn recover(
+        actor: DocActor,
+        recovered: Recovered<K>,
+        shard: usize,
+        shards: usize,
+    ) -> io::Result<Self> {
+        let Recovered {
+            base,
+            snapshot,
+            entries,
+            writer,
+        } = recovered;
+        let (mut doc, from) = match snapshot {
+            Some(snapshot) => (snapshot.document, snapshot.position as usize),
+            None => (base.clone(), 0),
+        };
+        let mut log_base = base.get_read_ctx().add_clock;
+        let mut ops = OpLog::new();
+        let mut received = HashMap::new();
+        for (position, entry) in entries.into_iter().enumerate() {
+            let change = entry.change;
+            match &change {
+                Change::Op(op) => {
+                    received.insert(OpId::of(op), entry.received);
+                    ops.push(op.clone());
+                }
+                change => {
+                    let clock = change.merged_clock().unwrap_or_default();
+                    log_base.merge(clock);
+                }
+            }
+            if position >= from {
+                change.apply_to(&mut doc);
+            }
+        }
+        let parts = Parts::shard(&doc, shard, shards)
+            .ok_or_else(|| invalid("the shard couldn't be split into parts"))?;
+        let mut state = State::new(actor, parts);
+        state.log_base = log_base;
+        state.ops = ops;
+        state.received = received;
+        state.trim_log();
+        state.log = Some(writer);
+        Ok(state)
+    }
+
+    pub fn versions(&self) -> Arc<Versions<K>> {
+        self.versions.clone()
+    }
+
+    pub fn parts(&self) -> &Parts<K> {
+        &self.parts
+    }
+
+    /// Makes every write so far visible to readers, and goes on writing to
+    /// the version they had before.
+    pub fn publish(&mut self) {
+        if self.unpublished.is_empty() {
+            return;
+        }
+        let previous = self.versions.swap(Arc::new(self.parts.clone()));
+        let mut parts = Arc::try_unwrap(previous)
+            .unwrap_or_else(|previous| previous.as_ref().clone());
+        for change in self.unpublished.drain(..) {
+            change.apply_to(&mut parts);
+        }
+        self.parts = parts;
+    }
+
+    /// Handles a command that needs the writer, returning the reply if
+    /// the command has one. Reads are answered from published versions.
+    pub fn handle(&mut self, cmd: Command<K>) -> Option<DocResponse<K>> {
+        match cmd {
+            Command::Add { add_ctx, key, item } => {
+                let op = self.parts.add_item(key, add_ctx, item);
+                Some(ack(&op, self.apply_op(op.clone())))
+            }
+            Command::Apply { op } => Some(ack(&op, self.apply_op(op.clone()))),
+            Command::CreateRecord { initial } => {
+                let created = self.parts.create_record(self.actor, initial);
+                let (key, op) = match created {
+                    Some(created) => created,
+                    None => return Some(out_of_keys()),
+                };
+                self.apply_op(op);
+                Some(DocResponse::RecordCreated { key })
+            }
+            Command::AckClock { actor, clock } => {
+                self.ack_clock(actor, clock);
+                self.collect_garbage();
+                None
+            }
+            Command::Merge { doc } => {
+                self.merge(doc);
+                None
+            }
+            Command::MergeRecords { set } => {
+                self.log_base.merge(set.clock.clone());
+                self.change(Change::MergeRecords(set));
+                None
+            }
+            Command::Resume { clock } => Some(self.missed(&clock)),
+            Command::GetStats => Some(DocResponse::Stats(self.stats())),
+            Command::Blame { key } => {
+                let received = |dot: &Dot<DocActor>| {
+                    self.received.get(&OpId::Dot(*dot)).copied()
+                };
+                let entries = self.parts.blame(&key, received);
+                Some(DocResponse::Blame { key, entries })
+            }
+            Command::GetHistory { key, limit, before } => {
+                Some(self.history(key, limit as usize, before))
+            }
+            Command::CollectGarbage => {
+                let (stable, report) = self.collect_garbage();
+                Some(DocResponse::GarbageCollected { stable, report })
+            }
+            Command::GetLog { from } => {
+                Some(match self.ops.from_position(from) {
+                    Some(ops) => DocResponse::Log {
+                        ops,
+                        next: self.ops.position(),
+                    },
+                    None => DocResponse::Rejected {
+                        reason: format!(
+                            "ops from position {} have been trimmed",
+                            from
+                        ),
+                    },
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn apply_op(&mut self, op: DocumentOp<K>) -> Delivery {
+        if !self.ops.push(op.clone()) {
+            return Delivery::Duplicate;
+        }
+        self.change(Change::Op(op));
+        self.trim_log();
+        Delivery::Ready
+    }
+
+    // Drops the oldest ops past `MAX_LOGGED_OPS`. A client that hasn't
+    // seen them can't catch up from the log any more.
+    fn trim_log(&mut self) {
+        for op in self.ops.trim(MAX_LOGGED_OPS) {
+            self.received.remove(&OpId::of(&op));
+            if let Op::Up { dot, .. } = op {
+                self.log_base.apply(dot);
+            }
+        }
+    }
+
+    /// Writes the changes logged so far through to the disk, so they can
+    /// be acknowledged.
+    pub fn sync(&mut self) {
+        if let Some(log) = &mut self.log {
+            if let Err(err) = log.sync() {
+                println!("couldn't sync the log: {}", err);
+            }
+        }
+    }
+
+    // applies a change, logging it first if the log is persisted, and
+    // snapshots the document when one is due
+    fn change(&mut self, change: Change<K>) {
+        let received = now_micros();
+        if let Change::Op(op) = &change {
+            self.received.insert(OpId::of(op), received);
+        }
+        if let Some(log) = &mut self.log {
+            if let Err(err) = log.append(received, &change) {
+                println!("couldn't log entry {}: {}", log.position(), err);
+            }
+        }
+        self.unpublished.push(Unpublished::Change(change.clone()));
+        if change.apply_to_parts(&mut self.parts).is_none() {
+            println!("couldn't split a merge between the parts");
+        }
+        if let Some(log) = &mut self.log {
+            if log.snapshot_due() {
+                let written = match self.parts.join() {
+                    Some(doc) => log.snapshot(&doc),
+                    None => Err(invalid("the parts couldn't be joined")),
+                };
+                if let Err(err) = written {
+                    println!("couldn't write snapshot: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Merges a full copy of the document. What it brings isn't in the op
+    /// log, so a client behind it has to be sent the whole document.
+    pub fn merge(&mut self, doc: Document<K>) {
+        self.log_base.merge(doc.get_read_ctx().add_clock);
+        self.change(Change::Merge(doc));
+    }
+
+    fn missed(&self, clock: &VClock<DocActor>) -> DocResponse<K> {
+        if &self.log_base <= clock {
+            DocResponse::Missed {
+                ops: self.ops.since(clock),
+            }
+        } else {
+            match self.parts.join() {
+                Some(doc) => DocResponse::Document(doc),
+                None => DocResponse::Rejected {
+                    reason: "the parts couldn't be joined".into(),
+                },
+            }
+        }
+    }
+
+    // the latest `limit` ops touching the record at `key` before `before`
+    // in its history
+    fn history(
+        &self,
+        key: K,
+        limit: usize,
+        before: Option<u64>,
+    ) -> DocResponse<K> {
+        let history = self.ops.history(&key);
+        // the ops before `first` have been trimmed
+        let first = self.ops.history_start(&key) as usize;
+        let len = first + history.len();
+        let end = before.map_or(len, |before| len.min(before as usize));
+        let end = end.max(first);
+        let start = end.saturating_sub(limit).max(first);
+        let entries = history
+            .enumerate()
+            .skip(start - first)
+            .take(end - start)
+            .map(|(i, op)| {
+                let received = self.received.get(&OpId::of(op)).copied();
+                HistoryEntry::new((first + i) as u64, op.clone(), received)
+            })
+            .collect();
+        DocResponse::History {
+            key,
+            entries,
+            earlier: Some(start as u64).filter(|&start| start > first as u64),
+        }
+    }
+
+    pub fn stats(&self) -> LogStats {
+        LogStats {
+            logged: self.ops.len(),
+            duplicates: self.ops.duplicates(),
+            pending: 0,
+        }
+    }
+
+    fn ack_clock(&mut self, actor: DocActor, clock: VClock<DocActor>) {
+        self.acked.entry(actor).or_default().merge(clock);
+    }
+
+    // Every actor that has written to the document must have acknowledged
+    // a clock before anything it might not have seen can be collected,
+    // including peer servers, which ack as their own actor.
+    fn stable_clock(&self) -> VClock<DocActor> {
+        let doc_clock = self.parts.get_read_ctx().add_clock;
+        let empty = VClock::new();
+        let acked = doc_clock
+            .iter()
+            .filter(|dot| *dot.actor != self.actor)
+            .map(|dot| self.acked.get(dot.actor).unwrap_or(&empty));
+        stable_clock(std::iter::once(&doc_clock).chain(acked))
+    }
+
+    fn collect_garbage(&mut self) -> (VClock<DocActor>, GcReport) {
+        let stable = self.stable_clock();
+        for op in self.ops.trim_removes(&stable) {
+            self.received.remove(&OpId::of(&op));
+        }
+        let report = self.parts.collect_garbage(&stable);
+        if report.deferred_dropped > 0 {
+            self.unpublished.push(Unpublished::Gc(stable.clone()));
+            println!(
+                "gc: dropped {} deferred removes, reclaimed {} bytes",
+                report.deferred_dropped,
+                report.reclaimed()
+            );
+        }
+        (stable, report)
+    }
+}
+
+/// A change made to the writer's parts since they were last published.
+enum Unpublished<K: DocKey> {
+    Change(Change<K>),
+    // garbage collected up to the stable clock
+    Gc(VClock<DocActor>),
+}
+
+impl<K: DocKey> Unpublished<K> {
+    // a failed change was reported when it was first made
+    fn apply_to(self, parts: &mut Parts<K>) {
+        match self {
+            Unpublished::Change(change) => {
+                let _ = change.apply_to_parts(parts);
+            }
+            Unpublished::Gc(stable) => {
+                parts.collect_garbage(&stable);
+            }
+        }
+    }
+}
+
+pub fn ack<K: DocKey>(
+    op: &DocumentOp<K>,
+    delivery: Delivery,
+) -> DocResponse<K> {
+    DocResponse::Ack {
+        id: OpId::of(op),
+        duplicate: delivery == Delivery::Duplicate,
+    }
+}
+
+/// The reply to `CreateRecord` once the server's counter no longer fits in
+/// a generated key.
+pub fn out_of_keys<K: DocKey>() -> DocResponse<K> {
+    DocResponse::Rejected {
+        reason: "the server has run out of record keys".into(),
+    }
+}
+
+/// Runs the single writer of a document. Requests that queued up while a
+/// batch was being applied are applied together, then the log is synced
+/// and the new version published once before any of them is answered, so
+/// a client always reads its own acknowledged writes.
+pub async fn run_writer<K: DocKey>(
+    mut state: State<K>,
+    mut requests: mpsc::UnboundedReceiver<WriteRequest<K>>,
+) {
+    while let Some(request) = requests.recv().await {
+        let mut replies = Vec::new();
+        let mut next = Some(request);
+        while let Some((cmd, reply)) = next {
+            replies.push((reply, state.handle(cmd)));
+            next = requests.try_recv().ok();
+        }
+        state.sync();
+        state.publish();
+        for (reply, resp) in replies {
+            let _ = reply.send(resp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crdts_sandbox_lib::document::{
+        Item, RecordEntry, RecordKey, SERVER_ACTOR,
+    };
+
+    fn add(state: &mut State<RecordKey>, key: RecordKey, text: &str) {
+        let add_ctx = state.parts().get_read_ctx().derive_add_ctx(1);
+        let item = Item::Single(RecordEntry::text(text));
+        state.handle(Command::Add { add_ctx, key, item });
+    }
+
+    #[test]
+    fn removes_are_resent_until_every_writer_acks_them() {
+        let mut state = State::new(SERVER_ACTOR, Parts::default());
+        add(&mut state, 3, "added");
+        let rm = state.parts().part(&3).remove_record(3);
+        state.handle(Command::Apply { op: rm.clone() });
+        let clock = state.parts().get_read_ctx().add_clock;
+        let resume = |state: &mut State<RecordKey>| {
+            let clock = clock.clone();
+            match state.handle(Command::Resume { clock }) {
+                Some(DocResponse::Missed { ops }) => ops,
+                resp => panic!("expected missed ops, got {:?}", resp),
+            }
+        };
+
+        assert_eq!(resume(&mut state), vec![rm.clone()]);
+        state.handle(Command::CollectGarbage);
+        assert_eq!(resume(&mut state), vec![rm]);
+        let actor = 1;
+        state.handle(Command::AckClock {
+            actor,
+            clock: clock.clone(),
+        });
+        assert!(resume(&mut state).is_empty());
+    }
+
+    #[test]
+    fn publishing_swaps_in_the_writers_copy_and_catches_up_the_other() {
+        let mut state = State::new(SERVER_ACTOR, Parts::default());
+        let versions = state.versions();
+        for key in 0..100 {
+            add(&mut state, key, "first");
+        }
+        state.publish();
+        let held = versions.load_full();
+        for key in 0..100 {
+            add(&mut state, key, "second");
+        }
+        let mut other = Document::default();
+        let op = other.add_item(
+            500,
+            other.get_read_ctx().derive_add_ctx(2),
+            Item::Single(RecordEntry::text("merged")),
+        );
+        other.apply(op);
+        state.merge(other);
+        state.publish();
+        assert_ne!(held.state_hash(), versions.load().state_hash());
+
+        for key in (0..100).step_by(3) {
+            add(&mut state, key, "third");
+        }
+        assert_ne!(state.parts().state_hash(), versions.load().state_hash());
+        state.publish();
+        let published = versions.load();
+        assert_eq!(published.state_hash(), state.parts().state_hash());
+        assert_eq!(published.record_count(), 101);
+        drop(published);
+
+        // publishing without writes leaves both copies alone
+        state.publish();
+        for key in 0..100 {
+            add(&mut state, key, "fourth");
+        }
+        state.publish();
+        assert_eq!(versions.load().state_hash(), state.parts().state_hash());
+    }
+}
@@ -1,14 +1,24 @@
 mod utils;
 
-use futures::{channel::mpsc, future::FutureExt, Stream, StreamExt};
+use futures::{channel::mpsc, StreamExt};
 
 use wasm_bindgen::{prelude::*, JsCast};
-use web_sys::{ErrorEvent, MessageEvent, WebSocket};
+use web_sys::MessageEvent;
+
+use std::rc::Rc;
 
 use crdts_sandbox_lib::document::{
-    Command, DocActor, DocResponse, Document, DocumentOp,
+    self, entry::hex_bytes, ContentType, DigestRange, DocActor, HashMatch,
+    Item, RecordEntry,
 };
 
+type Key = String;
+type Command = document::Command<Key>;
+type DocResponse = document::DocResponse<Key>;
+type DocReplica = document::DocReplica<Key>;
+type DocumentBuilder = document::DocumentBuilder<Key>;
+type DigestWalk = document::DigestWalk<Key>;
+
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
 #[cfg(feature = "wee_alloc")]
@@ -25,30 +35,66 @@ extern "C" {
     fn log(s: &str);
 }
 
+mod socket;
+
+use socket::{Socket, SocketEvent};
+
 #[allow(dead_code)]
 #[wasm_bindgen]
 pub struct WSConnection {
-    ws: WebSocket,
-    onopen: Closure<dyn FnMut(JsValue)>,
-    onmessage: Closure<dyn FnMut(MessageEvent)>,
-    onerror: Closure<dyn FnMut(ErrorEvent)>,
-    receiver: mpsc::Receiver<MessageEvent>,
+    ws: Rc<Socket>,
+    receiver: mpsc::Receiver<SocketEvent>,
+    actor: Option<DocActor>,
+    replica: DocReplica,
+    loading: Option<DocumentBuilder>,
+    walk: Option<DigestWalk>,
 }
 
+#[allow(dead_code)]
 #[wasm_bindgen]
 impl WSConnection {
     async fn get_received(&mut self) -> Option<MessageEvent> {
-        self.receiver.next().await
+        loop {
+            match self.receiver.next().await? {
+                SocketEvent::Message(e) => return Some(e),
+                event => self.handle_socket_event(event),
+            }
+        }
     }
 
     pub fn get_message(&mut self) -> Option<MessageEvent> {
-        if let Ok(Some(e)) = self.receiver.try_next() {
-            Some(e)
-        } else {
-            None
+        loop {
+            match self.receiver.try_recv().ok()? {
+                SocketEvent::Message(e) => return Some(e),
+                event => self.handle_socket_event(event),
+            }
+        }
+    }
+
+    fn handle_socket_event(&mut self, event: SocketEvent) {
+        match event {
+            SocketEvent::Opened => {
+                if let Err(err) = self.resume() {
+                    console_log!("error resuming session: {:?}", err);
+                }
+            }
+            SocketEvent::Closed => console_log!(
+                "connection lost, {} local ops pending",
+                self.replica.pending_local()
+            ),
+            SocketEvent::Message(_) => (),
         }
     }
 
+    // Asks for everything missed since the replica's clock and resends the
+    // local ops the server hasn't acknowledged.
+    fn resume(&mut self) -> Result<(), JsValue> {
+        let clock = self.replica.document().get_read_ctx().add_clock;
+        self.send_command(Command::Resume { clock })?;
+        self.replica.requeue_unacked();
+        self.send_queued()
+    }
+
     fn get_docresp(&mut self) -> Option<DocResponse> {
         let msg = self.get_message()?;
 
@@ -64,32 +110,189 @@ impl WSConnection {
 
     pub fn print_docresp(&mut self) {
         if let Some(resp) = self.get_docresp() {
-            let string = match resp {
+            match resp {
                 DocResponse::Document(doc) => {
                     console_log!("received document");
+                    self.replica.merge_document(doc);
+                    let doc = self.replica.document();
                     for item_ctx in doc.records.iter() {
                         let (k, v) = item_ctx.val;
                         console_log!("item {}", k);
                         v.read().val.iter().for_each(|x| {
-                            let s = std::str::from_utf8(x).unwrap();
-                            console_log!("  {}", s);
+                            console_log!("  {}", x);
                         });
                     }
+                    if let Some(actor) = self.actor {
+                        let clock = doc.get_read_ctx().add_clock;
+                        let _ = self
+                            .send_command(Command::AckClock { actor, clock });
+                    }
+                }
+                DocResponse::NotModified => {
+                    console_log!("document not modified");
                 }
                 DocResponse::Record(rec) => {
                     let rec = rec.val;
                     if let Some(record) = rec {
                         console_log!("received record");
                         record.read().val.iter().for_each(|x| {
-                            let s = std::str::from_utf8(x).unwrap();
-                            console_log!("  {}", s);
+                            console_log!("  {}", x);
                         });
                     }
                 }
                 DocResponse::ReadCtx(read_ctx) => {
-                    console_log!("received readctx");
+                    console_log!("received readctx {:?}", read_ctx.add_clock);
                 }
-            };
+                DocResponse::RecordCreated { key } => {
+                    console_log!("created record {}", key);
+                }
+                DocResponse::Rejected { reason } => {
+                    console_log!("rejected: {}", reason);
+                }
+                DocResponse::GarbageCollected { stable, report } => {
+                    console_log!(
+                        "collected garbage up to {:?}: {} bytes reclaimed",
+                        stable,
+                        report.reclaimed()
+                    );
+                }
+                DocResponse::Ack { id, duplicate } => {
+                    self.replica.ack(&id);
+                    if duplicate {
+                        console_log!("server already had op {:?}", id);
+                    } else {
+                        console_log!("server acked op {:?}", id);
+                    }
+                }
+                DocResponse::Stats(stats) => {
+                    console_log!(
+                        "{} ops logged, {} duplicates, {} pending",
+                        stats.logged,
+                        stats.duplicates,
+                        stats.pending
+                    );
+                }
+                DocResponse::Records { records, next } => {
+                    console_log!("received {} records", records.len());
+                    for paged in records.iter() {
+                        console_log!("item {}", paged.key);
+                        paged.record.read().val.iter().for_each(|x| {
+                            console_log!("  {}", x);
+                        });
+                    }
+                    if let Some(next) = next {
+                        console_log!("more records after {}", next);
+                    }
+                }
+                DocResponse::RecordChunk {
+                    records,
+                    sent,
+                    total,
+                } => {
+                    console_log!(
+                        "loading document: {}/{} records",
+                        sent,
+                        total
+                    );
+                    self.loading
+                        .get_or_insert_with(DocumentBuilder::new)
+                        .push(records);
+                }
+                DocResponse::DocumentEnd { read_ctx } => {
+                    let builder = self.loading.take().unwrap_or_default();
+                    match builder.finish(read_ctx.add_clock) {
+                        Some(doc) => {
+                            console_log!(
+                                "loaded {} records",
+                                doc.record_count()
+                            );
+                            self.replica.merge_document(doc);
+                        }
+                        None => console_log!("couldn't load document"),
+                    }
+                }
+                DocResponse::Digest(digest) => {
+                    if let Some(walk) = self.walk.as_mut() {
+                        for range in walk.receive(&digest) {
+                            let _ =
+                                self.send_command(Command::GetDigest { range });
+                        }
+                        self.walked();
+                    }
+                }
+                DocResponse::RecordSet(set) => {
+                    let walk = self.walk.take();
+                    let count = set.records.len();
+                    if !walk.is_some_and(|walk| walk.matches(&set)) {
+                        console_log!("server changed while comparing, retry");
+                    } else if self.replica.merge_records(set).is_some() {
+                        console_log!("merged {} differing records", count);
+                    } else {
+                        console_log!("couldn't merge records");
+                    }
+                }
+                DocResponse::Hashes {
+                    content,
+                    state,
+                    clock,
+                } => {
+                    console_log!(
+                        "server content hash {} at {:?}",
+                        hex_bytes(&content),
+                        clock
+                    );
+                    let doc = self.replica.document();
+                    match doc.compare_hashes(&content, &state) {
+                        HashMatch::State => {
+                            console_log!("same state as the server")
+                        }
+                        HashMatch::Content => {
+                            console_log!("same content as the server")
+                        }
+                        HashMatch::Differs => {
+                            console_log!("content differs from the server's")
+                        }
+                    }
+                }
+                DocResponse::Blame { key, entries } => {
+                    console_log!("blame for record {}", key);
+                    for blame in entries.iter() {
+                        let dots: Vec<_> = blame
+                            .dots
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect();
+                        console_log!("  {} - {}", blame.entry, dots.join(", "));
+                    }
+                }
+                DocResponse::History {
+                    key,
+                    entries,
+                    earlier,
+                } => {
+                    console_log!("history of record {}", key);
+                    for entry in entries.iter() {
+                        console_log!("  {}", entry);
+                    }
+                    if let Some(earlier) = earlier {
+                        console_log!("{} earlier ops", earlier);
+                    }
+                }
+                DocResponse::Missed { ops } => {
+                    console_log!("caught up on {} ops", ops.len());
+                    for op in ops {
+                        self.replica.apply_op(op);
+                    }
+                    if let Some(actor) = self.actor {
+                        let clock =
+                            self.replica.document().get_read_ctx().add_clock;
+                        let _ = self
+                            .send_command(Command::AckClock { actor, clock });
+                    }
+                }
+                // only servers ask each other for their logs
+                DocResponse::Log { .. } => (),
+            }
         } else {
             console_log!("no docresp available");
         }
@@ -105,95 +308,240 @@ impl WSConnection {
     }
 
     pub fn send_get_document(&self) -> Result<(), JsValue> {
-        self.send_command(Command::GetDocument)
+        let clock = self.replica.document().get_read_ctx().add_clock;
+        self.send_command(Command::GetDocument { clock: Some(clock) })
+    }
+
+    pub fn send_get_record(&self, key: &str) -> Result<(), JsValue> {
+        self.send_command(Command::GetRecord { key: key.into() })
+    }
+
+    /// Asks who added each entry of the record at `key`, and when the
+    /// server received it.
+    pub fn send_blame(&self, key: &str) -> Result<(), JsValue> {
+        self.send_command(Command::Blame { key: key.into() })
     }
 
-    pub fn send_get_record(&self, key: u32) -> Result<(), JsValue> {
-        self.send_command(Command::GetRecord { key })
+    /// Asks for up to `limit` ops that touched the record at `key`, the
+    /// latest ones before `before` in its history.
+    pub fn send_get_history(
+        &self,
+        key: &str,
+        limit: u32,
+        before: Option<u32>,
+    ) -> Result<(), JsValue> {
+        self.send_command(Command::GetHistory {
+            key: key.into(),
+            limit,
+            before: before.map(u64::from),
+        })
+    }
+
+    /// Asks for up to `limit` records after `after_key`; the reply says
+    /// which key to continue from.
+    pub fn send_get_records(
+        &self,
+        after_key: Option<String>,
+        limit: u32,
+    ) -> Result<(), JsValue> {
+        self.send_command(Command::GetRecords { after_key, limit })
+    }
+
+    /// Loads the whole document in chunks, logging progress as they come.
+    pub fn send_stream_document(&self, chunk_size: u32) -> Result<(), JsValue> {
+        self.send_command(Command::StreamDocument { chunk_size })
+    }
+
+    /// Compares the local document with the server's by walking its hash
+    /// tree, then fetches and merges only the records that differ.
+    pub fn send_compare(&mut self) -> Result<(), JsValue> {
+        let tree = self.replica.document().merkle_tree();
+        self.walk = Some(DigestWalk::new(tree));
+        self.send_command(Command::GetDigest {
+            range: DigestRange::root(),
+        })
+    }
+
+    // asks for the differing records once the walk has no digests left
+    // to wait for
+    fn walked(&mut self) {
+        let keys = match self.walk.as_ref() {
+            Some(walk) if walk.is_done() => walk.keys().to_vec(),
+            _ => return,
+        };
+        if keys.is_empty() {
+            console_log!("document matches the server's");
+            self.walk = None;
+        } else {
+            console_log!("{} records differ from the server's", keys.len());
+            let _ = self.send_command(Command::GetRecordSet { keys });
+        }
     }
 
     pub fn send_get_read_ctx(&self) -> Result<(), JsValue> {
         self.send_command(Command::GetReadCtx)
     }
 
+    pub fn send_collect_garbage(&self) -> Result<(), JsValue> {
+        self.send_command(Command::CollectGarbage)
+    }
+
+    /// Asks for the server's document hashes; the reply is compared with
+    /// the local document's.
+    pub fn send_get_hashes(&self) -> Result<(), JsValue> {
+        self.send_command(Command::GetHashes)
+    }
+
+    /// The hash of the local document's content, as hex.
+    pub fn content_hash(&self) -> String {
+        hex_bytes(&self.replica.document().content_hash())
+    }
+
+    pub fn send_get_stats(&self) -> Result<(), JsValue> {
+        self.send_command(Command::GetStats)
+    }
+
+    /// Makes later edits as `actor`; pending edits are still sent.
+    pub fn set_actor(&mut self, actor: DocActor) {
+        self.actor = Some(actor);
+        self.replica.set_actor(actor);
+    }
+
+    /// Local edits the server hasn't acknowledged yet.
+    pub fn pending_ops(&self) -> usize {
+        self.replica.pending_local()
+    }
+
+    /// Adds every non-empty line of `text` to the record under one dot.
+    pub fn send_add_entries(
+        &mut self,
+        key: &str,
+        text: &str,
+    ) -> Result<(), JsValue> {
+        self.send_add(key, text_entries(text))
+    }
+
+    /// Creates a record holding the lines of `text` under a key derived
+    /// from this replica's actor, and returns the key.
+    pub fn send_create_record(
+        &mut self,
+        text: &str,
+    ) -> Result<String, JsValue> {
+        let entries = text_entries(text);
+        if entries.is_empty() {
+            return Err(JsValue::from_str("nothing to add"));
+        }
+        let key = self
+            .replica_mut()?
+            .create_record(Item::from_vec(entries))
+            .ok_or_else(|| JsValue::from_str("out of record keys"))?;
+        self.send_queued()?;
+        Ok(key)
+    }
+
+    /// Adds raw bytes, tagged with an optional MIME type, to the record.
+    pub fn send_add_bytes(
+        &mut self,
+        key: &str,
+        content_type: Option<String>,
+        bytes: Vec<u8>,
+    ) -> Result<(), JsValue> {
+        let content_type =
+            content_type.map(|mime| ContentType::from_mime(&mime));
+        self.send_add(key, vec![RecordEntry::new(content_type, bytes)])
+    }
+
+    /// Removes every non-empty line of `text` from the record.
+    pub fn send_remove_entries(
+        &mut self,
+        key: &str,
+        text: &str,
+    ) -> Result<(), JsValue> {
+        let entries = text_entries(text);
+        if entries.is_empty() {
+            return Err(JsValue::from_str("nothing to remove"));
+        }
+        self.replica_mut()?.remove_entries(key.into(), entries);
+        self.send_queued()
+    }
+
+    pub fn send_remove_record(&mut self, key: &str) -> Result<(), JsValue> {
+        self.replica_mut()?.remove_record(key.into());
+        self.send_queued()
+    }
+
+    fn send_add(
+        &mut self,
+        key: &str,
+        entries: Vec<RecordEntry>,
+    ) -> Result<(), JsValue> {
+        if entries.is_empty() {
+            return Err(JsValue::from_str("nothing to add"));
+        }
+        let key = key.to_string();
+        if !self.replica.document().can_write(&key) {
+            return Err(JsValue::from_str(
+                "key is reserved for created records",
+            ));
+        }
+        self.replica_mut()?.add_item(key, Item::from_vec(entries));
+        self.send_queued()
+    }
+
+    fn replica_mut(&mut self) -> Result<&mut DocReplica, JsValue> {
+        if self.actor.is_none() {
+            return Err(JsValue::from_str("no actor set"));
+        }
+        Ok(&mut self.replica)
+    }
+
+    fn send_queued(&mut self) -> Result<(), JsValue> {
+        for op in self.replica.take_queued() {
+            self.send_command(Command::Apply { op })?;
+        }
+        Ok(())
+    }
+
     pub fn print_received_message(&mut self) {
-        if let Ok(msg) = self.receiver.try_next() {
-            if let Some(e) = msg {
-                if let Ok(abuf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
-                    console_log!(
-                        "message event, received arraybuffer: {:?}",
-                        abuf
-                    );
-                    let array = js_sys::Uint8Array::new(&abuf);
-                    let len = array.byte_length() as usize;
-                    console_log!(
-                        "Arraybuffer received {}bytes: {:?}",
-                        len,
-                        array.to_vec()
-                    );
-                } else if let Ok(blob) = e.data().dyn_into::<web_sys::Blob>() {
-                    console_log!("message event, received blob: {:?}", blob);
-                } else if let Ok(txt) = e.data().dyn_into::<js_sys::JsString>()
-                {
-                    console_log!("message event, received Text: {:?}", txt);
-                } else {
-                    console_log!(
-                        "message event, received Unknown: {:?}",
-                        e.data()
-                    );
-                }
+        if let Some(e) = self.get_message() {
+            if let Ok(abuf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+                console_log!("message event, received arraybuffer: {:?}", abuf);
+                let array = js_sys::Uint8Array::new(&abuf);
+                let len = array.byte_length() as usize;
+                console_log!(
+                    "Arraybuffer received {}bytes: {:?}",
+                    len,
+                    array.to_vec()
+                );
+            } else if let Ok(blob) = e.data().dyn_into::<web_sys::Blob>() {
+                console_log!("message event, received blob: {:?}", blob);
+            } else if let Ok(txt) = e.data().dyn_into::<js_sys::JsString>() {
+                console_log!("message event, received Text: {:?}", txt);
+            } else {
+                console_log!("message event, received Unknown: {:?}", e.data());
             }
         }
     }
 
     pub fn new(url: &str) -> Result<WSConnection, JsValue> {
-        let ws = WebSocket::new(url)?;
-        ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
-
-        let (sender, receiver) = mpsc::channel(64);
-
-        // onmessage callback
-        // let cloned_ws = ws.clone();
-        let mut cloned_sender = sender.clone();
-        let onmessage_callback =
-            Closure::wrap(Box::new(move |e: MessageEvent| {
-                match cloned_sender.try_send(e) {
-                    Ok(()) => console_log!("passed on message"),
-                    Err(_) => console_log!("Couldn't send message on channel"),
-                };
-            }) as Box<dyn FnMut(MessageEvent)>);
-
-        ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
-
-        let onerror_callback = Closure::wrap(Box::new(move |e: ErrorEvent| {
-            console_log!("error event: {:?}", e);
-        })
-            as Box<dyn FnMut(ErrorEvent)>);
-        ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
-
-        let cloned_ws = ws.clone();
-        let onopen_callback = Closure::wrap(Box::new(move |_| {
-            console_log!("socket opened");
-            match cloned_ws.send_with_str("ping") {
-                Ok(_) => console_log!("message successfully sent"),
-                Err(err) => console_log!("error sending message: {:?}", err),
-            }
-            // send off binary message
-            match cloned_ws.send_with_u8_array(&vec![0, 1, 2, 3]) {
-                Ok(_) => console_log!("binary message successfully sent"),
-                Err(err) => console_log!("error sending message: {:?}", err),
-            }
-        })
-            as Box<dyn FnMut(JsValue)>);
+        utils::set_panic_hook();
 
-        ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
+        let (ws, receiver) = Socket::open(url)?;
 
         Ok(WSConnection {
             ws,
-            onopen: onopen_callback,
-            onmessage: onmessage_callback,
-            onerror: onerror_callback,
             receiver,
+            actor: None,
+            replica: DocReplica::default(),
+            loading: None,
+            walk: None,
         })
     }
 }
+
+fn text_entries(text: &str) -> Vec<RecordEntry> {
+    text.lines()
+        .filter(|line| !line.is_empty())
+        .map(RecordEntry::text)
+        .collect()
+}
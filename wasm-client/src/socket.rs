@@ -0,0 +1,153 @@
+use futures::channel::mpsc;
+
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{CloseEvent, ErrorEvent, MessageEvent, WebSocket};
+
+use crdts_sandbox_lib::backoff::Backoff;
+
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+use super::log;
+
+pub enum SocketEvent {
+    Opened,
+    Message(MessageEvent),
+    Closed,
+}
+
+struct Callbacks {
+    onopen: Closure<dyn FnMut(JsValue)>,
+    onmessage: Closure<dyn FnMut(MessageEvent)>,
+    onerror: Closure<dyn FnMut(ErrorEvent)>,
+    onclose: Closure<dyn FnMut(CloseEvent)>,
+}
+
+/// A websocket that reopens itself, with exponential backoff, whenever it
+/// is closed. Every open, close and message is passed on as a
+/// `SocketEvent`.
+pub struct Socket {
+    url: String,
+    ws: RefCell<WebSocket>,
+    backoff: RefCell<Backoff>,
+    callbacks: RefCell<Option<Callbacks>>,
+}
+
+impl Socket {
+    pub fn open(
+        url: &str,
+    ) -> Result<(Rc<Socket>, mpsc::Receiver<SocketEvent>), JsValue> {
+        let (sender, receiver) = mpsc::channel(64);
+
+        let socket = Rc::new(Socket {
+            url: url.to_string(),
+            ws: RefCell::new(Socket::new_websocket(url)?),
+            backoff: RefCell::new(Backoff::default()),
+            callbacks: RefCell::new(None),
+        });
+        socket
+            .callbacks
+            .replace(Some(Socket::callbacks(&socket, sender)));
+        socket.register_callbacks();
+
+        Ok((socket, receiver))
+    }
+
+    fn new_websocket(url: &str) -> Result<WebSocket, JsValue> {
+        let ws = WebSocket::new(url)?;
+        ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
+        Ok(ws)
+    }
+
+    fn callbacks(
+        socket: &Rc<Socket>,
+        sender: mpsc::Sender<SocketEvent>,
+    ) -> Callbacks {
+        let mut cloned_sender = sender.clone();
+        let onmessage = Closure::wrap(Box::new(move |e: MessageEvent| {
+            if cloned_sender.try_send(SocketEvent::Message(e)).is_err() {
+                console_log!("Couldn't send message on channel");
+            }
+        })
+            as Box<dyn FnMut(MessageEvent)>);
+
+        let onerror = Closure::wrap(Box::new(move |e: ErrorEvent| {
+            console_log!("error event: {:?}", e);
+        }) as Box<dyn FnMut(ErrorEvent)>);
+
+        let mut cloned_sender = sender.clone();
+        let weak = Rc::downgrade(socket);
+        let onopen = Closure::wrap(Box::new(move |_| {
+            console_log!("socket opened");
+            if let Some(socket) = weak.upgrade() {
+                socket.backoff.borrow_mut().reset();
+            }
+            let _ = cloned_sender.try_send(SocketEvent::Opened);
+        }) as Box<dyn FnMut(JsValue)>);
+
+        let mut cloned_sender = sender;
+        let weak = Rc::downgrade(socket);
+        let onclose = Closure::wrap(Box::new(move |_: CloseEvent| {
+            let _ = cloned_sender.try_send(SocketEvent::Closed);
+            Socket::reconnect_later(&weak);
+        }) as Box<dyn FnMut(CloseEvent)>);
+
+        Callbacks {
+            onopen,
+            onmessage,
+            onerror,
+            onclose,
+        }
+    }
+
+    fn register_callbacks(&self) {
+        let ws = self.ws.borrow();
+        if let Some(callbacks) = self.callbacks.borrow().as_ref() {
+            ws.set_onopen(Some(callbacks.onopen.as_ref().unchecked_ref()));
+            ws.set_onmessage(Some(
+                callbacks.onmessage.as_ref().unchecked_ref(),
+            ));
+            ws.set_onerror(Some(callbacks.onerror.as_ref().unchecked_ref()));
+            ws.set_onclose(Some(callbacks.onclose.as_ref().unchecked_ref()));
+        }
+    }
+
+    fn reconnect_later(weak: &Weak<Socket>) {
+        let socket = match weak.upgrade() {
+            Some(socket) => socket,
+            None => return,
+        };
+        let delay = socket.backoff.borrow_mut().next_delay();
+        console_log!("socket closed, reconnecting in {:?}", delay);
+
+        let weak = weak.clone();
+        let retry = Closure::once_into_js(move || {
+            if let Some(socket) = weak.upgrade() {
+                match Socket::new_websocket(&socket.url) {
+                    Ok(ws) => {
+                        socket.ws.replace(ws);
+                        socket.register_callbacks();
+                    }
+                    Err(_) => Socket::reconnect_later(&weak),
+                }
+            }
+        });
+        if let Some(window) = web_sys::window() {
+            let _ = window
+                .set_timeout_with_callback_and_timeout_and_arguments_0(
+                    retry.unchecked_ref(),
+                    delay.as_millis() as i32,
+                );
+        }
+    }
+
+    pub fn send_with_str(&self, data: &str) -> Result<(), JsValue> {
+        self.ws.borrow().send_with_str(data)
+    }
+
+    pub fn send_with_u8_array(&self, data: &[u8]) -> Result<(), JsValue> {
+        self.ws.borrow().send_with_u8_array(data)
+    }
+}